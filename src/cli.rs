@@ -0,0 +1,243 @@
+use std::{error::Error, fmt};
+
+use crate::client::in_game::ClientSettings;
+use crate::server::ServerSettings;
+
+pub const USAGE: &str = "\
+usage: radwars [command] [options]
+
+commands:
+    client      join a match (default)
+    server      run a dedicated server, without a window
+    listen      host a match and play in it
+    protocol    print protocol constants and message sizes
+    help        print this message
+
+options:
+    --bind <address>                address to listen on
+    --connect <address>             server to join (client)
+    --max-clients <count>           player slots (server, listen)
+    --tick-rate <ticks>             updates per second (server)
+    --network-conditions <config>   simulate a bad network, e.g. latency=0.1,jitter=0.02,loss=0.05
+                                    (client, server, listen)
+    --players <count>               players in the measured snapshot (protocol)
+    --props <count>                 props in the measured snapshot (protocol)";
+
+const OPTIONS: &[&str] = &[
+    "--bind",
+    "--connect",
+    "--max-clients",
+    "--network-conditions",
+    "--tick-rate",
+    "--players",
+    "--props",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Client(ClientSettings),
+    Server(ServerSettings),
+    Listen(ServerSettings),
+    Protocol { players: usize, props: usize },
+    Help,
+}
+
+impl Command {
+    // Expects the arguments without the program name
+    pub fn parse<I: IntoIterator<Item = String>>(arguments: I) -> Result<Self, CliError> {
+        let mut arguments = arguments.into_iter().peekable();
+
+        let name = match arguments.peek() {
+            Some(argument) if !argument.starts_with('-') => arguments.next().unwrap(),
+            _ => "client".to_string(),
+        };
+
+        let mut command = match name.as_str() {
+            "client" => Command::Client(ClientSettings::default()),
+            "server" => Command::Server(ServerSettings::default()),
+            "listen" => Command::Listen(ServerSettings::default()),
+            "protocol" => Command::Protocol {
+                players: 16,
+                props: 32,
+            },
+            "help" => Command::Help,
+            _ => return Err(CliError::UnknownCommand(name)),
+        };
+
+        while let Some(option) = arguments.next() {
+            if option == "-h" || option == "--help" {
+                return Ok(Command::Help);
+            }
+
+            if !OPTIONS.contains(&option.as_str()) {
+                return Err(CliError::UnknownOption(option));
+            }
+
+            let value = arguments
+                .next()
+                .ok_or_else(|| CliError::MissingValue(option.clone()))?;
+
+            match (&mut command, option.as_str()) {
+                (Command::Client(settings), "--bind") => settings.bind_address = value,
+                (Command::Client(settings), "--connect") => settings.server_address = value,
+                (Command::Client(settings), "--network-conditions") => {
+                    settings.network_conditions = Some(parse_value(&option, &value)?)
+                }
+                (Command::Server(settings), "--bind") | (Command::Listen(settings), "--bind") => {
+                    settings.bind_address = value
+                }
+                (Command::Server(settings), "--max-clients")
+                | (Command::Listen(settings), "--max-clients") => {
+                    settings.max_clients = parse_value(&option, &value)?
+                }
+                (Command::Server(settings), "--tick-rate") => {
+                    settings.tick_rate = parse_value(&option, &value)?;
+
+                    if settings.tick_rate <= 0.0 {
+                        return Err(CliError::InvalidValue { option, value });
+                    }
+                }
+                (Command::Server(settings), "--network-conditions")
+                | (Command::Listen(settings), "--network-conditions") => {
+                    settings.network_conditions = Some(parse_value(&option, &value)?)
+                }
+                (Command::Protocol { players, .. }, "--players") => {
+                    *players = parse_value(&option, &value)?
+                }
+                (Command::Protocol { props, .. }, "--props") => {
+                    *props = parse_value(&option, &value)?
+                }
+                _ => {
+                    return Err(CliError::UnexpectedOption {
+                        command: name,
+                        option,
+                    })
+                }
+            }
+        }
+
+        Ok(command)
+    }
+}
+
+fn parse_value<T: std::str::FromStr>(option: &str, value: &str) -> Result<T, CliError> {
+    value.parse().map_err(|_| CliError::InvalidValue {
+        option: option.to_string(),
+        value: value.to_string(),
+    })
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CliError {
+    UnknownCommand(String),
+    UnknownOption(String),
+    MissingValue(String),
+    InvalidValue { option: String, value: String },
+    UnexpectedOption { command: String, option: String },
+}
+
+impl fmt::Display for CliError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CliError::UnknownCommand(command) => write!(f, "unknown command \"{}\"", command),
+            CliError::UnknownOption(option) => write!(f, "unknown option \"{}\"", option),
+            CliError::MissingValue(option) => write!(f, "{} needs a value", option),
+            CliError::InvalidValue { option, value } => {
+                write!(f, "invalid value \"{}\" for {}", value, option)
+            }
+            CliError::UnexpectedOption { command, option } => {
+                write!(f, "{} does not take {}", command, option)
+            }
+        }
+    }
+}
+
+impl Error for CliError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared::network_conditions::NetworkConditions;
+
+    fn parse(arguments: &[&str]) -> Result<Command, CliError> {
+        Command::parse(arguments.iter().map(|argument| argument.to_string()))
+    }
+
+    #[test]
+    fn defaults_to_client() {
+        assert_eq!(parse(&[]), Ok(Command::Client(ClientSettings::default())));
+        assert_eq!(
+            parse(&["--connect", "10.0.0.2:8311"]),
+            Ok(Command::Client(ClientSettings {
+                server_address: "10.0.0.2:8311".to_string(),
+                ..Default::default()
+            }))
+        );
+    }
+
+    #[test]
+    fn parses_server_options() {
+        assert_eq!(
+            parse(&[
+                "listen",
+                "--bind",
+                "0.0.0.0:9000",
+                "--max-clients",
+                "4",
+                "--network-conditions",
+                "latency=0.1",
+            ]),
+            Ok(Command::Listen(ServerSettings {
+                bind_address: "0.0.0.0:9000".to_string(),
+                max_clients: 4,
+                network_conditions: Some(NetworkConditions::default().with_latency(0.1)),
+                ..Default::default()
+            }))
+        );
+        assert_eq!(
+            parse(&["protocol", "--players", "64"]),
+            Ok(Command::Protocol {
+                players: 64,
+                props: 32
+            })
+        );
+        assert_eq!(parse(&["server", "--help"]), Ok(Command::Help));
+    }
+
+    #[test]
+    fn rejects_bad_arguments() {
+        assert_eq!(
+            parse(&["spectate"]),
+            Err(CliError::UnknownCommand("spectate".to_string()))
+        );
+        assert_eq!(
+            parse(&["server", "--verbose"]),
+            Err(CliError::UnknownOption("--verbose".to_string()))
+        );
+        assert_eq!(
+            parse(&["server", "--bind"]),
+            Err(CliError::MissingValue("--bind".to_string()))
+        );
+        assert_eq!(
+            parse(&["server", "--max-clients", "many"]),
+            Err(CliError::InvalidValue {
+                option: "--max-clients".to_string(),
+                value: "many".to_string(),
+            })
+        );
+        assert_eq!(
+            parse(&["server", "--tick-rate", "0"]),
+            Err(CliError::InvalidValue {
+                option: "--tick-rate".to_string(),
+                value: "0".to_string(),
+            })
+        );
+        assert_eq!(
+            parse(&["server", "--connect", "127.0.0.1:8311"]),
+            Err(CliError::UnexpectedOption {
+                command: "server".to_string(),
+                option: "--connect".to_string(),
+            })
+        );
+    }
+}
@@ -0,0 +1,270 @@
+use std::collections::VecDeque;
+
+use bevy::prelude::*;
+
+use crate::client::{connection::ServerConnection, udp_client::UdpManager};
+use crate::shared::{
+    game_message::{GameMessageType, TimeSyncRequestData, TimeSyncResponseData},
+    game_time::GameTime,
+    gameplay::{FixedTickClock, GameTick},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ClockSample {
+    // Server clock minus ours, assuming the request and the response took equally long
+    pub offset: f64,
+    pub round_trip_time: f64,
+}
+
+// Estimates the server clock from timestamped pings, so the client can run its ticks just far
+// enough ahead for its inputs to reach the server in time
+pub struct ClockSync {
+    pub request_interval: f64,
+    // Replies slower than this are dropped, they tell more about the network than the clocks
+    pub max_round_trip_time: f64,
+    // Seconds the client runs ahead on top of half the round trip, absorbs jitter
+    pub safety_margin: f64,
+    // Largest change to the speed of the fixed tick stage, as a fraction of real time
+    pub max_dilation: f64,
+    // Further off than this many ticks the client jumps to the target tick instead
+    pub snap_threshold: f64,
+    samples: VecDeque<ClockSample>,
+    capacity: usize,
+    estimate: Option<ClockSample>,
+    // Newest tick reported by the server and its clock at the time
+    server_tick: Option<(u32, f64)>,
+    last_request_at: f64,
+}
+
+impl Default for ClockSync {
+    fn default() -> Self {
+        Self::new(16)
+    }
+}
+
+impl ClockSync {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            request_interval: 0.25,
+            max_round_trip_time: 1.0,
+            safety_margin: 0.03,
+            max_dilation: 0.05,
+            snap_threshold: 10.0,
+            samples: VecDeque::with_capacity(capacity),
+            capacity,
+            estimate: None,
+            server_tick: None,
+            last_request_at: f64::NEG_INFINITY,
+        }
+    }
+
+    pub fn estimate(&self) -> Option<ClockSample> {
+        self.estimate
+    }
+
+    pub fn reset(&mut self) {
+        self.samples.clear();
+        self.estimate = None;
+        self.server_tick = None;
+        self.last_request_at = f64::NEG_INFINITY;
+    }
+
+    pub fn request(&mut self, now: f64) -> Option<TimeSyncRequestData> {
+        if now - self.last_request_at < self.request_interval {
+            return None;
+        }
+
+        self.last_request_at = now;
+
+        Some(TimeSyncRequestData { client_time: now })
+    }
+
+    pub fn receive(&mut self, response: &TimeSyncResponseData, now: f64) {
+        let round_trip_time = now - response.client_time;
+
+        // Either garbage or a reply to a request from an earlier connection
+        if round_trip_time < 0.0 || round_trip_time > self.max_round_trip_time {
+            return;
+        }
+
+        if self.samples.len() == self.capacity {
+            self.samples.pop_front();
+        }
+
+        self.samples.push_back(ClockSample {
+            offset: response.server_time + round_trip_time / 2.0 - now,
+            round_trip_time,
+        });
+
+        let is_newer = self
+            .server_tick
+            .is_none_or(|(_, server_time)| response.server_time > server_time);
+
+        if is_newer {
+            self.server_tick = Some((response.server_tick, response.server_time));
+        }
+
+        self.estimate = Some(self.estimate_from_samples());
+    }
+
+    // Slow round trips were most likely held up in one direction only, which skews their offset.
+    // Only the faster half is trusted for the offset, the median is the round trip time.
+    fn estimate_from_samples(&self) -> ClockSample {
+        let mut samples = self.samples.iter().copied().collect::<Vec<_>>();
+        samples.sort_by(|a, b| a.round_trip_time.partial_cmp(&b.round_trip_time).unwrap());
+
+        let trusted = &samples[..samples.len().div_ceil(2)];
+        let offset = trusted.iter().map(|sample| sample.offset).sum::<f64>() / trusted.len() as f64;
+
+        ClockSample {
+            offset,
+            round_trip_time: samples[samples.len() / 2].round_trip_time,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn server_time(&self, now: f64) -> Option<f64> {
+        self.estimate.map(|estimate| now + estimate.offset)
+    }
+
+    // Tick the client should be simulating now, fractional as ticks happen in between frames
+    pub fn target_tick(&self, now: f64, tick_rate: f64) -> Option<f64> {
+        let estimate = self.estimate?;
+        let (server_tick, server_time) = self.server_tick?;
+
+        let current_server_tick =
+            server_tick as f64 + (now + estimate.offset - server_time) * tick_rate;
+
+        Some(
+            current_server_tick + (estimate.round_trip_time / 2.0 + self.safety_margin) * tick_rate,
+        )
+    }
+
+    // Speed for the fixed tick stage that closes a gap of `error` ticks in about a second
+    pub fn time_scale(&self, error: f64, tick_rate: f64) -> f64 {
+        1.0 + (error / tick_rate).clamp(-self.max_dilation, self.max_dilation)
+    }
+}
+
+pub fn clock_sync_request(
+    time: Res<GameTime>,
+    mut udp_manager: ResMut<UdpManager>,
+    connection: Res<ServerConnection>,
+    mut clock_sync: ResMut<ClockSync>,
+) {
+    if !connection.is_connected() {
+        if clock_sync.estimate().is_some() {
+            clock_sync.reset();
+        }
+
+        return;
+    }
+
+    let now = time.seconds_since_startup();
+
+    if let Some(request) = clock_sync.request(now) {
+        if let Err(error) = udp_manager.send(GameMessageType::TimeSyncRequest(request), now) {
+            warn!("Failed to send time sync request: {}", error);
+        }
+    }
+}
+
+// Gently speeds up or slows down the fixed tick stage to stay on the target tick
+pub fn clock_sync_dilate_ticks(
+    time: Res<GameTime>,
+    clock_sync: Res<ClockSync>,
+    mut clock: ResMut<FixedTickClock>,
+    mut tick: ResMut<GameTick>,
+) {
+    let tick_rate = 1.0 / clock.step;
+
+    let target_tick = match clock_sync.target_tick(time.seconds_since_startup(), tick_rate) {
+        Some(target_tick) => target_tick,
+        None => {
+            clock.time_scale = 1.0;
+            return;
+        }
+    };
+
+    let error = target_tick - (tick.0 as f64 + clock.overstep() as f64);
+
+    if error.abs() > clock_sync.snap_threshold {
+        tick.0 = target_tick.floor().max(0.0) as u32;
+        clock.time_scale = 1.0;
+    } else {
+        clock.time_scale = clock_sync.time_scale(error, tick_rate);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(client_time: f64, server_time: f64, server_tick: u32) -> TimeSyncResponseData {
+        TimeSyncResponseData {
+            client_time,
+            server_time,
+            server_tick,
+        }
+    }
+
+    #[test]
+    fn estimates_offset_and_round_trip_time() {
+        let mut clock_sync = ClockSync::default();
+        assert_eq!(clock_sync.estimate(), None);
+
+        // Server clock is 10 seconds ahead, each way takes 50 ms
+        clock_sync.receive(&response(1.0, 11.05, 663), 1.1);
+
+        let estimate = clock_sync.estimate().unwrap();
+        assert!((estimate.offset - 10.0).abs() < 1e-9);
+        assert!((estimate.round_trip_time - 0.1).abs() < 1e-9);
+        assert!((clock_sync.server_time(2.0).unwrap() - 12.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn rejects_slow_round_trips() {
+        let mut clock_sync = ClockSync::default();
+
+        for index in 0..6 {
+            let sent_at = index as f64;
+            clock_sync.receive(&response(sent_at, sent_at + 10.05, 0), sent_at + 0.1);
+        }
+
+        // Held up on the way back only, taken at face value it would pull the offset down
+        clock_sync.receive(&response(6.0, 16.05, 0), 6.7);
+        clock_sync.receive(&response(7.0, 17.05, 0), 7.9);
+
+        let estimate = clock_sync.estimate().unwrap();
+        assert!((estimate.offset - 10.0).abs() < 1e-9);
+        assert!((estimate.round_trip_time - 0.1).abs() < 1e-9);
+
+        // Too slow to be kept at all
+        clock_sync.receive(&response(8.0, 18.05, 0), 10.0);
+        assert_eq!(clock_sync.samples.len(), 8);
+    }
+
+    #[test]
+    fn targets_a_tick_ahead_of_the_server() {
+        let mut clock_sync = ClockSync {
+            safety_margin: 0.05,
+            ..Default::default()
+        };
+
+        clock_sync.receive(&response(1.0, 1.05, 100), 1.1);
+
+        // Half a round trip and the margin, 100 ms ahead at 100 ticks per second
+        let target_tick = clock_sync.target_tick(1.1, 100.0).unwrap();
+        assert!((target_tick - 115.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn dilation_is_clamped() {
+        let clock_sync = ClockSync::default();
+
+        assert!((clock_sync.time_scale(0.0, 60.0) - 1.0).abs() < 1e-9);
+        assert!((clock_sync.time_scale(1.2, 60.0) - 1.02).abs() < 1e-9);
+        assert!((clock_sync.time_scale(30.0, 60.0) - 1.05).abs() < 1e-9);
+        assert!((clock_sync.time_scale(-30.0, 60.0) - 0.95).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,187 @@
+use bevy::{app::AppExit, prelude::*};
+
+use crate::client::{clock_sync::ClockSync, udp_client::UdpManager};
+use crate::shared::game_message::{
+    generate_salt, ClientId, ConnectionChallengeResponseData, ConnectionDeniedReason,
+    ConnectionRequestData, GameMessageType,
+};
+use crate::shared::game_time::GameTime;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    Disconnected,
+    Requesting { client_salt: u64 },
+    Challenged { challenge: u64 },
+    Connected { client_id: ClientId },
+    Denied(ConnectionDeniedReason),
+}
+
+pub struct ServerConnection {
+    pub state: ConnectionState,
+    pub retry_interval: f64,
+    pub heartbeat_interval: f64,
+    pub timeout: f64,
+    last_sent_at: f64,
+    last_received_at: f64,
+}
+
+impl Default for ServerConnection {
+    fn default() -> Self {
+        Self {
+            state: ConnectionState::Disconnected,
+            retry_interval: 0.5,
+            heartbeat_interval: 1.0,
+            timeout: 5.0,
+            last_sent_at: 0.0,
+            last_received_at: 0.0,
+        }
+    }
+}
+
+impl ServerConnection {
+    pub fn client_id(&self) -> Option<ClientId> {
+        match self.state {
+            ConnectionState::Connected { client_id } => Some(client_id),
+            _ => None,
+        }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.client_id().is_some()
+    }
+
+    // Advances the handshake with a message received from the server, returning the reply if one
+    // has to be sent back.
+    pub fn handle_message(
+        &mut self,
+        content: &GameMessageType,
+        now: f64,
+    ) -> Option<GameMessageType> {
+        if matches!(
+            self.state,
+            ConnectionState::Denied(_) | ConnectionState::Disconnected
+        ) {
+            return None;
+        }
+
+        self.last_received_at = now;
+
+        match (self.state, content) {
+            (
+                ConnectionState::Requesting { client_salt },
+                GameMessageType::ConnectionChallenge(challenge),
+            ) if challenge.client_salt == client_salt => {
+                let challenge = challenge.client_salt ^ challenge.server_salt;
+                self.state = ConnectionState::Challenged { challenge };
+                self.last_sent_at = now;
+
+                Some(GameMessageType::ConnectionChallengeResponse(
+                    ConnectionChallengeResponseData { challenge },
+                ))
+            }
+            (ConnectionState::Challenged { .. }, GameMessageType::ConnectionAccepted(accepted)) => {
+                info!("Connected to server as client {}", accepted.client_id);
+
+                self.state = ConnectionState::Connected {
+                    client_id: accepted.client_id,
+                };
+                self.heartbeat_interval = accepted.heartbeat_interval as f64;
+
+                None
+            }
+            (_, GameMessageType::ConnectionDenied(denied)) => {
+                warn!("Server denied connection: {:?}", denied.reason);
+
+                self.state = ConnectionState::Denied(denied.reason);
+
+                None
+            }
+            (ConnectionState::Connected { .. }, GameMessageType::Disconnect) => {
+                info!("Server closed the connection");
+
+                self.state = ConnectionState::Disconnected;
+
+                None
+            }
+            _ => None,
+        }
+    }
+}
+
+pub fn connection_update(
+    time: Res<GameTime>,
+    mut udp_manager: ResMut<UdpManager>,
+    mut connection: ResMut<ServerConnection>,
+    mut clock_sync: ResMut<ClockSync>,
+) {
+    let now = time.seconds_since_startup();
+
+    // However the previous connection ended, a restarted server sees a brand new client
+    if let ConnectionState::Disconnected = connection.state {
+        udp_manager.reset_channel();
+        clock_sync.reset();
+
+        connection.state = ConnectionState::Requesting {
+            client_salt: generate_salt(),
+        };
+        connection.last_received_at = now;
+        connection.last_sent_at = f64::NEG_INFINITY;
+    }
+
+    if now - connection.last_received_at > connection.timeout {
+        match connection.state {
+            ConnectionState::Denied(_) => {}
+            _ => {
+                warn!("Connection to server timed out");
+
+                connection.state = ConnectionState::Disconnected;
+                return;
+            }
+        }
+    }
+
+    let message = match connection.state {
+        ConnectionState::Requesting { client_salt }
+            if now - connection.last_sent_at > connection.retry_interval =>
+        {
+            GameMessageType::ConnectionRequest(ConnectionRequestData { client_salt })
+        }
+        ConnectionState::Challenged { challenge }
+            if now - connection.last_sent_at > connection.retry_interval =>
+        {
+            GameMessageType::ConnectionChallengeResponse(ConnectionChallengeResponseData {
+                challenge,
+            })
+        }
+        ConnectionState::Connected { .. }
+            if now - connection.last_sent_at > connection.heartbeat_interval =>
+        {
+            GameMessageType::Heartbeat
+        }
+        _ => return,
+    };
+
+    connection.last_sent_at = now;
+
+    if let Err(error) = udp_manager.send(message, now) {
+        warn!("Failed to send connection message: {}", error);
+    }
+}
+
+pub fn connection_disconnect_on_exit(
+    time: Res<GameTime>,
+    mut app_exit_events: EventReader<AppExit>,
+    mut udp_manager: ResMut<UdpManager>,
+    mut connection: ResMut<ServerConnection>,
+) {
+    if app_exit_events.iter().next().is_none() || !connection.is_connected() {
+        return;
+    }
+
+    connection.state = ConnectionState::Disconnected;
+
+    if let Err(error) = udp_manager.send(GameMessageType::Disconnect, time.seconds_since_startup())
+    {
+        warn!("Failed to notify server of disconnect: {}", error);
+    }
+}
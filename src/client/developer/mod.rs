@@ -1,12 +1,15 @@
+// The tools stay in here while the plugin has them switched off
+#![allow(dead_code)]
+
 use bevy::prelude::*;
 // use bevy::{ecs::TypeInfo, prelude::*};
-use bevy_egui::{egui::Window, EguiContext, EguiPlugin};
+use bevy_egui::{egui::Window, EguiContext};
 use std::time::Instant;
 
 #[derive(Debug, Default)]
 pub struct DeveloperPlugin {}
 impl Plugin for DeveloperPlugin {
-    fn build(&self, app_builder: &mut bevy::prelude::AppBuilder) {
+    fn build(&self, _app_builder: &mut bevy::prelude::AppBuilder) {
         // app_builder.add_plugin(EguiPlugin);
         // app_builder.insert_resource(DebugFpsCounter {
         //     last_measurment_instant: Instant::now(),
@@ -30,7 +33,7 @@ struct DebugFpsCounter {
 
 fn fps_counter(
     mut fps_counter_context: ResMut<DebugFpsCounter>,
-    mut egui_context: ResMut<EguiContext>,
+    egui_context: ResMut<EguiContext>,
 ) {
     let frame_duration = fps_counter_context
         .last_measurment_instant
@@ -1,46 +1,337 @@
-use bevy::{core::FixedTimestep, prelude::*};
+use bevy::{diagnostic::Diagnostics, prelude::*};
 
-use crate::client::udp_client::UdpManager;
-use crate::shared::game_message::{ClientInputData, GameMessageType};
+use crate::client::clock_sync::{clock_sync_dilate_ticks, clock_sync_request, ClockSync};
+use crate::client::connection::{
+    connection_disconnect_on_exit, connection_update, ServerConnection,
+};
+use crate::client::interpolation::{
+    interpolation_apply, interpolation_receive_snapshots, InterpolationClock,
+    InterpolationSettings, RemotePlayer,
+};
+use crate::client::network_diagnostics::{network_diagnostics_measure, network_diagnostics_setup};
+use crate::client::network_events::{
+    network_events_receive, network_events_send, ServerNetworkEvent,
+};
+use crate::client::prediction::{
+    prediction_reconcile, prediction_smooth_correction, ClientPrediction,
+};
+use crate::client::replication::{
+    replication_apply, NetworkEntities, ReplicationInbox, ReplicationMessage,
+};
+use crate::client::udp_client::{UdpManager, UdpReceiveError};
+use crate::shared::{
+    game_message::{ClientInputBatchData, GameMessageType, ServerGameStateSnapshotData},
+    game_time::GameTime,
+    gameplay::{
+        spawn_player_body, FixedTickClock, GameTick, LocalPlayer, PlayerInput, TickInterpolated,
+        FIXED_TICK_STAGE,
+    },
+    network_conditions::{NetworkConditioner, NetworkConditions},
+    network_diagnostics::ConnectionDiagnostics,
+    network_events::{NetworkEventInbox, NetworkEventOutbox, NetworkEventRegistry},
+    replication::ReplicationRegistry,
+    snapshot_delta::{apply_delta, SnapshotHistory},
+};
+
+#[derive(Debug, Clone)]
+pub struct ServerSnapshotEvent(pub ServerGameStateSnapshotData);
+
+// Every input packet repeats up to this many of the inputs before it
+const INPUT_REDUNDANCY: usize = 4;
+
+// Read by `ClientNetworkPlugin`, insert it beforehand to override the defaults
+#[derive(Debug, Clone, PartialEq)]
+pub struct ClientSettings {
+    pub bind_address: String,
+    pub server_address: String,
+    // Takes precedence over the environment variable
+    pub network_conditions: Option<NetworkConditions>,
+}
+
+impl Default for ClientSettings {
+    fn default() -> Self {
+        Self {
+            // Any free port, so several clients fit on one machine
+            bind_address: "0.0.0.0:0".to_string(),
+            server_address: "127.0.0.1:8311".to_string(),
+            network_conditions: None,
+        }
+    }
+}
+
+// Connection, snapshot and input traffic, without anything that needs a window
+#[derive(Default)]
+pub struct ClientNetworkPlugin {}
+impl Plugin for ClientNetworkPlugin {
+    fn build(&self, app_builder: &mut bevy::prelude::AppBuilder) {
+        if !app_builder.world().contains_resource::<ClientSettings>() {
+            app_builder.insert_resource(ClientSettings::default());
+        }
+
+        let settings = app_builder
+            .world()
+            .get_resource::<ClientSettings>()
+            .unwrap()
+            .clone();
+
+        if !app_builder
+            .world()
+            .contains_resource::<NetworkConditioner>()
+        {
+            app_builder.insert_resource(
+                settings
+                    .network_conditions
+                    .map_or_else(NetworkConditioner::from_env, NetworkConditioner::new),
+            );
+        }
+
+        // Tests insert a client on top of an in-memory transport beforehand
+        if !app_builder.world().contains_resource::<UdpManager>() {
+            let network_conditioner = app_builder
+                .world()
+                .get_resource::<NetworkConditioner>()
+                .unwrap()
+                .clone();
+
+            let mut server = UdpManager::new(&settings.bind_address, 1024, true)
+                .unwrap_or_else(|_| panic!("Failed to create server at: {}", settings.bind_address))
+                .with_network_conditioner(network_conditioner);
+            server
+                .connect(&settings.server_address)
+                .unwrap_or_else(|_| {
+                    panic!(
+                        "Failed to resolve server address: {}",
+                        settings.server_address
+                    )
+                });
+
+            app_builder.insert_resource(server);
+        }
+
+        app_builder
+            .insert_resource(ServerConnection::default())
+            .insert_resource(ClockSync::default())
+            .insert_resource(ClientPrediction::default())
+            .insert_resource(SnapshotHistory::default())
+            .init_resource::<Diagnostics>()
+            .init_resource::<ReplicationRegistry>()
+            .init_resource::<ReplicationInbox>()
+            .init_resource::<NetworkEntities>()
+            .init_resource::<NetworkEventRegistry>()
+            .init_resource::<NetworkEventInbox>()
+            .init_resource::<NetworkEventOutbox>()
+            .add_event::<ServerSnapshotEvent>()
+            .add_event::<ServerNetworkEvent>()
+            .add_startup_system(network_diagnostics_setup.system())
+            .add_system_to_stage(
+                CoreStage::PreUpdate,
+                client_receive.system().label("client_receive"),
+            )
+            .add_system_to_stage(
+                CoreStage::PreUpdate,
+                network_events_receive
+                    .system()
+                    .label("network_events_receive")
+                    .after("client_receive"),
+            )
+            .add_system_to_stage(
+                CoreStage::PreUpdate,
+                connection_update
+                    .system()
+                    .label("connection_update")
+                    .after("client_receive"),
+            )
+            .add_system_to_stage(
+                CoreStage::PreUpdate,
+                clock_sync_request.system().after("connection_update"),
+            )
+            .add_system_to_stage(
+                CoreStage::PreUpdate,
+                network_diagnostics_measure
+                    .system()
+                    .after("connection_update"),
+            )
+            .add_system_to_stage(
+                CoreStage::PreUpdate,
+                replication_apply.exclusive_system().at_end(),
+            )
+            .add_system_to_stage(CoreStage::Last, network_events_send.system())
+            .add_system_to_stage(CoreStage::Last, client_update_channel.system())
+            .add_system_to_stage(CoreStage::Last, connection_disconnect_on_exit.system());
+    }
+}
 
 #[derive(Default)]
 pub struct InGamePlugin {}
 impl Plugin for InGamePlugin {
     fn build(&self, app_builder: &mut bevy::prelude::AppBuilder) {
-        let client_updates_per_second = 1;
-        let client_target_address = "127.0.0.1:8311";
-        let client_listen_address = "127.0.0.1:8310";
+        app_builder
+            .add_plugin(ClientNetworkPlugin::default())
+            .insert_resource(InterpolationSettings::default())
+            .insert_resource(InterpolationClock::default())
+            .add_startup_system(spawn_local_player.system())
+            .add_system_to_stage(
+                CoreStage::PreUpdate,
+                prediction_reconcile.system().after("client_receive"),
+            )
+            .add_system_to_stage(
+                CoreStage::PreUpdate,
+                interpolation_receive_snapshots
+                    .system()
+                    .after("client_receive"),
+            )
+            .add_system_to_stage(
+                CoreStage::PreUpdate,
+                clock_sync_dilate_ticks.system().after("client_receive"),
+            )
+            .add_system_to_stage(
+                FIXED_TICK_STAGE,
+                client_send_input.system().before("player_movement"),
+            )
+            .add_system(show_remote_players.system())
+            .add_system(prediction_smooth_correction.system())
+            .add_system(interpolation_apply.system());
+    }
+}
 
-        let mut server = UdpManager::new(client_listen_address, 1024, true).expect(&format!(
-            "Failed to create server at: {}",
-            client_listen_address
-        ));
-        server.connect(client_target_address).unwrap();
+pub fn spawn_local_player(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+) {
+    let translation = Vec3::new(5.0, 20.0, -5.0);
+    let (player_entity, head_entity) = spawn_player_body(&mut commands, translation);
+
+    commands
+        .entity(player_entity)
+        .insert(LocalPlayer)
+        // Only rendered here, the server and prediction go by the pose of the body
+        .insert(TickInterpolated::default())
+        .insert_bundle(PbrBundle {
+            mesh: meshes.add(Mesh::from(shape::Capsule {
+                depth: 1.75,
+                radius: 0.4,
+                ..Default::default()
+            })),
+            material: materials.add(Color::rgb(1.0, 0.0, 0.0).into()),
+            transform: Transform::from_translation(translation),
+            ..Default::default()
+        });
+
+    commands
+        .entity(head_entity)
+        .insert_bundle(PerspectiveCameraBundle::default());
+}
 
-        app_builder.add_resource(server).add_stage_before(
-            stage::UPDATE,
-            "multiplayer_pre_update",
-            SystemStage::serial()
-                .with_run_criteria(FixedTimestep::step(1.0 / client_updates_per_second as f64))
-                .with_system(client_receive.system())
-                .with_system(client_send_input.system()),
-        );
+// Replication spawns remote players without anything to render
+fn show_remote_players(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    remote_player_query: Query<Entity, Added<RemotePlayer>>,
+) {
+    for entity in remote_player_query.iter() {
+        commands.entity(entity).with_children(|parent| {
+            parent.spawn_bundle(PbrBundle {
+                mesh: meshes.add(Mesh::from(shape::Capsule {
+                    depth: 1.75,
+                    radius: 0.4,
+                    ..Default::default()
+                })),
+                material: materials.add(Color::rgb(0.0, 0.0, 1.0).into()),
+                ..Default::default()
+            });
+        });
     }
 }
 
-fn client_receive(mut udp_manager: ResMut<UdpManager>) {
-    println!("Client update");
+fn client_receive(
+    time: Res<GameTime>,
+    mut udp_manager: ResMut<UdpManager>,
+    mut connection: ResMut<ServerConnection>,
+    mut clock_sync: ResMut<ClockSync>,
+    mut snapshot_history: ResMut<SnapshotHistory>,
+    mut replication_inbox: ResMut<ReplicationInbox>,
+    mut diagnostics: ResMut<Diagnostics>,
+    mut snapshot_events: EventWriter<ServerSnapshotEvent>,
+    mut network_events: EventWriter<ServerNetworkEvent>,
+) {
+    let now = time.seconds_since_startup();
 
     'data: loop {
-        let received = udp_manager.receive().expect("Failed to retrieve message");
+        let received = match udp_manager.receive(now) {
+            Ok(received) => received,
+            Err(UdpReceiveError::Decode(error)) => {
+                warn!("Discarded packet from server: {}", error);
+                continue 'data;
+            }
+            Err(UdpReceiveError::Fragment(error)) => {
+                warn!("Discarded fragment from server: {}", error);
+                continue 'data;
+            }
+            Err(error) => {
+                warn!("Failed to retrieve message: {}", error);
+                break 'data;
+            }
+        };
+
+        if let Some(content) = received {
+            if let Some(reply) = connection.handle_message(&content, now) {
+                if let Err(error) = udp_manager.send(reply, now) {
+                    warn!("Failed to reply to server: {}", error);
+                }
+            }
+
+            let is_snapshot = matches!(
+                content,
+                GameMessageType::ServerGameStateSnapshot(_)
+                    | GameMessageType::ServerGameStateDelta(_)
+            );
 
-        if let Some(message) = received {
-            match message.content {
+            if is_snapshot {
+                diagnostics.add_measurement(
+                    ConnectionDiagnostics::SERVER.snapshot_size,
+                    udp_manager.last_message_size() as f64,
+                );
+            }
+
+            match content {
                 GameMessageType::ServerGameStateSnapshot(message) => {
-                    println!("Received game snapshot: {:#?}", message);
+                    snapshot_history.insert(message.clone());
+                    snapshot_events.send(ServerSnapshotEvent(message));
+                }
+                GameMessageType::ServerGameStateDelta(delta) => {
+                    // Without the baseline the delta is useless, the server falls back to a full
+                    // snapshot once our acknowledged baseline is too old
+                    if let Some(baseline) = snapshot_history.get(delta.baseline_tick) {
+                        let message = apply_delta(baseline, &delta);
+
+                        snapshot_history.insert(message.clone());
+                        snapshot_events.send(ServerSnapshotEvent(message));
+                    }
+                }
+                GameMessageType::TimeSyncResponse(response) => {
+                    clock_sync.receive(&response, now);
+                }
+                GameMessageType::EntitiesSpawned(spawned) => {
+                    replication_inbox.push(ReplicationMessage::Spawned(spawned));
+                }
+                GameMessageType::EntitiesDespawned(despawned) => {
+                    replication_inbox.push(ReplicationMessage::Despawned(despawned));
                 }
+                GameMessageType::ComponentUpdates(updated) => {
+                    replication_inbox.push(ReplicationMessage::Updated(updated));
+                }
+                GameMessageType::NetworkEvent(event) => {
+                    network_events.send(ServerNetworkEvent(event));
+                }
+                GameMessageType::ConnectionChallenge(_)
+                | GameMessageType::ConnectionAccepted(_)
+                | GameMessageType::ConnectionDenied(_)
+                | GameMessageType::Heartbeat
+                | GameMessageType::Disconnect => {}
                 message => {
-                    println!("Received unexpected message: {:#?}", message);
+                    warn!("Received unexpected message: {:?}", message);
                 }
             }
         } else {
@@ -49,13 +340,45 @@ fn client_receive(mut udp_manager: ResMut<UdpManager>) {
     }
 }
 
-fn client_send_input(udp_manager: Res<UdpManager>, keyboard_input: Res<Input<KeyCode>>) {
-    udp_manager
-        .send(GameMessageType::ClientInput(ClientInputData {
-            move_forward: keyboard_input.pressed(KeyCode::W),
-            move_left: keyboard_input.pressed(KeyCode::A),
-            move_back: keyboard_input.pressed(KeyCode::S),
-            move_right: keyboard_input.pressed(KeyCode::D),
-        }))
-        .unwrap();
+// Runs once per tick, the server consumes inputs at the same rate
+fn client_send_input(
+    time: Res<GameTime>,
+    clock: Res<FixedTickClock>,
+    tick: Res<GameTick>,
+    mut udp_manager: ResMut<UdpManager>,
+    connection: Res<ServerConnection>,
+    snapshot_history: Res<SnapshotHistory>,
+    mut prediction: ResMut<ClientPrediction>,
+    player_query: Query<&PlayerInput, With<LocalPlayer>>,
+) {
+    if !connection.is_connected() {
+        return;
+    }
+
+    for player_input in player_query.iter() {
+        prediction.record_input(player_input, tick.0, clock.step as f32);
+
+        let message = GameMessageType::ClientInput(ClientInputBatchData {
+            acknowledged_snapshot: snapshot_history.latest_tick().unwrap_or_default(),
+            inputs: prediction.unacknowledged_inputs(INPUT_REDUNDANCY),
+        });
+
+        if let Err(error) = udp_manager.send(message, time.seconds_since_startup()) {
+            warn!("Failed to send input: {}", error);
+        }
+    }
+}
+
+fn client_update_channel(
+    time: Res<GameTime>,
+    mut udp_manager: ResMut<UdpManager>,
+    connection: Res<ServerConnection>,
+) {
+    if !connection.is_connected() {
+        return;
+    }
+
+    if let Err(error) = udp_manager.update(time.seconds_since_startup()) {
+        warn!("Failed to resend messages: {}", error);
+    }
 }
@@ -0,0 +1,306 @@
+use std::collections::{HashMap, VecDeque};
+
+use bevy::prelude::*;
+use bevy_rapier3d::{physics::RigidBodyHandleComponent, rapier::dynamics::RigidBodySet};
+use nalgebra::{Quaternion, UnitQuaternion, Vector3};
+
+use crate::client::{connection::ServerConnection, in_game::ServerSnapshotEvent};
+use crate::shared::{game_message::ClientId, game_time::GameTime, gameplay::NetworkProp};
+
+pub struct InterpolationSettings {
+    // How far behind the newest snapshot remote entities are rendered, in seconds
+    pub delay: f64,
+    // How long an entity keeps moving along its last velocity when snapshots stop arriving
+    pub max_extrapolation: f64,
+    pub buffer_capacity: usize,
+}
+
+impl Default for InterpolationSettings {
+    fn default() -> Self {
+        Self {
+            delay: 0.1,
+            max_extrapolation: 0.25,
+            buffer_capacity: 32,
+        }
+    }
+}
+
+// Server time remote entities are currently rendered at
+#[derive(Debug, Default)]
+pub struct InterpolationClock {
+    pub render_time: f64,
+    latest_snapshot_time: Option<f64>,
+}
+
+impl InterpolationClock {
+    fn advance(&mut self, delta: f64, delay: f64) {
+        let latest_snapshot_time = match self.latest_snapshot_time {
+            Some(latest_snapshot_time) => latest_snapshot_time,
+            None => return,
+        };
+
+        let target_time = latest_snapshot_time - delay;
+        self.render_time += delta;
+
+        // Jump when far off (first snapshot, long stall) and drift towards the target otherwise
+        // so the rendered motion stays continuous
+        if (target_time - self.render_time).abs() > 1.0 {
+            self.render_time = target_time;
+        } else {
+            self.render_time += (target_time - self.render_time) * 0.1;
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EntitySnapshot {
+    pub time: f64,
+    pub translation: Vec3,
+    pub rotation: Quat,
+    pub linear_velocity: Vec3,
+}
+
+#[derive(Debug, Default)]
+pub struct InterpolationBuffer {
+    snapshots: VecDeque<EntitySnapshot>,
+}
+
+impl InterpolationBuffer {
+    pub fn push(&mut self, snapshot: EntitySnapshot, capacity: usize) {
+        if let Some(latest) = self.snapshots.back() {
+            // Late packets are useless once something newer was received
+            if snapshot.time <= latest.time {
+                return;
+            }
+        }
+
+        if self.snapshots.len() == capacity {
+            self.snapshots.pop_front();
+        }
+
+        self.snapshots.push_back(snapshot);
+    }
+
+    pub fn sample(&self, time: f64, max_extrapolation: f64) -> Option<(Vec3, Quat)> {
+        let oldest = self.snapshots.front()?;
+        let latest = self.snapshots.back()?;
+
+        if time <= oldest.time {
+            return Some((oldest.translation, oldest.rotation));
+        }
+
+        if time >= latest.time {
+            let extrapolation = (time - latest.time).min(max_extrapolation) as f32;
+
+            return Some((
+                latest.translation + latest.linear_velocity * extrapolation,
+                latest.rotation,
+            ));
+        }
+
+        let (from, to) = self
+            .snapshots
+            .iter()
+            .zip(self.snapshots.iter().skip(1))
+            .find(|(_, to)| to.time >= time)?;
+
+        let factor = ((time - from.time) / (to.time - from.time)) as f32;
+
+        // q and -q are the same rotation, slerp would take the long way round between them
+        let to_rotation = if from.rotation.dot(to.rotation) < 0.0 {
+            -to.rotation
+        } else {
+            to.rotation
+        };
+
+        Some((
+            from.translation.lerp(to.translation, factor),
+            from.rotation.slerp(to_rotation, factor),
+        ))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RemotePlayer {
+    pub client_id: ClientId,
+}
+
+// Entities get their buffer with the first snapshot that mentions them
+fn buffer_snapshots(
+    commands: &mut Commands,
+    entity: Entity,
+    buffer: Option<Mut<InterpolationBuffer>>,
+    entity_snapshots: Vec<EntitySnapshot>,
+    capacity: usize,
+) {
+    match buffer {
+        Some(mut buffer) => {
+            for entity_snapshot in entity_snapshots {
+                buffer.push(entity_snapshot, capacity);
+            }
+        }
+        None => {
+            let mut buffer = InterpolationBuffer::default();
+            for entity_snapshot in entity_snapshots {
+                buffer.push(entity_snapshot, capacity);
+            }
+
+            commands.entity(entity).insert(buffer);
+        }
+    }
+}
+
+// Remote players are spawned and despawned by replication, snapshots only move them
+pub fn interpolation_receive_snapshots(
+    mut commands: Commands,
+    connection: Res<ServerConnection>,
+    settings: Res<InterpolationSettings>,
+    mut clock: ResMut<InterpolationClock>,
+    mut snapshot_events: EventReader<ServerSnapshotEvent>,
+    mut remote_player_query: Query<
+        (Entity, &RemotePlayer, Option<&mut InterpolationBuffer>),
+        Without<NetworkProp>,
+    >,
+    mut prop_query: Query<
+        (Entity, &NetworkProp, Option<&mut InterpolationBuffer>),
+        Without<RemotePlayer>,
+    >,
+) {
+    let local_client_id = connection.client_id();
+
+    // Several snapshots can arrive in one frame, gather them first so buffers inserted for the
+    // first one aren't inserted again for the next
+    let mut player_snapshots: HashMap<ClientId, Vec<EntitySnapshot>> = HashMap::default();
+    let mut prop_snapshots: HashMap<u32, Vec<EntitySnapshot>> = HashMap::default();
+
+    for ServerSnapshotEvent(snapshot) in snapshot_events.iter() {
+        clock.latest_snapshot_time = Some(
+            clock
+                .latest_snapshot_time
+                .map_or(snapshot.time, |time| time.max(snapshot.time)),
+        );
+
+        for player in snapshot.players.iter() {
+            if Some(player.client_id) == local_client_id {
+                continue;
+            }
+
+            player_snapshots
+                .entry(player.client_id)
+                .or_default()
+                .push(EntitySnapshot {
+                    time: snapshot.time,
+                    translation: player.translation,
+                    rotation: player.rotation,
+                    linear_velocity: player.linear_velocity,
+                });
+        }
+
+        for prop in snapshot.props.iter() {
+            prop_snapshots
+                .entry(prop.prop_id)
+                .or_default()
+                .push(EntitySnapshot {
+                    time: snapshot.time,
+                    translation: prop.translation,
+                    rotation: prop.rotation,
+                    linear_velocity: prop.linear_velocity,
+                });
+        }
+    }
+
+    for (entity, remote_player, buffer) in remote_player_query.iter_mut() {
+        if let Some(entity_snapshots) = player_snapshots.remove(&remote_player.client_id) {
+            buffer_snapshots(
+                &mut commands,
+                entity,
+                buffer,
+                entity_snapshots,
+                settings.buffer_capacity,
+            );
+        }
+    }
+
+    for (entity, network_prop, buffer) in prop_query.iter_mut() {
+        if let Some(entity_snapshots) = prop_snapshots.remove(&network_prop.prop_id) {
+            buffer_snapshots(
+                &mut commands,
+                entity,
+                buffer,
+                entity_snapshots,
+                settings.buffer_capacity,
+            );
+        }
+    }
+}
+
+pub fn interpolation_apply(
+    time: Res<GameTime>,
+    settings: Res<InterpolationSettings>,
+    mut clock: ResMut<InterpolationClock>,
+    mut rigid_bodies: ResMut<RigidBodySet>,
+    mut remote_player_query: Query<(&InterpolationBuffer, &mut Transform), With<RemotePlayer>>,
+    prop_query: Query<(&InterpolationBuffer, &RigidBodyHandleComponent), With<NetworkProp>>,
+) {
+    clock.advance(time.delta_seconds_f64(), settings.delay);
+
+    for (buffer, mut transform) in remote_player_query.iter_mut() {
+        if let Some((translation, rotation)) =
+            buffer.sample(clock.render_time, settings.max_extrapolation)
+        {
+            transform.translation = translation;
+            transform.rotation = rotation;
+        }
+    }
+
+    // Props keep their local rigid bodies so the local player can collide with them, the body is
+    // moved to the interpolated pose instead of being simulated
+    for (buffer, rigid_body_handle) in prop_query.iter() {
+        let (translation, rotation) =
+            match buffer.sample(clock.render_time, settings.max_extrapolation) {
+                Some(sample) => sample,
+                None => continue,
+            };
+
+        if let Some(rigid_body) = rigid_bodies.get_mut(rigid_body_handle.handle()) {
+            let mut position = *rigid_body.position();
+            position.translation.vector = Vector3::new(translation.x, translation.y, translation.z);
+            position.rotation = UnitQuaternion::from_quaternion(Quaternion::new(
+                rotation.w, rotation.x, rotation.y, rotation.z,
+            ));
+
+            rigid_body.set_position(position, false);
+            rigid_body.set_linvel(Vector3::zeros(), false);
+            rigid_body.set_angvel(Vector3::zeros(), false);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(time: f64, rotation: Quat) -> EntitySnapshot {
+        EntitySnapshot {
+            time,
+            translation: Vec3::ZERO,
+            rotation,
+            linear_velocity: Vec3::ZERO,
+        }
+    }
+
+    #[test]
+    fn rotations_take_the_shortest_path() {
+        let from = Quat::from_rotation_y(0.1);
+        let to = Quat::from_rotation_y(0.3);
+
+        let mut buffer = InterpolationBuffer::default();
+        buffer.push(snapshot(1.0, from), 4);
+        buffer.push(snapshot(2.0, -to), 4);
+
+        let (_, rotation) = buffer.sample(1.5, 0.0).unwrap();
+        let expected = Quat::from_rotation_y(0.2);
+
+        assert!(rotation.dot(expected).abs() > 0.999);
+    }
+}
@@ -0,0 +1,72 @@
+use bevy::prelude::*;
+
+use crate::client::{in_game::spawn_local_player, interpolation::RemotePlayer};
+use crate::server::{session::ServerSessions, ServerPlugin};
+use crate::shared::{
+    gameplay::{LocalPlayer, NetworkPlayer, PlayerHead},
+    replication::Replicated,
+};
+
+// Runs the server inside the client. The host plays on the authoritative simulation directly,
+// remote players connect over UDP like they would to a dedicated server.
+#[derive(Default)]
+pub struct ListenServerPlugin {}
+impl Plugin for ListenServerPlugin {
+    fn build(&self, app_builder: &mut bevy::prelude::AppBuilder) {
+        app_builder
+            .add_plugin(ServerPlugin)
+            .add_startup_system(spawn_local_player.system())
+            .add_system(listen_server_register_host.system())
+            .add_system(listen_server_show_remote_players.system());
+    }
+}
+
+// Gives the host's player a local session so it is simulated and replicated like any other
+pub fn listen_server_register_host(
+    mut commands: Commands,
+    mut sessions: ResMut<ServerSessions>,
+    host_query: Query<(Entity, &Children), (With<LocalPlayer>, Without<NetworkPlayer>)>,
+    head_query: Query<(), With<PlayerHead>>,
+) {
+    for (entity, children) in host_query.iter() {
+        let client_id = sessions.connect_local();
+        info!("Hosting as client {}", client_id);
+
+        commands
+            .entity(entity)
+            .insert_bundle((NetworkPlayer { client_id }, Replicated));
+
+        for child in children.iter() {
+            if head_query.get(*child).is_ok() {
+                commands.entity(*child).insert(Replicated);
+            }
+        }
+    }
+}
+
+// Bodies of remote players are spawned by the server without anything to render
+fn listen_server_show_remote_players(
+    mut commands: Commands,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    player_query: Query<(Entity, &NetworkPlayer), (Without<LocalPlayer>, Without<RemotePlayer>)>,
+) {
+    for (entity, network_player) in player_query.iter() {
+        commands
+            .entity(entity)
+            .insert(RemotePlayer {
+                client_id: network_player.client_id,
+            })
+            .with_children(|parent| {
+                parent.spawn_bundle(PbrBundle {
+                    mesh: meshes.add(Mesh::from(shape::Capsule {
+                        depth: 1.75,
+                        radius: 0.4,
+                        ..Default::default()
+                    })),
+                    material: materials.add(Color::rgb(0.0, 0.0, 1.0).into()),
+                    ..Default::default()
+                });
+            });
+    }
+}
@@ -1,7 +1,6 @@
 use bevy::{
-    audio::AudioPlugin, pbr::PbrPlugin, prelude::*,
-    render::RenderPlugin, sprite::SpritePlugin, text::TextPlugin, ui::UiPlugin, wgpu::WgpuPlugin,
-    window::WindowPlugin, winit::WinitPlugin,
+    audio::AudioPlugin, pbr::PbrPlugin, prelude::*, render::RenderPlugin, sprite::SpritePlugin,
+    text::TextPlugin, ui::UiPlugin, wgpu::WgpuPlugin, window::WindowPlugin, winit::WinitPlugin,
 };
 
 mod steam;
@@ -10,7 +9,21 @@ use steam::SteamPlugin;
 mod developer;
 use developer::DeveloperPlugin;
 
-pub fn init(app_builder: &mut AppBuilder) {
+pub mod clock_sync;
+pub mod connection;
+pub mod in_game;
+pub mod interpolation;
+pub mod listen_server;
+pub mod network_diagnostics;
+pub mod network_events;
+pub mod prediction;
+pub mod replication;
+pub mod udp_client;
+use in_game::InGamePlugin;
+use listen_server::ListenServerPlugin;
+
+// A listen server hosts the match in this process instead of joining one
+pub fn init(app_builder: &mut AppBuilder, listen_server: bool) {
     app_builder.insert_resource(WindowDescriptor {
         title: "Radwars".to_string(),
         width: 1024.0,
@@ -19,23 +32,29 @@ pub fn init(app_builder: &mut AppBuilder) {
         ..Default::default()
     });
 
-    app_builder.add_plugins(ClientPlugins);
+    app_builder.add_plugins(ClientPlugins { listen_server });
 }
 
-pub struct ClientPlugins;
+pub struct ClientPlugins {
+    pub listen_server: bool,
+}
 impl PluginGroup for ClientPlugins {
     fn build(&mut self, group: &mut bevy::app::PluginGroupBuilder) {
         group.add(WindowPlugin::default());
         group.add(RenderPlugin::default());
-        group.add(SpritePlugin::default());
-        group.add(PbrPlugin::default());
-        group.add(UiPlugin::default());
-        group.add(TextPlugin::default());
-        group.add(AudioPlugin::default());
-        group.add(WinitPlugin::default());
-        group.add(WgpuPlugin::default());
-
-        //group.add(InGamePlugin::default()); TODO: Add this networking to gameplay plugin
+        group.add(SpritePlugin);
+        group.add(PbrPlugin);
+        group.add(UiPlugin);
+        group.add(TextPlugin);
+        group.add(AudioPlugin);
+        group.add(WinitPlugin);
+        group.add(WgpuPlugin);
+
+        if self.listen_server {
+            group.add(ListenServerPlugin::default());
+        } else {
+            group.add(InGamePlugin::default());
+        }
 
         if cfg!(feature = "steam") {
             group.add(SteamPlugin::default());
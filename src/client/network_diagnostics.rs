@@ -0,0 +1,35 @@
+use bevy::{
+    diagnostic::{Diagnostic, Diagnostics},
+    prelude::*,
+};
+
+use crate::client::{connection::ServerConnection, udp_client::UdpManager};
+use crate::shared::game_time::GameTime;
+use crate::shared::network_diagnostics::{ConnectionDiagnostics, TrafficSampler, PREDICTION_ERROR};
+
+pub fn network_diagnostics_setup(mut diagnostics: ResMut<Diagnostics>) {
+    ConnectionDiagnostics::SERVER.register(&mut diagnostics, "server");
+
+    diagnostics.add(Diagnostic::new(PREDICTION_ERROR, "prediction error", 20).with_suffix("m"));
+}
+
+pub fn network_diagnostics_measure(
+    mut traffic_sampler: Local<TrafficSampler>,
+    time: Res<GameTime>,
+    udp_manager: Res<UdpManager>,
+    connection: Res<ServerConnection>,
+    mut diagnostics: ResMut<Diagnostics>,
+) {
+    // The channel starts over with the next connection, and so do its counters
+    if !connection.is_connected() {
+        *traffic_sampler = TrafficSampler::default();
+        return;
+    }
+
+    ConnectionDiagnostics::SERVER.measure(
+        &mut diagnostics,
+        udp_manager.endpoint(),
+        &mut traffic_sampler,
+        time.seconds_since_startup(),
+    );
+}
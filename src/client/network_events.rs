@@ -0,0 +1,54 @@
+use bevy::prelude::*;
+
+use crate::client::{connection::ServerConnection, udp_client::UdpManager};
+use crate::shared::{
+    game_message::{GameMessageType, NetworkEventData},
+    game_time::GameTime,
+    network_events::{
+        NetworkEventDirection, NetworkEventInbox, NetworkEventOutbox, NetworkEventRegistry,
+    },
+};
+
+// Sent by `client_receive` for every event message from the server
+#[derive(Debug, Clone)]
+pub struct ServerNetworkEvent(pub NetworkEventData);
+
+pub fn network_events_receive(
+    registry: Res<NetworkEventRegistry>,
+    mut inbox: ResMut<NetworkEventInbox>,
+    mut network_events: EventReader<ServerNetworkEvent>,
+) {
+    for ServerNetworkEvent(event) in network_events.iter() {
+        match registry.direction(event.kind) {
+            Some(NetworkEventDirection::ToClients) => inbox.push(None, event.clone()),
+            _ => warn!(
+                "Discarded event {} from server, clients can't receive it",
+                event.kind
+            ),
+        }
+    }
+}
+
+pub fn network_events_send(
+    time: Res<GameTime>,
+    mut udp_manager: ResMut<UdpManager>,
+    connection: Res<ServerConnection>,
+    mut outbox: ResMut<NetworkEventOutbox>,
+) {
+    // Only the server sends events to clients
+    outbox.to_clients.clear();
+
+    // Nobody is there to receive them, the next session starts without them
+    if !connection.is_connected() {
+        outbox.to_server.clear();
+        return;
+    }
+
+    let now = time.seconds_since_startup();
+
+    for event in outbox.to_server.drain(..) {
+        if let Err(error) = udp_manager.send(GameMessageType::NetworkEvent(event), now) {
+            warn!("Failed to send event: {}", error);
+        }
+    }
+}
@@ -0,0 +1,501 @@
+use std::collections::VecDeque;
+
+use bevy::{diagnostic::Diagnostics, prelude::*};
+use bevy_rapier3d::{physics::RigidBodyHandleComponent, rapier::dynamics::RigidBodySet};
+use nalgebra::Vector3;
+
+use crate::client::{connection::ServerConnection, in_game::ServerSnapshotEvent};
+use crate::shared::{
+    bit_packing::quantized,
+    game_message::{sequence_greater_than, ClientInputData, PlayerStateData},
+    game_time::GameTime,
+    gameplay::{player_movement_velocity, player_turn_rate, LocalPlayer, PlayerInput},
+    network_diagnostics::PREDICTION_ERROR,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PredictedState {
+    pub translation: Vec3,
+    pub rotation: Quat,
+    pub linear_velocity: Vec3,
+}
+
+impl PredictedState {
+    // Replays a single input with the same movement rules the server uses. Collisions are not
+    // simulated, the vertical velocity is carried over from the last authoritative state instead.
+    pub fn step(&self, input: &PlayerInput, delta: f32) -> Self {
+        let linear_velocity = player_movement_velocity(input, self.rotation, self.linear_velocity);
+        let rotation = self.rotation * Quat::from_rotation_y(player_turn_rate(input) * delta);
+
+        Self {
+            translation: self.translation + linear_velocity * delta,
+            rotation: rotation.normalize(),
+            linear_velocity,
+        }
+    }
+}
+
+impl From<&PlayerStateData> for PredictedState {
+    fn from(state: &PlayerStateData) -> Self {
+        Self {
+            translation: state.translation,
+            rotation: state.rotation,
+            linear_velocity: state.linear_velocity,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PendingInput {
+    pub input: ClientInputData,
+    pub delta: f32,
+}
+
+pub struct ClientPrediction {
+    // Inputs sent to the server that it hasn't acknowledged yet, oldest first
+    pending_inputs: VecDeque<PendingInput>,
+    capacity: usize,
+    next_sequence: u32,
+    // Offset still to be applied to the local player to reach the reconciled position
+    correction: Vec3,
+    // Distance between the prediction and the reconciled state found by the latest reconcile
+    latest_error: Option<f32>,
+    pub tolerance: f32,
+    pub snap_distance: f32,
+    pub smoothing_rate: f32,
+}
+
+impl Default for ClientPrediction {
+    fn default() -> Self {
+        Self::new(128)
+    }
+}
+
+impl ClientPrediction {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            pending_inputs: VecDeque::with_capacity(capacity),
+            capacity,
+            next_sequence: 1,
+            correction: Vec3::ZERO,
+            latest_error: None,
+            tolerance: 0.05,
+            snap_distance: 3.0,
+            smoothing_rate: 10.0,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn pending_inputs(&self) -> impl Iterator<Item = &PendingInput> {
+        self.pending_inputs.iter()
+    }
+
+    pub fn correction(&self) -> Vec3 {
+        self.correction
+    }
+
+    pub fn latest_error(&self) -> Option<f32> {
+        self.latest_error
+    }
+
+    // Tags the input with the next sequence number and keeps it around for replaying
+    pub fn record_input(
+        &mut self,
+        player_input: &PlayerInput,
+        tick: u32,
+        delta: f32,
+    ) -> ClientInputData {
+        // Predict with the same quantized input the server will receive
+        let mut input = quantized(&ClientInputData::from(player_input));
+        input.sequence = self.next_sequence;
+        input.tick = tick;
+        self.next_sequence = self.next_sequence.wrapping_add(1);
+
+        if self.pending_inputs.len() == self.capacity {
+            self.pending_inputs.pop_front();
+        }
+
+        self.pending_inputs.push_back(PendingInput {
+            input: input.clone(),
+            delta,
+        });
+
+        input
+    }
+
+    // Up to `count` of the newest inputs the server hasn't acknowledged, oldest first
+    pub fn unacknowledged_inputs(&self, count: usize) -> Vec<ClientInputData> {
+        let skip = self.pending_inputs.len().saturating_sub(count);
+
+        self.pending_inputs
+            .iter()
+            .skip(skip)
+            .map(|pending_input| pending_input.input.clone())
+            .collect()
+    }
+
+    // Rewinds to the server state, replays every input the server hasn't processed yet and
+    // returns the reconciled state if it disagrees with the current prediction. Small
+    // disagreements are queued in `correction` so they can be smoothed out over a few frames.
+    pub fn reconcile(
+        &mut self,
+        server_state: PredictedState,
+        last_processed_input: u32,
+        current_state: PredictedState,
+    ) -> Option<PredictedState> {
+        while let Some(pending_input) = self.pending_inputs.front() {
+            if sequence_greater_than(pending_input.input.sequence, last_processed_input) {
+                break;
+            }
+
+            self.pending_inputs.pop_front();
+        }
+
+        let reconciled_state =
+            self.pending_inputs
+                .iter()
+                .fold(server_state, |state, pending_input| {
+                    state.step(
+                        &PlayerInput::from(&pending_input.input),
+                        pending_input.delta,
+                    )
+                });
+
+        let error = reconciled_state.translation - current_state.translation;
+        self.latest_error = Some(error.length());
+
+        // Within tolerance the prediction is considered correct, the leftover is still smoothed
+        // away so errors can't accumulate
+        if error.length() <= self.tolerance {
+            self.correction = error;
+            return None;
+        }
+
+        self.correction = if error.length() > self.snap_distance {
+            Vec3::ZERO
+        } else {
+            error
+        };
+
+        Some(reconciled_state)
+    }
+
+    // Portion of the outstanding correction to apply this frame
+    pub fn smooth_correction(&mut self, delta: f32) -> Vec3 {
+        let step = self.correction * (1.0 - (-self.smoothing_rate * delta).exp());
+        self.correction -= step;
+
+        if self.correction.length() <= self.tolerance * 0.1 {
+            let remaining = self.correction;
+            self.correction = Vec3::ZERO;
+
+            return step + remaining;
+        }
+
+        step
+    }
+}
+
+pub fn prediction_reconcile(
+    connection: Res<ServerConnection>,
+    mut diagnostics: ResMut<Diagnostics>,
+    mut prediction: ResMut<ClientPrediction>,
+    mut rigid_bodies: ResMut<RigidBodySet>,
+    mut snapshot_events: EventReader<ServerSnapshotEvent>,
+    player_query: Query<&RigidBodyHandleComponent, With<LocalPlayer>>,
+) {
+    let client_id = match connection.client_id() {
+        Some(client_id) => client_id,
+        None => return,
+    };
+
+    for ServerSnapshotEvent(snapshot) in snapshot_events.iter() {
+        let server_state = match snapshot
+            .players
+            .iter()
+            .find(|player| player.client_id == client_id)
+        {
+            Some(player) => PredictedState::from(player),
+            None => continue,
+        };
+
+        for rigid_body_handle in player_query.iter() {
+            let rigid_body = match rigid_bodies.get_mut(rigid_body_handle.handle()) {
+                Some(rigid_body) => rigid_body,
+                None => continue,
+            };
+
+            // The transform is interpolated between ticks for rendering, the body has the pose
+            // the inputs were predicted up to
+            let position = rigid_body.position();
+            let (translation, rotation) = (position.translation.vector, position.rotation);
+            let linvel = rigid_body.linvel();
+            let current_state = PredictedState {
+                translation: Vec3::new(translation.x, translation.y, translation.z),
+                rotation: Quat::from_xyzw(rotation.i, rotation.j, rotation.k, rotation.w),
+                linear_velocity: Vec3::new(linvel.x, linvel.y, linvel.z),
+            };
+
+            let reconciled =
+                prediction.reconcile(server_state, snapshot.last_processed_input, current_state);
+
+            if let Some(error) = prediction.latest_error() {
+                diagnostics.add_measurement(PREDICTION_ERROR, error as f64);
+            }
+
+            if let Some(reconciled_state) = reconciled {
+                let velocity = reconciled_state.linear_velocity;
+                rigid_body.set_linvel(Vector3::new(velocity.x, velocity.y, velocity.z), true);
+
+                // Anything that isn't smoothed has to be applied right away
+                if prediction.correction() == Vec3::ZERO {
+                    let mut position = *rigid_body.position();
+                    let translation = reconciled_state.translation;
+                    position.translation.vector =
+                        Vector3::new(translation.x, translation.y, translation.z);
+
+                    rigid_body.set_position(position, true);
+                }
+            }
+        }
+    }
+}
+
+pub fn prediction_smooth_correction(
+    time: Res<GameTime>,
+    mut prediction: ResMut<ClientPrediction>,
+    mut rigid_bodies: ResMut<RigidBodySet>,
+    player_query: Query<&RigidBodyHandleComponent, With<LocalPlayer>>,
+) {
+    if prediction.correction() == Vec3::ZERO {
+        return;
+    }
+
+    let step = prediction.smooth_correction(time.delta_seconds());
+
+    for rigid_body_handle in player_query.iter() {
+        if let Some(rigid_body) = rigid_bodies.get_mut(rigid_body_handle.handle()) {
+            let mut position = *rigid_body.position();
+            position.translation.vector += Vector3::new(step.x, step.y, step.z);
+
+            rigid_body.set_position(position, true);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DELTA: f32 = 1.0 / 20.0;
+
+    fn input_for_tick(tick: u32) -> PlayerInput {
+        PlayerInput {
+            move_forward: tick % 40 < 25,
+            move_right: tick % 15 < 5,
+            mouse_horizontal: if tick.is_multiple_of(10) { 0.5 } else { 0.0 },
+            ..Default::default()
+        }
+    }
+
+    fn initial_state() -> PredictedState {
+        PredictedState {
+            translation: Vec3::new(5.0, 1.0, -5.0),
+            rotation: Quat::IDENTITY,
+            linear_velocity: Vec3::ZERO,
+        }
+    }
+
+    struct SimulatedServer {
+        state: PredictedState,
+        last_processed_input: u32,
+        // Inputs in flight towards the server, paired with the tick they arrive at
+        incoming: VecDeque<(u32, ClientInputData)>,
+        // Snapshots in flight towards the client
+        outgoing: VecDeque<(u32, PredictedState, u32)>,
+        latency_ticks: u32,
+    }
+
+    impl SimulatedServer {
+        fn new(latency_ticks: u32) -> Self {
+            Self {
+                state: initial_state(),
+                last_processed_input: 0,
+                incoming: VecDeque::new(),
+                outgoing: VecDeque::new(),
+                latency_ticks,
+            }
+        }
+
+        fn send_input(&mut self, tick: u32, input: ClientInputData) {
+            self.incoming.push_back((tick + self.latency_ticks, input));
+        }
+
+        fn update(&mut self, tick: u32) {
+            while let Some((arrival, _)) = self.incoming.front() {
+                if *arrival > tick {
+                    break;
+                }
+
+                let (_, input) = self.incoming.pop_front().unwrap();
+                self.state = self.state.step(&PlayerInput::from(&input), DELTA);
+                self.last_processed_input = input.sequence;
+            }
+
+            self.outgoing.push_back((
+                tick + self.latency_ticks,
+                self.state,
+                self.last_processed_input,
+            ));
+        }
+
+        fn receive_snapshots(&mut self, tick: u32) -> Vec<(PredictedState, u32)> {
+            let mut snapshots = Vec::new();
+
+            while let Some((arrival, _, _)) = self.outgoing.front() {
+                if *arrival > tick {
+                    break;
+                }
+
+                let (_, state, last_processed_input) = self.outgoing.pop_front().unwrap();
+                snapshots.push((state, last_processed_input));
+            }
+
+            snapshots
+        }
+    }
+
+    // Runs the client against the simulated server, `diverge` may disturb the server state
+    // before each tick. Returns the client state and the number of corrections made.
+    fn run(
+        ticks: u32,
+        server: &mut SimulatedServer,
+        prediction: &mut ClientPrediction,
+        diverge: impl Fn(u32, &mut PredictedState),
+    ) -> (PredictedState, usize) {
+        let mut client_state = initial_state();
+        let mut corrections = 0;
+
+        for tick in 0..ticks + server.latency_ticks * 2 {
+            if tick < ticks {
+                let player_input = input_for_tick(tick);
+                let input = prediction.record_input(&player_input, tick, DELTA);
+
+                client_state = client_state.step(&player_input, DELTA);
+                server.send_input(tick, input);
+            }
+
+            diverge(tick, &mut server.state);
+            server.update(tick);
+
+            for (server_state, last_processed_input) in server.receive_snapshots(tick) {
+                if let Some(reconciled_state) =
+                    prediction.reconcile(server_state, last_processed_input, client_state)
+                {
+                    corrections += 1;
+
+                    client_state.linear_velocity = reconciled_state.linear_velocity;
+                    client_state.rotation = reconciled_state.rotation;
+
+                    if prediction.correction() == Vec3::ZERO {
+                        client_state.translation = reconciled_state.translation;
+                    }
+                }
+            }
+
+            client_state.translation += prediction.smooth_correction(DELTA);
+        }
+
+        (client_state, corrections)
+    }
+
+    #[test]
+    fn matching_simulation_needs_no_corrections() {
+        let mut server = SimulatedServer::new(3);
+        let mut prediction = ClientPrediction::default();
+
+        let (client_state, corrections) = run(200, &mut server, &mut prediction, |_, _| {});
+
+        assert_eq!(corrections, 0);
+        assert_eq!(prediction.pending_inputs().count(), 0);
+        assert!((client_state.translation - server.state.translation).length() < 1e-3);
+    }
+
+    #[test]
+    fn small_divergence_is_smoothed_out() {
+        let mut server = SimulatedServer::new(3);
+        let mut prediction = ClientPrediction::default();
+
+        let (client_state, corrections) = run(200, &mut server, &mut prediction, |tick, state| {
+            // Something the client couldn't predict, like a collision, pushes the player aside
+            if tick == 50 {
+                state.translation.x += 0.5;
+            }
+        });
+
+        assert!(corrections > 0);
+        assert_eq!(prediction.correction(), Vec3::ZERO);
+        assert!((client_state.translation - server.state.translation).length() < 1e-3);
+    }
+
+    #[test]
+    fn large_divergence_snaps_immediately() {
+        let mut server = SimulatedServer::new(2);
+        let mut prediction = ClientPrediction::default();
+
+        let mut client_state = initial_state();
+        let player_input = input_for_tick(0);
+
+        for tick in 0..4 {
+            let input = prediction.record_input(&player_input, tick, DELTA);
+            client_state = client_state.step(&player_input, DELTA);
+            server.send_input(tick, input);
+        }
+
+        // The server teleported the player (respawn) and processed the first two inputs
+        let mut server_state = initial_state();
+        server_state.translation.y += 10.0;
+        server_state = server_state.step(&player_input, DELTA);
+        server_state = server_state.step(&player_input, DELTA);
+
+        let reconciled_state = prediction
+            .reconcile(server_state, 2, client_state)
+            .expect("teleport must be corrected");
+
+        // Replay uses the input as the server received it
+        let sent_input = PlayerInput::from(&quantized(&ClientInputData::from(&player_input)));
+        let expected_state = server_state
+            .step(&sent_input, DELTA)
+            .step(&sent_input, DELTA);
+
+        assert_eq!(prediction.correction(), Vec3::ZERO);
+        assert!((prediction.latest_error().unwrap() - 10.0).abs() < 0.01);
+        assert_eq!(prediction.pending_inputs().count(), 2);
+        assert!((reconciled_state.translation - expected_state.translation).length() < 1e-5);
+    }
+
+    #[test]
+    fn input_buffer_drops_oldest_when_full() {
+        let mut prediction = ClientPrediction::new(4);
+        let player_input = PlayerInput::default();
+
+        for tick in 0..10 {
+            prediction.record_input(&player_input, tick, DELTA);
+        }
+
+        let sequences: Vec<u32> = prediction
+            .pending_inputs()
+            .map(|pending_input| pending_input.input.sequence)
+            .collect();
+
+        assert_eq!(sequences, vec![7, 8, 9, 10]);
+
+        let redundant: Vec<u32> = prediction
+            .unacknowledged_inputs(2)
+            .iter()
+            .map(|input| input.tick)
+            .collect();
+
+        assert_eq!(redundant, vec![8, 9]);
+    }
+}
@@ -0,0 +1,225 @@
+use std::collections::{HashMap, HashSet};
+
+use bevy::{prelude::*, transform::hierarchy::despawn_with_children_recursive};
+
+use crate::client::{connection::ServerConnection, interpolation::RemotePlayer};
+use crate::shared::{
+    game_message::{
+        ClientId, ComponentUpdatesData, EntitiesDespawnedData, EntitiesSpawnedData,
+        EntitySpawnData, NetworkId, NetworkPrefab,
+    },
+    gameplay::{LocalPlayer, NetworkProp, PlayerHead},
+    replication::apply_components,
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ReplicationMessage {
+    Spawned(EntitiesSpawnedData),
+    Despawned(EntitiesDespawnedData),
+    Updated(ComponentUpdatesData),
+}
+
+// Filled by `client_receive`, applied in the order the server sent the messages
+#[derive(Debug, Default)]
+pub struct ReplicationInbox {
+    messages: Vec<ReplicationMessage>,
+}
+
+impl ReplicationInbox {
+    pub fn push(&mut self, message: ReplicationMessage) {
+        self.messages.push(message);
+    }
+}
+
+// Local entities of everything the server replicates to us
+#[derive(Debug, Default)]
+pub struct NetworkEntities {
+    entities: HashMap<NetworkId, Entity>,
+    // Entities that existed before the server spawned them, like the local player and the props
+    // of the map. They are only ever forgotten, never despawned.
+    adopted: HashSet<NetworkId>,
+}
+
+impl NetworkEntities {
+    pub fn get(&self, network_id: NetworkId) -> Option<Entity> {
+        self.entities.get(&network_id).copied()
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.entities.len()
+    }
+
+    fn despawn(&mut self, world: &mut World, network_id: NetworkId) {
+        let entity = match self.entities.remove(&network_id) {
+            Some(entity) => entity,
+            None => return,
+        };
+
+        // Children the server despawned along with their parent may be gone already
+        if world.get_entity(entity).is_none() {
+            self.adopted.remove(&network_id);
+        } else if self.adopted.remove(&network_id) {
+            world.entity_mut(entity).remove::<NetworkId>();
+        } else {
+            despawn_with_children_recursive(world, entity);
+        }
+    }
+
+    fn clear(&mut self, world: &mut World) {
+        let network_ids = self.entities.keys().copied().collect::<Vec<_>>();
+
+        for network_id in network_ids {
+            self.despawn(world, network_id);
+        }
+    }
+}
+
+fn find_local_player(world: &mut World) -> Option<Entity> {
+    world
+        .query_filtered::<Entity, With<LocalPlayer>>()
+        .iter(world)
+        .next()
+}
+
+fn find_local_head(world: &World, parent: Option<Entity>) -> Option<Entity> {
+    let parent = parent?;
+    world.get::<LocalPlayer>(parent)?;
+
+    world
+        .get::<Children>(parent)?
+        .iter()
+        .copied()
+        .find(|child| world.get::<PlayerHead>(*child).is_some())
+}
+
+fn find_prop(world: &mut World, prop_id: u32) -> Option<Entity> {
+    world
+        .query::<(Entity, &NetworkProp)>()
+        .iter(world)
+        .find(|(_, network_prop)| network_prop.prop_id == prop_id)
+        .map(|(entity, _)| entity)
+}
+
+fn spawn_entity(
+    world: &mut World,
+    network_entities: &mut NetworkEntities,
+    client_id: Option<ClientId>,
+    spawn: EntitySpawnData,
+) {
+    let parent = spawn.parent.and_then(|parent| network_entities.get(parent));
+
+    let adopted = match spawn.prefab {
+        Some(NetworkPrefab::Player { client_id: id }) if Some(id) == client_id => {
+            find_local_player(world)
+        }
+        Some(NetworkPrefab::PlayerHead) => find_local_head(world, parent),
+        Some(NetworkPrefab::Prop { prop_id }) => find_prop(world, prop_id),
+        _ => None,
+    };
+
+    let entity = match adopted {
+        Some(entity) => {
+            network_entities.adopted.insert(spawn.network_id);
+            entity
+        }
+        None => {
+            let mut entity_mut = world.spawn();
+            entity_mut.insert_bundle((
+                Transform {
+                    translation: spawn.translation,
+                    rotation: spawn.rotation,
+                    ..Default::default()
+                },
+                GlobalTransform::identity(),
+            ));
+
+            match spawn.prefab {
+                Some(NetworkPrefab::Player { client_id: id }) if Some(id) != client_id => {
+                    entity_mut.insert(RemotePlayer { client_id: id });
+                }
+                Some(NetworkPrefab::PlayerHead) => {
+                    entity_mut.insert(PlayerHead);
+                }
+                Some(NetworkPrefab::Prop { prop_id }) => {
+                    entity_mut.insert(NetworkProp { prop_id });
+                }
+                _ => {}
+            }
+
+            let entity = entity_mut.id();
+            if let Some(parent) = parent {
+                world.entity_mut(parent).push_children(&[entity]);
+            }
+
+            entity
+        }
+    };
+
+    world.entity_mut(entity).insert(spawn.network_id);
+    network_entities.entities.insert(spawn.network_id, entity);
+
+    if let Err(error) = apply_components(world, entity, &spawn.components) {
+        warn!(
+            "Failed to apply components of {:?}: {}",
+            spawn.network_id, error
+        );
+    }
+}
+
+// Exclusive, entities are spawned and components inserted through reflection
+pub fn replication_apply(world: &mut World) {
+    let connection = world.get_resource::<ServerConnection>().unwrap();
+    let is_connected = connection.is_connected();
+    let client_id = connection.client_id();
+
+    let mut messages = std::mem::take(
+        &mut world
+            .get_resource_mut::<ReplicationInbox>()
+            .unwrap()
+            .messages,
+    );
+    let mut network_entities =
+        std::mem::take(&mut *world.get_resource_mut::<NetworkEntities>().unwrap());
+
+    // Whatever the server spawned goes away with the connection, the next one spawns it again
+    if !is_connected {
+        messages.clear();
+        network_entities.clear(world);
+    }
+
+    for message in messages {
+        match message {
+            ReplicationMessage::Spawned(spawned) => {
+                for spawn in spawned.entities {
+                    spawn_entity(world, &mut network_entities, client_id, spawn);
+                }
+            }
+            ReplicationMessage::Despawned(despawned) => {
+                for network_id in despawned.network_ids {
+                    network_entities.despawn(world, network_id);
+                }
+            }
+            ReplicationMessage::Updated(updated) => {
+                for update in updated.entities {
+                    let entity = match network_entities.get(update.network_id) {
+                        Some(entity) => entity,
+                        None => {
+                            warn!("Received components of unknown {:?}", update.network_id);
+                            continue;
+                        }
+                    };
+
+                    if let Err(error) = apply_components(world, entity, &update.components) {
+                        warn!(
+                            "Failed to apply components of {:?}: {}",
+                            update.network_id, error
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    *world.get_resource_mut::<NetworkEntities>().unwrap() = network_entities;
+}
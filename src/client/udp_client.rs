@@ -0,0 +1,230 @@
+use std::{
+    collections::VecDeque,
+    error::Error,
+    fmt, io,
+    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+};
+
+use crate::shared::{
+    channel::ChannelEndpoint,
+    fragmentation::{is_fragment, FragmentError, FragmentReassembler, Fragmenter, MAX_PACKET_SIZE},
+    game_message::{GameMessage, GameMessageError, GameMessageType},
+    network_conditions::{ConditionedTransport, LinkEnd, NetworkConditioner},
+    transport::Transport,
+};
+
+pub struct UdpManager {
+    transport: Box<dyn Transport>,
+    buffer: Vec<u8>,
+    endpoint: ChannelEndpoint,
+    fragmenter: Fragmenter,
+    reassembler: FragmentReassembler,
+    // Messages already through the channel but not handed out yet
+    received: VecDeque<(GameMessageType, usize)>,
+    last_message_size: usize,
+    server_address: Option<SocketAddr>,
+}
+
+impl UdpManager {
+    pub fn new(address: &str, buffer_size: usize, non_blocking: bool) -> io::Result<Self> {
+        let socket = UdpSocket::bind(address)?;
+        socket.set_nonblocking(non_blocking)?;
+
+        Ok(Self::with_transport(Box::new(socket), buffer_size))
+    }
+
+    pub fn with_transport(transport: Box<dyn Transport>, buffer_size: usize) -> Self {
+        Self {
+            transport,
+            // Anything smaller would truncate full sized fragments
+            buffer: vec![0; buffer_size.max(MAX_PACKET_SIZE)],
+            endpoint: ChannelEndpoint::default(),
+            fragmenter: Fragmenter::default(),
+            reassembler: FragmentReassembler::default(),
+            received: VecDeque::default(),
+            last_message_size: 0,
+            server_address: None,
+        }
+    }
+
+    // Simulates a bad network on everything the client sends
+    pub fn with_network_conditioner(mut self, network_conditioner: NetworkConditioner) -> Self {
+        self.transport = Box::new(ConditionedTransport::new(
+            self.transport,
+            network_conditioner,
+            LinkEnd::Client,
+        ));
+        self
+    }
+
+    pub fn connect(&mut self, address: &str) -> io::Result<()> {
+        let server_address = address.to_socket_addrs()?.next().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} does not resolve to an address", address),
+            )
+        })?;
+
+        self.server_address = Some(server_address);
+
+        Ok(())
+    }
+
+    // Starts over with a fresh channel, the server forgets ours when the session ends
+    pub fn reset_channel(&mut self) {
+        self.endpoint = ChannelEndpoint::default();
+        self.reassembler = FragmentReassembler::default();
+        self.received.clear();
+    }
+
+    pub fn endpoint(&self) -> &ChannelEndpoint {
+        &self.endpoint
+    }
+
+    // Encoded size of the message handed out last, fragments put back together
+    pub fn last_message_size(&self) -> usize {
+        self.last_message_size
+    }
+
+    pub fn send(&mut self, content: GameMessageType, now: f64) -> Result<(), UdpSendError> {
+        let server_address = self.server_address.ok_or(UdpSendError::NotConnected)?;
+
+        let message = self.endpoint.send(content, now);
+        self.send_message(server_address, &message)
+    }
+
+    fn send_message(
+        &mut self,
+        server_address: SocketAddr,
+        message: &GameMessage,
+    ) -> Result<(), UdpSendError> {
+        let bytes = message.encode().map_err(UdpSendError::Encode)?;
+        let datagrams = self
+            .fragmenter
+            .split(bytes)
+            .map_err(UdpSendError::Fragment)?;
+
+        for datagram in datagrams.iter() {
+            self.transport
+                .send_to(datagram, server_address)
+                .map_err(UdpSendError::Io)?;
+
+            self.endpoint.record_bytes_sent(datagram.len());
+        }
+
+        Ok(())
+    }
+
+    // Resends unacknowledged reliable messages and sends acks that are due
+    pub fn update(&mut self, now: f64) -> Result<(), UdpSendError> {
+        let server_address = self.server_address.ok_or(UdpSendError::NotConnected)?;
+
+        self.reassembler.expire(now);
+
+        for message in self.endpoint.update(now) {
+            self.send_message(server_address, &message)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn receive(&mut self, now: f64) -> Result<Option<GameMessageType>, UdpReceiveError> {
+        loop {
+            if let Some((content, size)) = self.received.pop_front() {
+                self.last_message_size = size;
+                return Ok(Some(content));
+            }
+
+            let (length, sender) = match self.transport.receive_from(&mut self.buffer) {
+                Ok(Some(received)) => received,
+                Ok(None) => return Ok(None),
+                Err(error) => return Err(UdpReceiveError::Io(error)),
+            };
+
+            // Only the server is allowed to talk to us
+            if Some(sender) != self.server_address {
+                continue;
+            }
+
+            let datagram = &self.buffer[..length];
+            self.endpoint.record_bytes_received(length);
+
+            let (message, size) = if is_fragment(datagram) {
+                match self.reassembler.receive(datagram, now) {
+                    Ok(Some(bytes)) => (GameMessage::decode(&bytes), bytes.len()),
+                    Ok(None) => continue,
+                    Err(error) => return Err(UdpReceiveError::Fragment(error)),
+                }
+            } else {
+                (GameMessage::decode(datagram), length)
+            };
+            let message = message.map_err(UdpReceiveError::Decode)?;
+
+            self.received.extend(
+                self.endpoint
+                    .receive(message, now)
+                    .into_iter()
+                    .map(|content| (content, size)),
+            );
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum UdpSendError {
+    NotConnected,
+    Encode(GameMessageError),
+    Fragment(FragmentError),
+    Io(io::Error),
+}
+
+impl fmt::Display for UdpSendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UdpSendError::NotConnected => write!(f, "socket is not connected to a server"),
+            UdpSendError::Encode(error) => write!(f, "failed to encode message: {}", error),
+            UdpSendError::Fragment(error) => write!(f, "failed to split message: {}", error),
+            UdpSendError::Io(error) => write!(f, "failed to send message: {}", error),
+        }
+    }
+}
+
+impl Error for UdpSendError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            UdpSendError::NotConnected => None,
+            UdpSendError::Encode(error) => Some(error),
+            UdpSendError::Fragment(error) => Some(error),
+            UdpSendError::Io(error) => Some(error),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum UdpReceiveError {
+    Decode(GameMessageError),
+    Fragment(FragmentError),
+    Io(io::Error),
+}
+
+impl fmt::Display for UdpReceiveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UdpReceiveError::Decode(error) => write!(f, "failed to decode message: {}", error),
+            UdpReceiveError::Fragment(error) => {
+                write!(f, "failed to reassemble message: {}", error)
+            }
+            UdpReceiveError::Io(error) => write!(f, "failed to receive message: {}", error),
+        }
+    }
+}
+
+impl Error for UdpReceiveError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            UdpReceiveError::Decode(error) => Some(error),
+            UdpReceiveError::Fragment(error) => Some(error),
+            UdpReceiveError::Io(error) => Some(error),
+        }
+    }
+}
@@ -0,0 +1,892 @@
+use std::{collections::HashSet, net::SocketAddr, thread, time::Duration};
+
+use bevy::{
+    app::{AppExit, Events},
+    core::CorePlugin,
+    diagnostic::Diagnostics,
+    ecs::system::CommandQueue,
+    log::LogPlugin,
+    prelude::*,
+    transform::TransformPlugin,
+};
+use bevy_rapier3d::{
+    physics::RapierPhysicsPlugin,
+    rapier::{
+        dynamics::RigidBodyBuilder,
+        geometry::{ColliderBuilder, ColliderSet},
+    },
+};
+use serde::{Deserialize, Serialize};
+
+use crate::client::{
+    clock_sync::ClockSync,
+    connection::{ConnectionState, ServerConnection},
+    in_game::ClientNetworkPlugin,
+    interpolation::RemotePlayer,
+    listen_server::listen_server_register_host,
+    replication::NetworkEntities,
+    udp_client::UdpManager,
+};
+use crate::server::{
+    relevancy::{AlwaysRelevant, RelevancySettings},
+    session::ServerSessions,
+    snapshot_budget::SnapshotBudgetSettings,
+    udp_server::{UdpServer, UdpServerBuilder},
+    ServerPlugin, ServerPlugins, ServerSettings,
+};
+use crate::shared::{
+    game_message::{ClientId, ConnectionDeniedReason, GameMessage, GameMessageType},
+    game_time::{GameTime, GameTimePlugin, ManualClock},
+    gameplay::{spawn_player_body, FixedTickPlugin, LocalPlayer, NetworkPlayer, NetworkProp},
+    network_conditions::{NetworkConditioner, NetworkConditions},
+    network_diagnostics::ConnectionDiagnostics,
+    network_events::{EventRecipients, FromClient, NetworkEventAppExt, RateLimit, ToClients},
+    replication::{Replicated, ReplicationAppExt},
+    snapshot_delta::SnapshotHistory,
+    transport::{LoopbackNetwork, Transport},
+    SharedPlugins,
+};
+
+const SERVER_ADDRESS: &str = "127.0.0.1:8311";
+// Seconds every step of the harness lets pass, one tick of the server
+const STEP: f64 = 1.0 / 60.0;
+
+// A server and any number of clients in one process, talking over a loopback network
+struct LoopbackHarness {
+    // Shared by every app and the network, nothing in the harness reads the wall clock
+    manual_clock: ManualClock,
+    network: LoopbackNetwork,
+    network_conditioner: NetworkConditioner,
+    // Declarations shared code would make on either side, like network events
+    shared_setup: fn(&mut AppBuilder),
+    server: App,
+    clients: Vec<App>,
+}
+
+// Just what the server needs to run, without loading the map
+fn minimal_server_plugins(app_builder: &mut AppBuilder) {
+    app_builder
+        .add_plugin(CorePlugin)
+        .add_plugin(GameTimePlugin)
+        .add_plugin(TransformPlugin)
+        .add_plugin(RapierPhysicsPlugin)
+        .add_plugin(FixedTickPlugin)
+        .add_plugin(ServerPlugin);
+}
+
+// What `radwars server` runs with
+fn dedicated_server_plugins(app_builder: &mut AppBuilder) {
+    app_builder
+        // Tests share one process, which only takes one global logger
+        .add_plugins_with(SharedPlugins, |group| group.disable::<LogPlugin>())
+        .add_plugins(ServerPlugins);
+}
+
+impl LoopbackHarness {
+    fn new() -> Self {
+        Self::build(NetworkConditions::default(), minimal_server_plugins, |_| {})
+    }
+
+    // Every side applies the conditions to what it sends
+    fn with_network_conditions(network_conditions: NetworkConditions) -> Self {
+        Self::build(network_conditions, minimal_server_plugins, |_| {})
+    }
+
+    fn with_server_plugins(server_plugins: fn(&mut AppBuilder)) -> Self {
+        Self::build(NetworkConditions::default(), server_plugins, |_| {})
+    }
+
+    fn with_shared_setup(shared_setup: fn(&mut AppBuilder)) -> Self {
+        Self::build(
+            NetworkConditions::default(),
+            minimal_server_plugins,
+            shared_setup,
+        )
+    }
+
+    fn build(
+        network_conditions: NetworkConditions,
+        server_plugins: fn(&mut AppBuilder),
+        shared_setup: fn(&mut AppBuilder),
+    ) -> Self {
+        let manual_clock = ManualClock::default();
+        let network = LoopbackNetwork::default();
+        let network_conditioner =
+            NetworkConditioner::new(network_conditions).with_manual_clock(manual_clock.clone());
+        let transport = network.bind(SERVER_ADDRESS.parse().unwrap()).unwrap();
+
+        let mut app_builder = App::build();
+        app_builder
+            .insert_resource(GameTime::from_manual_clock(manual_clock.clone()))
+            .insert_resource(network_conditioner.clone())
+            .insert_resource(
+                UdpServerBuilder::default()
+                    .with_max_clients(4)
+                    .with_network_conditioner(network_conditioner.clone())
+                    .build_with_transport(Box::new(transport)),
+            );
+        server_plugins(&mut app_builder);
+        shared_setup(&mut app_builder);
+
+        Self {
+            manual_clock,
+            network,
+            network_conditioner,
+            shared_setup,
+            server: app_builder.app,
+            clients: Vec::new(),
+        }
+    }
+
+    fn add_client(&mut self) -> usize {
+        let address = SocketAddr::from(([127, 0, 0, 1], 9000 + self.clients.len() as u16));
+        let client = self.build_client(address);
+
+        self.clients.push(client);
+        self.clients.len() - 1
+    }
+
+    // Starts the client over on the address it had, like a game that crashed and came back
+    fn restart_client(&mut self, client: usize) {
+        // The old app has to let go of the address first
+        self.clients[client] = App::default();
+
+        let address = SocketAddr::from(([127, 0, 0, 1], 9000 + client as u16));
+        self.clients[client] = self.build_client(address);
+    }
+
+    fn build_client(&self, address: SocketAddr) -> App {
+        let transport = self.network.bind(address).unwrap();
+
+        let mut udp_manager = UdpManager::with_transport(Box::new(transport), 1024)
+            .with_network_conditioner(self.network_conditioner.clone());
+        udp_manager.connect(SERVER_ADDRESS).unwrap();
+
+        let mut app_builder = App::build();
+        app_builder
+            .insert_resource(GameTime::from_manual_clock(self.manual_clock.clone()))
+            .add_plugin(CorePlugin)
+            .add_plugin(GameTimePlugin)
+            .insert_resource(self.network_conditioner.clone())
+            .insert_resource(udp_manager)
+            .add_plugin(ClientNetworkPlugin::default());
+        (self.shared_setup)(&mut app_builder);
+
+        app_builder.app
+    }
+
+    fn step(&mut self) {
+        self.manual_clock.advance(STEP);
+
+        self.server.update();
+
+        for client in self.clients.iter_mut() {
+            client.update();
+        }
+    }
+
+    fn run_until(&mut self, max_steps: usize, predicate: impl Fn(&Self) -> bool) -> bool {
+        for _ in 0..max_steps {
+            self.step();
+
+            if predicate(self) {
+                return true;
+            }
+        }
+
+        false
+    }
+
+    fn client_id(&self, client: usize) -> Option<ClientId> {
+        self.clients[client]
+            .world
+            .get_resource::<ServerConnection>()
+            .unwrap()
+            .client_id()
+    }
+
+    fn network_entities(&self, client: usize) -> usize {
+        self.clients[client]
+            .world
+            .get_resource::<NetworkEntities>()
+            .unwrap()
+            .len()
+    }
+
+    fn latest_snapshot_players(&self, client: usize) -> Vec<ClientId> {
+        let history = self.clients[client]
+            .world
+            .get_resource::<SnapshotHistory>()
+            .unwrap();
+
+        history
+            .latest_tick()
+            .and_then(|tick| history.get(tick))
+            .map(|snapshot| {
+                snapshot
+                    .players
+                    .iter()
+                    .map(|player| player.client_id)
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    fn latest_snapshot_props(&self, client: usize) -> Vec<u32> {
+        let history = self.clients[client]
+            .world
+            .get_resource::<SnapshotHistory>()
+            .unwrap();
+
+        history
+            .latest_tick()
+            .and_then(|tick| history.get(tick))
+            .map(|snapshot| snapshot.props.iter().map(|prop| prop.prop_id).collect())
+            .unwrap_or_default()
+    }
+
+    fn server_players(&mut self) -> Vec<ClientId> {
+        let mut client_ids = self
+            .server
+            .world
+            .query::<&NetworkPlayer>()
+            .iter(&self.server.world)
+            .map(|network_player| network_player.client_id)
+            .collect::<Vec<_>>();
+        client_ids.sort_unstable();
+
+        client_ids
+    }
+}
+
+#[test]
+fn client_connects_and_receives_its_player() {
+    let mut harness = LoopbackHarness::new();
+    let client = harness.add_client();
+
+    assert!(harness.run_until(200, |harness| harness.client_id(client).is_some()));
+    let client_id = harness.client_id(client).unwrap();
+
+    assert!(harness.run_until(200, |harness| harness
+        .latest_snapshot_players(client)
+        .contains(&client_id)));
+    assert_eq!(harness.server_players(), vec![client_id]);
+}
+
+#[test]
+fn client_joins_a_dedicated_server() {
+    let mut harness = LoopbackHarness::with_server_plugins(dedicated_server_plugins);
+    let client = harness.add_client();
+
+    assert!(harness.run_until(200, |harness| harness.client_id(client).is_some()));
+    let client_id = harness.client_id(client).unwrap();
+
+    // The cube the gameplay plugin spawns is replicated next to the player
+    assert!(harness.run_until(200, |harness| {
+        harness.latest_snapshot_players(client).contains(&client_id)
+            && harness.latest_snapshot_props(client) == vec![0]
+    }));
+}
+
+#[test]
+fn clients_see_each_other() {
+    let mut harness = LoopbackHarness::new();
+    let first = harness.add_client();
+    let second = harness.add_client();
+
+    assert!(harness.run_until(200, |harness| {
+        harness.client_id(first).is_some() && harness.client_id(second).is_some()
+    }));
+
+    let mut client_ids = vec![
+        harness.client_id(first).unwrap(),
+        harness.client_id(second).unwrap(),
+    ];
+    client_ids.sort_unstable();
+    assert_ne!(client_ids[0], client_ids[1]);
+
+    assert!(harness.run_until(200, |harness| {
+        [first, second].iter().all(|&client| {
+            let mut players = harness.latest_snapshot_players(client);
+            players.sort_unstable();
+            players == client_ids
+        })
+    }));
+    assert_eq!(harness.server_players(), client_ids);
+}
+
+#[test]
+fn connects_over_a_bad_network() {
+    let mut harness = LoopbackHarness::with_network_conditions(
+        NetworkConditions::default()
+            .with_latency(0.02)
+            .with_jitter(0.01)
+            .with_packet_loss(0.1)
+            .with_duplication(0.05)
+            .with_reordering(0.05),
+    );
+    let client = harness.add_client();
+
+    assert!(harness.run_until(600, |harness| harness.client_id(client).is_some()));
+    let client_id = harness.client_id(client).unwrap();
+
+    assert!(harness.run_until(600, |harness| harness
+        .latest_snapshot_players(client)
+        .contains(&client_id)));
+}
+
+#[test]
+fn clock_sync_measures_the_round_trip() {
+    let mut harness =
+        LoopbackHarness::with_network_conditions(NetworkConditions::default().with_latency(0.02));
+    let client = harness.add_client();
+
+    assert!(harness.run_until(600, |harness| {
+        harness.clients[client]
+            .world
+            .get_resource::<ClockSync>()
+            .unwrap()
+            .estimate()
+            .is_some()
+    }));
+
+    // Both ways are delayed, on top of that replies wait for the next update
+    let estimate = harness.clients[client]
+        .world
+        .get_resource::<ClockSync>()
+        .unwrap()
+        .estimate()
+        .unwrap();
+    assert!(estimate.round_trip_time >= 0.04);
+    assert!(estimate.round_trip_time < 0.2);
+    // Both apps started at about the same time
+    assert!(estimate.offset.abs() < 0.1);
+}
+
+#[test]
+fn network_diagnostics_are_measured() {
+    let mut harness = LoopbackHarness::new();
+    let client = harness.add_client();
+
+    assert!(harness.run_until(200, |harness| harness.client_id(client).is_some()));
+    let client_id = harness.client_id(client).unwrap();
+
+    let client_diagnostics = ConnectionDiagnostics::SERVER;
+    let server_diagnostics = ConnectionDiagnostics::for_client(client_id);
+
+    let measured = |app: &App, diagnostics: &ConnectionDiagnostics| {
+        let measurements = app.world.get_resource::<Diagnostics>().unwrap();
+
+        [
+            diagnostics.round_trip_time,
+            diagnostics.bytes_received_per_second,
+            diagnostics.bytes_sent_per_second,
+            diagnostics.snapshot_size,
+        ]
+        .iter()
+        .all(|id| measurements.get_measurement(*id).is_some())
+    };
+
+    assert!(harness.run_until(600, |harness| {
+        measured(&harness.clients[client], &client_diagnostics)
+            && measured(&harness.server, &server_diagnostics)
+    }));
+
+    let snapshot_size = harness
+        .server
+        .world
+        .get_resource::<Diagnostics>()
+        .unwrap()
+        .get_measurement(server_diagnostics.snapshot_size)
+        .unwrap()
+        .value;
+    assert!(snapshot_size > 0.0);
+}
+
+#[derive(Reflect, Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+#[reflect(Component)]
+struct Score {
+    points: u32,
+}
+
+#[test]
+fn replicated_components_reach_the_client() {
+    let mut harness = LoopbackHarness::with_shared_setup(|app_builder| {
+        app_builder.replicate::<Score>();
+    });
+    let client = harness.add_client();
+
+    let server_prop = harness
+        .server
+        .world
+        .spawn()
+        .insert_bundle((
+            Transform::identity(),
+            GlobalTransform::identity(),
+            NetworkProp { prop_id: 7 },
+            Replicated,
+            Score { points: 1 },
+        ))
+        .id();
+    let client_prop = harness.clients[client]
+        .world
+        .spawn()
+        .insert(NetworkProp { prop_id: 7 })
+        .id();
+
+    let has_score = |harness: &LoopbackHarness, points: u32| {
+        harness.clients[client].world.get::<Score>(client_prop) == Some(&Score { points })
+    };
+
+    assert!(harness.run_until(400, |harness| has_score(harness, 1)));
+
+    harness
+        .server
+        .world
+        .get_mut::<Score>(server_prop)
+        .unwrap()
+        .points = 2;
+
+    assert!(harness.run_until(200, |harness| has_score(harness, 2)));
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct Fired {
+    shot: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct Died {
+    client_id: ClientId,
+}
+
+fn declare_events(app_builder: &mut AppBuilder) {
+    app_builder
+        .add_client_event::<Fired>(RateLimit::new(1.0, 3))
+        .add_server_event::<Died>();
+}
+
+struct ReceivedEvents<T>(Vec<T>);
+
+fn collect_events<T: Send + Sync + Clone + 'static>(
+    mut received: ResMut<ReceivedEvents<T>>,
+    mut events: EventReader<T>,
+) {
+    received.0.extend(events.iter().cloned());
+}
+
+#[test]
+fn network_events_reach_the_other_side() {
+    let mut harness = LoopbackHarness::with_shared_setup(declare_events);
+    let client = harness.add_client();
+
+    harness
+        .server
+        .world
+        .insert_resource(ReceivedEvents::<FromClient<Fired>>(Vec::new()));
+    harness.server.schedule.add_system_to_stage(
+        CoreStage::Update,
+        collect_events::<FromClient<Fired>>.system(),
+    );
+    harness.clients[client]
+        .world
+        .insert_resource(ReceivedEvents::<Died>(Vec::new()));
+    harness.clients[client]
+        .schedule
+        .add_system_to_stage(CoreStage::Update, collect_events::<Died>.system());
+
+    assert!(harness.run_until(200, |harness| harness.client_id(client).is_some()));
+    let client_id = harness.client_id(client).unwrap();
+
+    // More than the rate limit allows at once
+    let mut fired = harness.clients[client]
+        .world
+        .get_resource_mut::<Events<Fired>>()
+        .unwrap();
+    for shot in 0..5 {
+        fired.send(Fired { shot });
+    }
+
+    let received_shots = |harness: &LoopbackHarness| {
+        harness
+            .server
+            .world
+            .get_resource::<ReceivedEvents<FromClient<Fired>>>()
+            .unwrap()
+            .0
+            .iter()
+            .map(|received| (received.client_id, received.event.shot))
+            .collect::<Vec<_>>()
+    };
+
+    assert!(harness.run_until(200, |harness| !received_shots(harness).is_empty()));
+    for _ in 0..20 {
+        harness.step();
+    }
+    assert_eq!(
+        received_shots(&harness),
+        vec![(client_id, 0), (client_id, 1), (client_id, 2)]
+    );
+
+    harness
+        .server
+        .world
+        .get_resource_mut::<Events<ToClients<Died>>>()
+        .unwrap()
+        .send(ToClients {
+            recipients: EventRecipients::Client(client_id),
+            event: Died { client_id },
+        });
+
+    assert!(harness.run_until(200, |harness| {
+        harness.clients[client]
+            .world
+            .get_resource::<ReceivedEvents<Died>>()
+            .unwrap()
+            .0
+            == vec![Died { client_id }]
+    }));
+}
+
+#[test]
+fn replicated_players_are_spawned_and_despawned() {
+    let mut harness = LoopbackHarness::new();
+    let first = harness.add_client();
+    let second = harness.add_client();
+
+    assert!(harness.run_until(200, |harness| {
+        harness.client_id(first).is_some() && harness.client_id(second).is_some()
+    }));
+    let second_client_id = harness.client_id(second).unwrap();
+
+    // Both players along with their heads
+    assert!(harness.run_until(200, |harness| harness.network_entities(first) == 4));
+
+    let world = &mut harness.clients[first].world;
+    let remote_players = world
+        .query::<(&RemotePlayer, &Children)>()
+        .iter(world)
+        .map(|(remote_player, children)| (remote_player.client_id, children.len()))
+        .collect::<Vec<_>>();
+    assert_eq!(remote_players, vec![(second_client_id, 1)]);
+
+    harness.clients[second]
+        .world
+        .get_resource_mut::<Events<AppExit>>()
+        .unwrap()
+        .send(AppExit);
+
+    assert!(harness.run_until(400, |harness| harness.network_entities(first) == 2));
+
+    let world = &mut harness.clients[first].world;
+    assert_eq!(world.query::<&RemotePlayer>().iter(world).count(), 0);
+}
+
+#[test]
+fn relevancy_scopes_what_clients_are_sent() {
+    let mut harness = LoopbackHarness::new();
+    harness.server.world.insert_resource(RelevancySettings {
+        max_distance: 0.0,
+        proximity_distance: 0.0,
+        linger: 0.0,
+    });
+    // Far away from everyone, sent anyway
+    harness.server.world.spawn().insert_bundle((
+        Transform::from_translation(Vec3::new(500.0, 0.0, 0.0)),
+        GlobalTransform::identity(),
+        Replicated,
+        AlwaysRelevant,
+    ));
+
+    let first = harness.add_client();
+    let second = harness.add_client();
+
+    assert!(harness.run_until(200, |harness| {
+        harness.client_id(first).is_some() && harness.client_id(second).is_some()
+    }));
+    let first_client_id = harness.client_id(first).unwrap();
+    let second_client_id = harness.client_id(second).unwrap();
+
+    // Its own player along with its head, and the always relevant entity
+    assert!(harness.run_until(200, |harness| harness.network_entities(first) == 3));
+    for _ in 0..50 {
+        harness.step();
+    }
+    assert_eq!(harness.network_entities(first), 3);
+    assert_eq!(
+        harness.latest_snapshot_players(first),
+        vec![first_client_id]
+    );
+
+    harness
+        .server
+        .world
+        .get_resource_mut::<RelevancySettings>()
+        .unwrap()
+        .proximity_distance = 100.0;
+
+    assert!(harness.run_until(200, |harness| harness.network_entities(first) == 5));
+    assert!(harness.run_until(200, |harness| {
+        harness
+            .latest_snapshot_players(first)
+            .contains(&second_client_id)
+    }));
+
+    harness
+        .server
+        .world
+        .get_resource_mut::<RelevancySettings>()
+        .unwrap()
+        .proximity_distance = 0.0;
+
+    assert!(harness.run_until(200, |harness| harness.network_entities(first) == 3));
+    assert!(harness.run_until(200, |harness| {
+        harness.latest_snapshot_players(first) == vec![first_client_id]
+    }));
+
+    let world = &mut harness.clients[first].world;
+    assert_eq!(world.query::<&RemotePlayer>().iter(world).count(), 0);
+}
+
+#[test]
+fn snapshots_stay_within_the_bandwidth_budget() {
+    let mut harness = LoopbackHarness::new();
+    // Room for the client's own player and about one falling prop per snapshot
+    harness
+        .server
+        .world
+        .insert_resource(SnapshotBudgetSettings {
+            bytes_per_second: 2_000.0,
+            ..Default::default()
+        });
+
+    let prop_count = 12;
+    for prop_id in 0..prop_count {
+        let translation = Vec3::new(prop_id as f32 * 2.0, 20.0, 0.0);
+        harness.server.world.spawn().insert_bundle((
+            Transform::from_translation(translation),
+            GlobalTransform::identity(),
+            NetworkProp { prop_id },
+            Replicated,
+            RigidBodyBuilder::new_dynamic().translation(
+                translation.x,
+                translation.y,
+                translation.z,
+            ),
+            ColliderBuilder::cuboid(0.5, 0.5, 0.5),
+        ));
+    }
+
+    let client = harness.add_client();
+    assert!(harness.run_until(200, |harness| harness.client_id(client).is_some()));
+    let client_id = harness.client_id(client).unwrap();
+
+    // Props left out of one snapshot make it into later ones
+    let mut seen_props = HashSet::new();
+    for _ in 0..1000 {
+        harness.step();
+        seen_props.extend(harness.latest_snapshot_props(client));
+
+        if seen_props.len() == prop_count as usize {
+            break;
+        }
+    }
+    assert_eq!(seen_props.len(), prop_count as usize);
+
+    let deferred = harness
+        .server
+        .world
+        .get_resource::<Diagnostics>()
+        .unwrap()
+        .get(ConnectionDiagnostics::for_client(client_id).snapshot_deferred)
+        .and_then(|diagnostic| diagnostic.average());
+    assert!(deferred.unwrap() > 0.0);
+}
+
+#[test]
+fn remote_client_sees_listen_server_host() {
+    let mut harness = LoopbackHarness::new();
+    harness
+        .server
+        .schedule
+        .add_system_to_stage(CoreStage::Update, listen_server_register_host.system());
+
+    // Stands in for the host's own player, spawned by the client half of a listen server
+    let mut command_queue = CommandQueue::default();
+    let mut commands = Commands::new(&mut command_queue, &harness.server.world);
+    let (host_entity, _) = spawn_player_body(&mut commands, Vec3::new(0.0, 20.0, 0.0));
+    commands.entity(host_entity).insert(LocalPlayer);
+    command_queue.apply(&mut harness.server.world);
+
+    let client = harness.add_client();
+    assert!(harness.run_until(200, |harness| harness.client_id(client).is_some()));
+
+    let host_client_id = harness
+        .server
+        .world
+        .get::<NetworkPlayer>(host_entity)
+        .unwrap()
+        .client_id;
+    let mut client_ids = vec![host_client_id, harness.client_id(client).unwrap()];
+    client_ids.sort_unstable();
+    assert_ne!(client_ids[0], client_ids[1]);
+
+    assert!(harness.run_until(200, |harness| {
+        let mut players = harness.latest_snapshot_players(client);
+        players.sort_unstable();
+        players == client_ids
+    }));
+    assert_eq!(
+        harness
+            .server
+            .world
+            .get_resource::<ServerSessions>()
+            .unwrap()
+            .len(),
+        2
+    );
+}
+
+#[test]
+fn disconnect_removes_the_session() {
+    let mut harness = LoopbackHarness::new();
+    let client = harness.add_client();
+
+    assert!(harness.run_until(200, |harness| harness.client_id(client).is_some()));
+
+    harness.clients[client]
+        .world
+        .get_resource_mut::<Events<AppExit>>()
+        .unwrap()
+        .send(AppExit);
+
+    assert!(harness.run_until(200, |harness| harness
+        .server
+        .world
+        .get_resource::<ServerSessions>()
+        .unwrap()
+        .len()
+        == 0));
+    assert!(harness.server_players().is_empty());
+}
+
+#[test]
+fn restarted_client_replaces_its_session() {
+    let mut harness = LoopbackHarness::new();
+    let client = harness.add_client();
+
+    assert!(harness.run_until(200, |harness| harness.client_id(client).is_some()));
+    let old_client_id = harness.client_id(client).unwrap();
+
+    harness.restart_client(client);
+
+    assert!(harness.run_until(200, |harness| harness
+        .client_id(client)
+        .is_some_and(|client_id| client_id != old_client_id)));
+    let new_client_id = harness.client_id(client).unwrap();
+
+    for _ in 0..10 {
+        harness.step();
+    }
+
+    assert_eq!(
+        harness
+            .server
+            .world
+            .get_resource::<ServerSessions>()
+            .unwrap()
+            .len(),
+        1
+    );
+    assert_eq!(harness.server_players(), vec![new_client_id]);
+}
+
+#[test]
+fn strangers_take_no_peer_slots() {
+    let mut harness = LoopbackHarness::new();
+    let heartbeat = GameMessage::new(0, GameMessageType::Heartbeat)
+        .encode()
+        .unwrap();
+
+    // Twice as many addresses as the server has slots
+    for port in 0..8 {
+        let stranger = harness
+            .network
+            .bind(SocketAddr::from(([127, 0, 0, 2], 7000 + port)))
+            .unwrap();
+        let server_address = SERVER_ADDRESS.parse().unwrap();
+        stranger
+            .send_to(&[0xde, 0xad, 0xbe, 0xef], server_address)
+            .unwrap();
+        stranger.send_to(&heartbeat, server_address).unwrap();
+    }
+
+    harness.step();
+    assert_eq!(
+        harness
+            .server
+            .world
+            .get_resource::<UdpServer>()
+            .unwrap()
+            .peers()
+            .count(),
+        0
+    );
+
+    let client = harness.add_client();
+    assert!(harness.run_until(200, |harness| harness.client_id(client).is_some()));
+}
+
+#[test]
+fn full_server_denies_new_clients() {
+    let mut harness = LoopbackHarness::new();
+    let clients = (0..4).map(|_| harness.add_client()).collect::<Vec<_>>();
+    assert!(harness.run_until(200, |harness| clients
+        .iter()
+        .all(|&client| harness.client_id(client).is_some())));
+
+    // One more than the server has slots
+    let late_client = harness.add_client();
+    assert!(harness.run_until(200, |harness| matches!(
+        harness.clients[late_client]
+            .world
+            .get_resource::<ServerConnection>()
+            .unwrap()
+            .state,
+        ConnectionState::Denied(ConnectionDeniedReason::ServerFull)
+    )));
+    assert_eq!(
+        harness
+            .server
+            .world
+            .get_resource::<UdpServer>()
+            .unwrap()
+            .peers()
+            .count(),
+        4
+    );
+}
+
+#[test]
+fn dedicated_server_starts_without_a_renderer() {
+    let mut app_builder = App::build();
+    app_builder.insert_resource(ServerSettings {
+        bind_address: "127.0.0.1:0".to_string(),
+        ..Default::default()
+    });
+    dedicated_server_plugins(&mut app_builder);
+    let mut server = app_builder.app;
+
+    server.update();
+
+    // The map is loaded in the background, its meshes become static colliders the players stand on
+    for _ in 0..500 {
+        if server.world.get_resource::<ColliderSet>().unwrap().len() > 1 {
+            return;
+        }
+
+        thread::sleep(Duration::from_millis(10));
+        server.update();
+    }
+    panic!("the map never got its colliders");
+}
@@ -1,23 +1,60 @@
+// Systems take whatever they need as parameters, so these are expected in bevy code
+#![allow(clippy::too_many_arguments, clippy::type_complexity)]
+
+use std::{env, process};
+
 use bevy::prelude::App;
 
+mod cli;
+use cli::{Command, USAGE};
+
 mod shared;
 use shared::SharedPlugins;
 
-#[cfg(feature = "server")]
+mod client;
 mod server;
-#[cfg(feature = "server")]
-use server::init;
+mod tools;
 
-#[cfg(not(feature = "server"))]
-mod client;
-#[cfg(not(feature = "server"))]
-use client::init;
+#[cfg(test)]
+mod integration_tests;
 
 fn main() {
+    let command = Command::parse(env::args().skip(1)).unwrap_or_else(|error| {
+        eprintln!("{}\n\n{}", error, USAGE);
+        process::exit(2);
+    });
+
     let mut app_builder = App::build();
-    app_builder.add_plugins(SharedPlugins);
 
-    init(&mut app_builder);
+    match command {
+        Command::Client(settings) => {
+            app_builder
+                .insert_resource(settings)
+                .add_plugins(SharedPlugins);
+            client::init(&mut app_builder, false);
+        }
+        Command::Listen(settings) => {
+            app_builder
+                .insert_resource(settings)
+                .add_plugins(SharedPlugins);
+            client::init(&mut app_builder, true);
+        }
+        // Never touches the window or renderer plugins
+        Command::Server(settings) => {
+            app_builder
+                .insert_resource(settings)
+                .add_plugins(SharedPlugins);
+            server::init(&mut app_builder);
+        }
+        Command::Protocol { players, props } => {
+            tools::print_protocol(players, props);
+            return;
+        }
+        Command::Help => {
+            println!("{}", USAGE);
+            return;
+        }
+    }
 
     app_builder.run();
 }
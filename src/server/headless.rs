@@ -0,0 +1,25 @@
+use bevy::{
+    prelude::*,
+    render::{
+        draw::Draw, pipeline::RenderPipelines, render_graph::base::MainPass, texture::Texture,
+    },
+};
+
+// The dedicated server has no renderer, but gameplay still creates meshes and materials and the
+// map scene is made of PBR entities. Their meshes are what the map colliders are built from.
+#[derive(Debug, Default)]
+pub struct HeadlessRenderAssetsPlugin;
+
+impl Plugin for HeadlessRenderAssetsPlugin {
+    fn build(&self, app_builder: &mut AppBuilder) {
+        app_builder
+            .add_asset::<Mesh>()
+            .add_asset::<Texture>()
+            .add_asset::<StandardMaterial>()
+            // Spawning a scene needs every component in it to be registered
+            .register_type::<Draw>()
+            .register_type::<Visible>()
+            .register_type::<RenderPipelines>()
+            .register_type::<MainPass>();
+    }
+}
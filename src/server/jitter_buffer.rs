@@ -0,0 +1,150 @@
+use std::collections::VecDeque;
+
+use crate::shared::game_message::{sequence_greater_than, ClientInputData};
+
+// Inputs of one client waiting for their tick. Packets repeat the inputs before them and arrive
+// unevenly, the buffer evens that out so exactly one input is consumed per tick.
+#[derive(Debug)]
+pub struct InputJitterBuffer {
+    // Ordered by sequence, oldest first
+    inputs: VecDeque<ClientInputData>,
+    // More inputs waiting than this only adds latency, the oldest are dropped
+    pub capacity: usize,
+    last_consumed: Option<ClientInputData>,
+    // Ticks that had no new input and repeated the last one
+    pub underflows: u64,
+    // Inputs dropped because too many were waiting
+    pub overflows: u64,
+}
+
+impl Default for InputJitterBuffer {
+    fn default() -> Self {
+        Self::new(6)
+    }
+}
+
+impl InputJitterBuffer {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            inputs: VecDeque::with_capacity(capacity + 1),
+            capacity,
+            last_consumed: None,
+            underflows: 0,
+            overflows: 0,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.inputs.len()
+    }
+
+    // Copies of inputs already buffered or consumed are ignored
+    pub fn insert(&mut self, input: ClientInputData) {
+        if let Some(last_consumed) = &self.last_consumed {
+            if !sequence_greater_than(input.sequence, last_consumed.sequence) {
+                return;
+            }
+        }
+
+        let index = self
+            .inputs
+            .iter()
+            .position(|buffered| !sequence_greater_than(input.sequence, buffered.sequence));
+
+        match index {
+            Some(index) if self.inputs[index].sequence == input.sequence => return,
+            Some(index) => self.inputs.insert(index, input),
+            None => self.inputs.push_back(input),
+        }
+
+        while self.inputs.len() > self.capacity {
+            self.last_consumed = self.inputs.pop_front();
+            self.overflows += 1;
+        }
+    }
+
+    // The input for the current tick, `None` until the first input arrived
+    pub fn pop(&mut self) -> Option<ClientInputData> {
+        match self.inputs.pop_front() {
+            Some(input) => {
+                self.last_consumed = Some(input);
+            }
+            None if self.last_consumed.is_some() => {
+                self.underflows += 1;
+            }
+            None => {}
+        }
+
+        self.last_consumed.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn input(sequence: u32) -> ClientInputData {
+        ClientInputData {
+            sequence,
+            tick: sequence + 100,
+            ..Default::default()
+        }
+    }
+
+    fn sequences(buffer: &mut InputJitterBuffer, ticks: usize) -> Vec<Option<u32>> {
+        (0..ticks)
+            .map(|_| buffer.pop().map(|input| input.sequence))
+            .collect()
+    }
+
+    #[test]
+    fn consumes_one_input_per_tick_in_order() {
+        let mut buffer = InputJitterBuffer::default();
+        assert_eq!(buffer.pop(), None);
+
+        // Redundant batches overlap, and arrive out of order
+        for sequence in [3, 4, 5, 1, 2, 3].iter() {
+            buffer.insert(input(*sequence));
+        }
+        assert_eq!(buffer.len(), 5);
+
+        assert_eq!(
+            sequences(&mut buffer, 5),
+            vec![Some(1), Some(2), Some(3), Some(4), Some(5)]
+        );
+        assert_eq!(buffer.underflows, 0);
+
+        // Too late to be of any use
+        buffer.insert(input(4));
+        assert_eq!(buffer.len(), 0);
+    }
+
+    #[test]
+    fn repeats_the_last_input_on_underflow() {
+        let mut buffer = InputJitterBuffer::default();
+        buffer.insert(input(1));
+
+        assert_eq!(sequences(&mut buffer, 3), vec![Some(1), Some(1), Some(1)]);
+        assert_eq!(buffer.underflows, 2);
+
+        buffer.insert(input(2));
+        assert_eq!(sequences(&mut buffer, 1), vec![Some(2)]);
+    }
+
+    #[test]
+    fn drops_the_oldest_on_overflow() {
+        let mut buffer = InputJitterBuffer::new(3);
+
+        for sequence in 1..=5 {
+            buffer.insert(input(sequence));
+        }
+
+        assert_eq!(buffer.overflows, 2);
+        assert_eq!(sequences(&mut buffer, 3), vec![Some(3), Some(4), Some(5)]);
+
+        // Dropped inputs count as consumed
+        buffer.insert(input(2));
+        assert_eq!(buffer.len(), 0);
+    }
+}
@@ -1,35 +1,249 @@
+use std::time::Duration;
+
 use bevy::{
-    core::FixedTimestep,
-    ecs::{ResMut, SystemStage},
+    diagnostic::{Diagnostics, LogDiagnosticsPlugin},
+    ecs::schedule::ShouldRun,
     prelude::*,
 };
 
-use crate::shared::game_message::{GameMessageType, ServerGameStateSnapshotData};
-
-mod udp_server;
-use crate::server::udp_server::{UdpServer, UdpServerBuilder};
+pub mod headless;
+pub mod jitter_buffer;
+pub mod network_diagnostics;
+pub mod network_events;
+pub mod relevancy;
+pub mod replication;
+pub mod runner;
+pub mod session;
+pub mod simulation;
+pub mod snapshot_budget;
+pub mod udp_server;
+use crate::server::headless::HeadlessRenderAssetsPlugin;
+use crate::server::network_diagnostics::{
+    network_diagnostics_measure, network_diagnostics_track_clients,
+};
+use crate::server::network_events::{network_events_receive, network_events_send};
+use crate::server::relevancy::{relevancy_update, RelevancySettings};
+use crate::server::replication::{
+    replication_collect, replication_send, NetworkIdAllocator, ReplicationOutbox,
+};
+use crate::server::runner::{server_runner, TickStats};
+use crate::server::session::{
+    session_despawn_players, session_disconnect_on_exit, session_handle_messages,
+    session_heartbeat, session_spawn_players, session_time_sync, session_timeout,
+    ClientConnectedEvent, ClientDisconnectedEvent, ServerSessions,
+};
+use crate::server::simulation::{
+    simulation_apply_inputs, simulation_broadcast_snapshot, simulation_receive_inputs,
+};
+use crate::server::snapshot_budget::SnapshotBudgetSettings;
+use crate::server::udp_server::{
+    server_receive, server_update_channels, ServerMessageEvent, UdpServer, UdpServerBuilder,
+};
+use crate::shared::{
+    game_time::GameTime,
+    gameplay::{FixedTickClock, FIXED_TICK_STAGE},
+    network_conditions::{NetworkConditioner, NetworkConditions},
+    network_events::{NetworkEventInbox, NetworkEventOutbox, NetworkEventRegistry},
+    replication::ReplicationRegistry,
+};
 
 pub fn init(app_builder: &mut AppBuilder) {
     app_builder.add_plugins(ServerPlugins);
-    app_builder.set_runner(move |app| server_runner(app));
-}
-
-fn server_runner(mut app: App) {
-    loop {
-        app.update();
-    }
+    // Headless, the logs are the only place to watch the connections
+    app_builder.add_plugin(LogDiagnosticsPlugin {
+        wait_duration: Duration::from_secs(10),
+        ..Default::default()
+    });
+    app_builder.set_runner(server_runner);
 }
 
 pub struct ServerPlugins;
 impl PluginGroup for ServerPlugins {
     fn build(&mut self, group: &mut bevy::app::PluginGroupBuilder) {
-        group.add(ServerPlugin::default());
+        group.add(HeadlessRenderAssetsPlugin);
+        group.add(ServerPlugin);
     }
 }
 
+// Read by `ServerPlugin`, insert it beforehand to override the defaults
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServerSettings {
+    pub bind_address: String,
+    pub max_clients: usize,
+    // Updates per second when running as a dedicated server
+    pub tick_rate: f64,
+    // Takes precedence over the environment variable
+    pub network_conditions: Option<NetworkConditions>,
+}
+
+impl Default for ServerSettings {
+    fn default() -> Self {
+        Self {
+            // Every interface, players on other machines have to reach it
+            bind_address: "0.0.0.0:8311".to_string(),
+            max_clients: 16,
+            tick_rate: 60.0,
+            network_conditions: None,
+        }
+    }
+}
+
+// Client inputs are applied in the stage of `FixedTickPlugin`, which has to be added first
 #[derive(Debug, Default)]
 pub struct ServerPlugin;
 
 impl Plugin for ServerPlugin {
-    fn build(&self, _: &mut bevy::prelude::AppBuilder) {}
+    fn build(&self, app_builder: &mut bevy::prelude::AppBuilder) {
+        let snapshots_per_second = 20.0;
+        let mut snapshot_clock = FixedTickClock::new(1.0 / snapshots_per_second);
+
+        if !app_builder.world().contains_resource::<ServerSettings>() {
+            app_builder.insert_resource(ServerSettings::default());
+        }
+
+        let settings = app_builder
+            .world()
+            .get_resource::<ServerSettings>()
+            .unwrap()
+            .clone();
+
+        if !app_builder
+            .world()
+            .contains_resource::<NetworkConditioner>()
+        {
+            app_builder.insert_resource(
+                settings
+                    .network_conditions
+                    .map_or_else(NetworkConditioner::from_env, NetworkConditioner::new),
+            );
+        }
+
+        // Tests insert a server on top of an in-memory transport beforehand
+        if !app_builder.world().contains_resource::<UdpServer>() {
+            let network_conditioner = app_builder
+                .world()
+                .get_resource::<NetworkConditioner>()
+                .unwrap()
+                .clone();
+
+            let udp_server = UdpServerBuilder::default()
+                .with_bind_address(&settings.bind_address)
+                .with_receive_buffer_size(1024)
+                .with_max_clients(settings.max_clients)
+                .with_non_blocking(true)
+                .with_network_conditioner(network_conditioner)
+                .build()
+                .unwrap_or_else(|_| {
+                    panic!("Failed to create server at: {}", settings.bind_address)
+                });
+
+            app_builder.insert_resource(udp_server);
+        }
+
+        let mut sessions = ServerSessions::default();
+        sessions.max_clients = settings.max_clients;
+
+        app_builder
+            .insert_resource(sessions)
+            .insert_resource(TickStats::default())
+            .init_resource::<Diagnostics>()
+            .init_resource::<ReplicationRegistry>()
+            .init_resource::<ReplicationOutbox>()
+            .init_resource::<NetworkIdAllocator>()
+            .init_resource::<RelevancySettings>()
+            .init_resource::<SnapshotBudgetSettings>()
+            .init_resource::<NetworkEventRegistry>()
+            .init_resource::<NetworkEventInbox>()
+            .init_resource::<NetworkEventOutbox>()
+            .add_event::<ServerMessageEvent>()
+            .add_event::<ClientConnectedEvent>()
+            .add_event::<ClientDisconnectedEvent>()
+            .add_system_to_stage(
+                CoreStage::PreUpdate,
+                server_receive.system().label("server_receive"),
+            )
+            .add_system_to_stage(
+                CoreStage::PreUpdate,
+                session_handle_messages
+                    .system()
+                    .label("session_handle_messages")
+                    .after("server_receive"),
+            )
+            .add_system_to_stage(
+                CoreStage::PreUpdate,
+                session_timeout.system().after("session_handle_messages"),
+            )
+            .add_system_to_stage(
+                CoreStage::PreUpdate,
+                session_time_sync.system().after("session_handle_messages"),
+            )
+            .add_system_to_stage(
+                CoreStage::PreUpdate,
+                network_events_receive
+                    .system()
+                    .label("network_events_receive")
+                    .after("session_handle_messages"),
+            )
+            .add_system_to_stage(
+                CoreStage::PreUpdate,
+                simulation_receive_inputs
+                    .system()
+                    .after("session_handle_messages"),
+            )
+            .add_system_to_stage(
+                FIXED_TICK_STAGE,
+                simulation_apply_inputs.system().before("player_movement"),
+            )
+            .add_system(session_spawn_players.system())
+            .add_system(network_diagnostics_track_clients.system())
+            .add_system(network_diagnostics_measure.system())
+            .add_system(session_despawn_players.system())
+            .add_system_to_stage(CoreStage::PostUpdate, session_heartbeat.system())
+            .add_stage_after(
+                CoreStage::PostUpdate,
+                "server_snapshot",
+                SystemStage::single_threaded()
+                    .with_run_criteria(
+                        (move |time: Res<GameTime>| {
+                            if snapshot_clock.poll(time.delta_seconds_f64()) {
+                                ShouldRun::YesAndCheckAgain
+                            } else {
+                                ShouldRun::No
+                            }
+                        })
+                        .system(),
+                    )
+                    .with_system(replication_collect.exclusive_system())
+                    .with_system(replication_send.system().label("replication_send"))
+                    .with_system(
+                        simulation_broadcast_snapshot
+                            .system()
+                            .label("simulation_broadcast_snapshot")
+                            .after("replication_send"),
+                    )
+                    .with_system(
+                        relevancy_update
+                            .system()
+                            .after("simulation_broadcast_snapshot"),
+                    ),
+            )
+            .add_system_to_stage(
+                CoreStage::Last,
+                network_events_send
+                    .system()
+                    .before("server_update_channels"),
+            )
+            .add_system_to_stage(
+                CoreStage::Last,
+                session_disconnect_on_exit
+                    .system()
+                    .before("server_update_channels"),
+            )
+            .add_system_to_stage(
+                CoreStage::Last,
+                server_update_channels
+                    .system()
+                    .label("server_update_channels"),
+            );
+    }
 }
@@ -0,0 +1,45 @@
+use bevy::{diagnostic::Diagnostics, prelude::*};
+
+use crate::server::{
+    session::{ClientConnectedEvent, ClientDisconnectedEvent, ServerSessions},
+    udp_server::UdpServer,
+};
+use crate::shared::{game_time::GameTime, network_diagnostics::ConnectionDiagnostics};
+
+// Diagnostics of a client are registered when it connects and emptied once it leaves, so the
+// next client to get the same id starts without its history
+pub fn network_diagnostics_track_clients(
+    mut diagnostics: ResMut<Diagnostics>,
+    mut connected_events: EventReader<ClientConnectedEvent>,
+    mut disconnected_events: EventReader<ClientDisconnectedEvent>,
+) {
+    let client_ids = connected_events
+        .iter()
+        .map(|event| event.client_id)
+        .chain(disconnected_events.iter().map(|event| event.client_id));
+
+    for client_id in client_ids {
+        ConnectionDiagnostics::for_client(client_id)
+            .register(&mut diagnostics, &format!("client {}", client_id));
+    }
+}
+
+pub fn network_diagnostics_measure(
+    time: Res<GameTime>,
+    udp_server: Res<UdpServer>,
+    mut sessions: ResMut<ServerSessions>,
+    mut diagnostics: ResMut<Diagnostics>,
+) {
+    let now = time.seconds_since_startup();
+
+    for session in sessions.iter_mut() {
+        if let Some(endpoint) = udp_server.endpoint(&session.peer) {
+            ConnectionDiagnostics::for_client(session.client_id).measure(
+                &mut diagnostics,
+                endpoint,
+                &mut session.traffic_sampler,
+                now,
+            );
+        }
+    }
+}
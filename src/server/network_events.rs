@@ -0,0 +1,97 @@
+use bevy::prelude::*;
+
+use crate::server::{
+    session::ServerSessions,
+    udp_server::{ServerMessageEvent, UdpServer},
+};
+use crate::shared::{
+    game_message::GameMessageType,
+    game_time::GameTime,
+    network_events::{
+        NetworkEventDirection, NetworkEventInbox, NetworkEventOutbox, NetworkEventRegistry,
+    },
+};
+
+// Every client has a rate limiter per event type, whatever goes over the limit is dropped
+pub fn network_events_receive(
+    time: Res<GameTime>,
+    registry: Res<NetworkEventRegistry>,
+    mut sessions: ResMut<ServerSessions>,
+    mut inbox: ResMut<NetworkEventInbox>,
+    mut message_events: EventReader<ServerMessageEvent>,
+) {
+    let now = time.seconds_since_startup();
+
+    for ServerMessageEvent { peer, content } in message_events.iter() {
+        let event = match content {
+            GameMessageType::NetworkEvent(event) => event,
+            _ => continue,
+        };
+
+        let session = match sessions.get_mut(peer) {
+            Some(session) => session,
+            None => continue,
+        };
+
+        let rate_limit = match registry.direction(event.kind) {
+            Some(NetworkEventDirection::ToServer(rate_limit)) => rate_limit,
+            _ => {
+                warn!(
+                    "Discarded event {} from client {}, clients can't send it",
+                    event.kind, session.client_id
+                );
+                continue;
+            }
+        };
+
+        let rate_limiter = session.event_rate_limiters.entry(event.kind).or_default();
+
+        if !rate_limiter.try_acquire(&rate_limit, now) {
+            warn!(
+                "Discarded event {} from client {}, rate limit exceeded",
+                event.kind, session.client_id
+            );
+            continue;
+        }
+
+        inbox.push(Some(session.client_id), event.clone());
+    }
+}
+
+pub fn network_events_send(
+    time: Res<GameTime>,
+    mut udp_server: ResMut<UdpServer>,
+    sessions: Res<ServerSessions>,
+    mut outbox: ResMut<NetworkEventOutbox>,
+    mut inbox: ResMut<NetworkEventInbox>,
+) {
+    let now = time.seconds_since_startup();
+
+    // The host of a listen server gets its events straight from the inbox, and so does the server
+    let local_client_id = sessions.local_client_id();
+
+    for event in outbox.to_server.drain(..) {
+        if let Some(client_id) = local_client_id {
+            inbox.push(Some(client_id), event);
+        }
+    }
+
+    for (recipients, event) in outbox.to_clients.drain(..) {
+        let peers = sessions
+            .iter()
+            .filter(|session| recipients.includes(session.client_id))
+            .map(|session| session.peer);
+
+        for peer in peers {
+            let message = GameMessageType::NetworkEvent(event.clone());
+
+            if let Err(error) = udp_server.send(peer, message, now) {
+                warn!("Failed to send event to {}: {}", peer, error);
+            }
+        }
+
+        if local_client_id.is_some_and(|client_id| recipients.includes(client_id)) {
+            inbox.push(None, event);
+        }
+    }
+}
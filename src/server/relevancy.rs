@@ -0,0 +1,271 @@
+use std::collections::{HashMap, HashSet};
+
+use bevy::prelude::*;
+use bevy_rapier3d::rapier::{
+    dynamics::RigidBodySet,
+    geometry::{Collider, ColliderHandle, ColliderSet, InteractionGroups, Ray},
+    math::{Point, Vector},
+    pipeline::QueryPipeline,
+};
+
+use crate::server::session::ServerSessions;
+use crate::shared::{game_message::NetworkId, game_time::GameTime, replication::Replicated};
+
+// Sent to every client wherever its player is, only looked at on the root of a hierarchy
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct AlwaysRelevant;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RelevancySettings {
+    // Nothing further away is sent, seen or not
+    pub max_distance: f32,
+    // Anything closer is sent even behind walls, so it doesn't pop in around corners
+    pub proximity_distance: f32,
+    // Seconds an entity stays in scope after it was last relevant, so entities moving along the
+    // edge of it aren't spawned and despawned over and over
+    pub linger: f64,
+}
+
+impl Default for RelevancySettings {
+    fn default() -> Self {
+        Self {
+            max_distance: 200.0,
+            proximity_distance: 8.0,
+            linger: 2.0,
+        }
+    }
+}
+
+fn is_relevant(
+    settings: &RelevancySettings,
+    viewer: Vec3,
+    target: Vec3,
+    is_occluded: impl FnOnce(Vec3, Vec3) -> bool,
+) -> bool {
+    let distance = viewer.distance(target);
+
+    if distance < settings.proximity_distance {
+        return true;
+    }
+
+    distance < settings.max_distance && !is_occluded(viewer, target)
+}
+
+// Only the map blocks the view, its trimeshes are the only colliders on static bodies
+fn is_occluded_by_map(
+    query_pipeline: &QueryPipeline,
+    colliders: &ColliderSet,
+    rigid_bodies: &RigidBodySet,
+    viewer: Vec3,
+    target: Vec3,
+) -> bool {
+    let offset = target - viewer;
+    let distance = offset.length();
+
+    if distance <= f32::EPSILON {
+        return false;
+    }
+
+    let direction = offset / distance;
+    let ray = Ray::new(
+        Point::new(viewer.x, viewer.y, viewer.z),
+        Vector::new(direction.x, direction.y, direction.z),
+    );
+    let filter = |_: ColliderHandle, collider: &Collider| {
+        rigid_bodies
+            .get(collider.parent())
+            .is_some_and(|rigid_body| rigid_body.is_static())
+    };
+
+    query_pipeline
+        .cast_ray(
+            colliders,
+            &ray,
+            distance,
+            true,
+            InteractionGroups::all(),
+            Some(&filter),
+        )
+        .is_some()
+}
+
+fn hierarchy_root(entity: Entity, parents: &HashMap<Entity, Entity>) -> Entity {
+    let mut current = entity;
+
+    while let Some(parent) = parents.get(&current) {
+        current = *parent;
+    }
+
+    current
+}
+
+type RelevancyQuery<'a> = (
+    Entity,
+    &'a NetworkId,
+    &'a Transform,
+    Option<&'a Parent>,
+    Option<&'a AlwaysRelevant>,
+);
+
+// Runs after the snapshot went out, entities coming in scope are spawned by the next replication
+pub fn relevancy_update(
+    time: Res<GameTime>,
+    settings: Res<RelevancySettings>,
+    mut sessions: ResMut<ServerSessions>,
+    query_pipeline: Res<QueryPipeline>,
+    colliders: Res<ColliderSet>,
+    rigid_bodies: Res<RigidBodySet>,
+    replicated_query: Query<RelevancyQuery, With<Replicated>>,
+) {
+    let now = time.seconds_since_startup();
+
+    let translations = replicated_query
+        .iter()
+        .map(|(entity, _, transform, ..)| (entity, transform.translation))
+        .collect::<HashMap<_, _>>();
+
+    // Children come and go along with the root of their hierarchy
+    let parents = replicated_query
+        .iter()
+        .filter_map(|(entity, _, _, parent, _)| {
+            parent
+                .filter(|parent| translations.contains_key(&parent.0))
+                .map(|parent| (entity, parent.0))
+        })
+        .collect::<HashMap<_, _>>();
+
+    let roots = replicated_query
+        .iter()
+        .map(|(entity, network_id, ..)| (*network_id, hierarchy_root(entity, &parents)))
+        .collect::<Vec<_>>();
+
+    let always_relevant = replicated_query
+        .iter()
+        .filter(|(.., always_relevant)| always_relevant.is_some())
+        .map(|(entity, ..)| entity)
+        .collect::<HashSet<_>>();
+
+    let existing = roots
+        .iter()
+        .map(|(network_id, _)| *network_id)
+        .collect::<HashSet<_>>();
+
+    for session in sessions.iter_mut() {
+        let player_entity = session.player_entity;
+        // Without a player the client only gets what everyone gets
+        let viewer = player_entity.and_then(|player_entity| translations.get(&player_entity));
+
+        let mut relevant_roots = HashMap::new();
+
+        for (network_id, root) in roots.iter() {
+            let relevant = *relevant_roots.entry(*root).or_insert_with(|| {
+                if Some(*root) == player_entity || always_relevant.contains(root) {
+                    return true;
+                }
+
+                viewer.is_some_and(|viewer| {
+                    is_relevant(&settings, *viewer, translations[root], |viewer, target| {
+                        is_occluded_by_map(
+                            &query_pipeline,
+                            &colliders,
+                            &rigid_bodies,
+                            viewer,
+                            target,
+                        )
+                    })
+                })
+            });
+
+            if relevant {
+                session.relevant_entities.insert(*network_id, now);
+            }
+        }
+
+        let linger = settings.linger;
+        session.relevant_entities.retain(|network_id, relevant_at| {
+            existing.contains(network_id) && now - *relevant_at <= linger
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy_rapier3d::rapier::{dynamics::RigidBodyBuilder, geometry::ColliderBuilder};
+
+    use super::*;
+
+    // A static trimesh wall across the x axis at `x`, like the ones the map is made of
+    fn map_with_wall(x: f32) -> (QueryPipeline, ColliderSet, RigidBodySet) {
+        let mut rigid_bodies = RigidBodySet::new();
+        let mut colliders = ColliderSet::new();
+
+        let vertices = vec![
+            Point::new(x, -5.0, -5.0),
+            Point::new(x, 5.0, -5.0),
+            Point::new(x, 5.0, 5.0),
+            Point::new(x, -5.0, 5.0),
+        ];
+        let indices = vec![[0, 1, 2], [0, 2, 3]];
+
+        let wall = rigid_bodies.insert(RigidBodyBuilder::new_static().build());
+        colliders.insert(
+            ColliderBuilder::trimesh(vertices, indices).build(),
+            wall,
+            &mut rigid_bodies,
+        );
+
+        let mut query_pipeline = QueryPipeline::new();
+        query_pipeline.update(&rigid_bodies, &colliders);
+
+        (query_pipeline, colliders, rigid_bodies)
+    }
+
+    #[test]
+    fn relevancy_depends_on_distance_and_sight() {
+        let settings = RelevancySettings {
+            max_distance: 50.0,
+            proximity_distance: 5.0,
+            linger: 1.0,
+        };
+        let viewer = Vec3::ZERO;
+        let (near, mid, far) = (Vec3::Y * 3.0, Vec3::X * 20.0, Vec3::Z * 60.0);
+        let seen = |_, _| false;
+        let hidden = |_, _| true;
+
+        assert!(is_relevant(&settings, viewer, mid, seen));
+        assert!(!is_relevant(&settings, viewer, mid, hidden));
+        assert!(!is_relevant(&settings, viewer, far, seen));
+
+        // Walls don't hide what is right around the corner
+        assert!(is_relevant(&settings, viewer, near, hidden));
+    }
+    #[test]
+    fn map_walls_hide_what_is_behind_them() {
+        let settings = RelevancySettings {
+            max_distance: 50.0,
+            proximity_distance: 5.0,
+            linger: 1.0,
+        };
+        let (query_pipeline, colliders, rigid_bodies) = map_with_wall(10.0);
+        let relevant = |viewer: Vec3, target: Vec3| {
+            is_relevant(&settings, viewer, target, |viewer, target| {
+                is_occluded_by_map(&query_pipeline, &colliders, &rigid_bodies, viewer, target)
+            })
+        };
+
+        let viewer = Vec3::ZERO;
+        assert!(!relevant(viewer, Vec3::X * 20.0));
+        assert!(relevant(viewer, Vec3::X * -20.0));
+
+        // Right on the other side of the wall is close enough
+        let (viewer, target) = (Vec3::X * 8.0, Vec3::X * 12.0);
+        assert!(is_occluded_by_map(
+            &query_pipeline,
+            &colliders,
+            &rigid_bodies,
+            viewer,
+            target
+        ));
+        assert!(relevant(viewer, target));
+    }
+}
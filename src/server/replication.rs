@@ -0,0 +1,292 @@
+use std::collections::{HashMap, HashSet};
+
+use bevy::prelude::*;
+
+use crate::server::{
+    session::{ServerSessions, Session},
+    udp_server::UdpServer,
+};
+use crate::shared::{
+    game_message::{
+        ComponentUpdatesData, EntitiesDespawnedData, EntitiesSpawnedData, EntityComponentsData,
+        EntitySpawnData, GameMessageType, NetworkId, NetworkPrefab, ReplicatedComponentData,
+    },
+    game_time::GameTime,
+    gameplay::{NetworkPlayer, NetworkProp, PlayerHead},
+    replication::{collect_components, Replicated},
+};
+
+#[derive(Debug, Default)]
+pub struct NetworkIdAllocator {
+    next: u32,
+}
+
+impl NetworkIdAllocator {
+    pub fn allocate(&mut self) -> NetworkId {
+        let network_id = NetworkId(self.next);
+        self.next = self.next.wrapping_add(1);
+
+        network_id
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct ReplicationOutbox {
+    changed: HashMap<NetworkId, Vec<ReplicatedComponentData>>,
+    // Only collected while a client still has entities to spawn
+    full_state: HashMap<NetworkId, Vec<ReplicatedComponentData>>,
+}
+
+fn by_network_id(
+    entities: Vec<EntityComponentsData>,
+) -> HashMap<NetworkId, Vec<ReplicatedComponentData>> {
+    entities
+        .into_iter()
+        .map(|entity| (entity.network_id, entity.components))
+        .collect()
+}
+
+fn collect_or_warn(world: &mut World, changed_only: bool) -> Vec<EntityComponentsData> {
+    collect_components(world, changed_only).unwrap_or_else(|error| {
+        warn!("Failed to collect replicated components: {}", error);
+        Vec::new()
+    })
+}
+
+// Exclusive, change detection of reflected components relies on the ticks of the world
+pub fn replication_collect(world: &mut World) {
+    let unassigned = world
+        .query_filtered::<Entity, (With<Replicated>, Without<NetworkId>)>()
+        .iter(world)
+        .collect::<Vec<_>>();
+
+    let assigned = {
+        let mut allocator = world.get_resource_mut::<NetworkIdAllocator>().unwrap();
+        unassigned
+            .into_iter()
+            .map(|entity| (entity, allocator.allocate()))
+            .collect::<Vec<_>>()
+    };
+
+    for (entity, network_id) in assigned {
+        world.entity_mut(entity).insert(network_id);
+    }
+
+    let network_ids = world
+        .query_filtered::<&NetworkId, With<Replicated>>()
+        .iter(world)
+        .copied()
+        .collect::<HashSet<_>>();
+
+    let needs_full_state = world
+        .get_resource::<ServerSessions>()
+        .is_some_and(|sessions| {
+            sessions.iter().any(|session| {
+                session.relevant_entities.keys().any(|network_id| {
+                    network_ids.contains(network_id)
+                        && !session.spawned_entities.contains(network_id)
+                })
+            })
+        });
+
+    // Collected every time so the changes of one run never show up in the next
+    let changed = by_network_id(collect_or_warn(world, true));
+
+    let full_state = if needs_full_state {
+        by_network_id(collect_or_warn(world, false))
+    } else {
+        HashMap::new()
+    };
+
+    if let Some(mut outbox) = world.get_resource_mut::<ReplicationOutbox>() {
+        outbox.changed = changed;
+        outbox.full_state = full_state;
+    }
+}
+
+fn network_prefab(
+    network_player: Option<&NetworkPlayer>,
+    network_prop: Option<&NetworkProp>,
+    player_head: Option<&PlayerHead>,
+) -> Option<NetworkPrefab> {
+    match (network_player, network_prop, player_head) {
+        (Some(network_player), _, _) => Some(NetworkPrefab::Player {
+            client_id: network_player.client_id,
+        }),
+        (None, Some(network_prop), _) => Some(NetworkPrefab::Prop {
+            prop_id: network_prop.prop_id,
+        }),
+        (None, None, Some(_)) => Some(NetworkPrefab::PlayerHead),
+        (None, None, None) => None,
+    }
+}
+
+fn hierarchy_depth(entity: Entity, parents: &HashMap<Entity, Entity>) -> usize {
+    let mut depth = 0;
+    let mut current = entity;
+
+    while let Some(parent) = parents.get(&current) {
+        depth += 1;
+        current = *parent;
+    }
+
+    depth
+}
+
+// Counts against the bandwidth budget of snapshots, see `server::snapshot_budget`
+fn send_or_warn(
+    udp_server: &mut UdpServer,
+    session: &mut Session,
+    message: GameMessageType,
+    now: f64,
+) -> bool {
+    match udp_server.send(session.peer, message, now) {
+        Ok(size) => {
+            session.bandwidth.spend(size);
+            true
+        }
+        Err(error) => {
+            warn!("Failed to send replication to {}: {}", session.peer, error);
+            false
+        }
+    }
+}
+
+type ReplicatedQuery<'a> = (
+    Entity,
+    &'a NetworkId,
+    &'a Transform,
+    Option<&'a Parent>,
+    Option<&'a NetworkPlayer>,
+    Option<&'a NetworkProp>,
+    Option<&'a PlayerHead>,
+);
+
+// Every client is told about relevant entities it doesn't know yet, about the ones that are gone or
+// out of scope and about components that changed on the ones it knows, in that order
+pub fn replication_send(
+    time: Res<GameTime>,
+    mut udp_server: ResMut<UdpServer>,
+    mut sessions: ResMut<ServerSessions>,
+    mut outbox: ResMut<ReplicationOutbox>,
+    replicated_query: Query<ReplicatedQuery, With<Replicated>>,
+) {
+    let now = time.seconds_since_startup();
+
+    let network_ids = replicated_query
+        .iter()
+        .map(|(entity, network_id, ..)| (entity, *network_id))
+        .collect::<HashMap<_, _>>();
+
+    let existing = network_ids.values().copied().collect::<HashSet<_>>();
+
+    // Parents outside of replication aren't sent, their children end up at the root
+    let parents = replicated_query
+        .iter()
+        .filter_map(|(entity, _, _, parent, ..)| {
+            parent
+                .filter(|parent| network_ids.contains_key(&parent.0))
+                .map(|parent| (entity, parent.0))
+        })
+        .collect::<HashMap<_, _>>();
+
+    let mut spawns = replicated_query
+        .iter()
+        .map(
+            |(entity, network_id, transform, _, network_player, network_prop, player_head)| {
+                let spawn = EntitySpawnData {
+                    network_id: *network_id,
+                    prefab: network_prefab(network_player, network_prop, player_head),
+                    parent: parents.get(&entity).map(|parent| network_ids[parent]),
+                    translation: transform.translation,
+                    rotation: transform.rotation,
+                    components: Vec::new(),
+                };
+
+                (hierarchy_depth(entity, &parents), spawn)
+            },
+        )
+        .collect::<Vec<_>>();
+
+    // Clients attach children to parents they already have
+    spawns.sort_by_key(|(depth, spawn)| (*depth, spawn.network_id));
+
+    for session in sessions.iter_mut() {
+        let in_scope = session
+            .relevant_entities
+            .keys()
+            .filter(|network_id| existing.contains(*network_id))
+            .copied()
+            .collect::<HashSet<_>>();
+
+        let mut despawned = session
+            .spawned_entities
+            .difference(&in_scope)
+            .copied()
+            .collect::<Vec<_>>();
+        despawned.sort_unstable();
+
+        let spawned = spawns
+            .iter()
+            .filter(|(_, spawn)| {
+                in_scope.contains(&spawn.network_id)
+                    && !session.spawned_entities.contains(&spawn.network_id)
+            })
+            .map(|(_, spawn)| EntitySpawnData {
+                components: outbox
+                    .full_state
+                    .get(&spawn.network_id)
+                    .cloned()
+                    .unwrap_or_default(),
+                ..spawn.clone()
+            })
+            .collect::<Vec<_>>();
+
+        let mut updated = outbox
+            .changed
+            .iter()
+            .filter(|(network_id, _)| {
+                in_scope.contains(*network_id) && session.spawned_entities.contains(*network_id)
+            })
+            .map(|(network_id, components)| EntityComponentsData {
+                network_id: *network_id,
+                components: components.clone(),
+            })
+            .collect::<Vec<_>>();
+        updated.sort_by_key(|entity| entity.network_id);
+
+        if !despawned.is_empty() {
+            let message = GameMessageType::EntitiesDespawned(EntitiesDespawnedData {
+                network_ids: despawned.clone(),
+            });
+
+            if send_or_warn(&mut udp_server, session, message, now) {
+                for network_id in despawned.iter() {
+                    session.spawned_entities.remove(network_id);
+                }
+            }
+        }
+
+        if !spawned.is_empty() {
+            let spawned_ids = spawned
+                .iter()
+                .map(|spawn| spawn.network_id)
+                .collect::<Vec<_>>();
+            let message =
+                GameMessageType::EntitiesSpawned(EntitiesSpawnedData { entities: spawned });
+
+            if send_or_warn(&mut udp_server, session, message, now) {
+                session.spawned_entities.extend(spawned_ids);
+            }
+        }
+
+        if !updated.is_empty() {
+            let message =
+                GameMessageType::ComponentUpdates(ComponentUpdatesData { entities: updated });
+            send_or_warn(&mut udp_server, session, message, now);
+        }
+    }
+
+    outbox.changed.clear();
+    outbox.full_state.clear();
+}
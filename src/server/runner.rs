@@ -0,0 +1,193 @@
+use std::{
+    hint,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use bevy::{
+    app::{AppExit, Events, ManualEventReader},
+    prelude::*,
+};
+
+use crate::server::ServerSettings;
+
+// Sleeping is only accurate to about a millisecond, the rest of the wait is spent spinning
+const SPIN_THRESHOLD: Duration = Duration::from_millis(1);
+
+// Falling further behind than this drops ticks instead of running them back to back
+const MAX_CATCH_UP_TICKS: u32 = 5;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct TickStats {
+    // Ticks whose update took longer than the tick interval
+    pub overruns: u64,
+    // Ticks given up on to catch up after falling too far behind
+    pub skipped: u64,
+    pub last_update_duration: Duration,
+}
+
+// Schedules ticks at a fixed rate, independent of how long each one takes
+#[derive(Debug, Clone)]
+pub struct TickClock {
+    interval: Duration,
+    next_tick_at: Instant,
+}
+
+impl TickClock {
+    pub fn new(tick_rate: f64, now: Instant) -> Self {
+        Self {
+            interval: Duration::from_secs_f64(1.0 / tick_rate),
+            next_tick_at: now,
+        }
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    pub fn next_tick_at(&self) -> Instant {
+        self.next_tick_at
+    }
+
+    // Schedules the tick after the one that just ran, returns how many ticks had to be skipped
+    pub fn advance(&mut self, now: Instant) -> u32 {
+        self.next_tick_at += self.interval;
+
+        if now <= self.next_tick_at {
+            return 0;
+        }
+
+        let behind = ((now - self.next_tick_at).as_secs_f64() / self.interval.as_secs_f64()) as u32;
+
+        if behind < MAX_CATCH_UP_TICKS {
+            return 0;
+        }
+
+        self.next_tick_at = now;
+        behind
+    }
+}
+
+pub fn wait_until(deadline: Instant) {
+    loop {
+        let now = Instant::now();
+
+        if now >= deadline {
+            return;
+        }
+
+        let remaining = deadline - now;
+
+        if remaining > SPIN_THRESHOLD {
+            thread::sleep(remaining - SPIN_THRESHOLD);
+        } else {
+            hint::spin_loop();
+        }
+    }
+}
+
+// Runs one app update per tick until an AppExit event is sent, SIGINT and SIGTERM send one
+pub fn server_runner(mut app: App) {
+    let tick_rate = app
+        .world
+        .get_resource::<ServerSettings>()
+        .map_or(ServerSettings::default().tick_rate, |settings| {
+            settings.tick_rate
+        });
+
+    let shutdown_requested = Arc::new(AtomicBool::new(false));
+    let handler_flag = shutdown_requested.clone();
+    if let Err(error) = ctrlc::set_handler(move || handler_flag.store(true, Ordering::SeqCst)) {
+        warn!("Failed to install the shutdown handler: {}", error);
+    }
+
+    let mut app_exit_reader = ManualEventReader::<AppExit>::default();
+    let mut clock = TickClock::new(tick_rate, Instant::now());
+
+    info!("Running at {} ticks per second", tick_rate);
+
+    loop {
+        wait_until(clock.next_tick_at());
+
+        // The exit event still goes through one last update, so systems can say goodbye
+        if shutdown_requested.swap(false, Ordering::SeqCst) {
+            info!("Shutting down");
+            app.world
+                .get_resource_mut::<Events<AppExit>>()
+                .unwrap()
+                .send(AppExit);
+        }
+
+        let started_at = Instant::now();
+        app.update();
+
+        let finished_at = Instant::now();
+        let skipped = clock.advance(finished_at);
+
+        if let Some(mut stats) = app.world.get_resource_mut::<TickStats>() {
+            stats.last_update_duration = finished_at - started_at;
+
+            if stats.last_update_duration > clock.interval() {
+                stats.overruns += 1;
+            }
+
+            if skipped > 0 {
+                warn!("Server fell behind, skipping {} ticks", skipped);
+                stats.skipped += skipped as u64;
+            }
+        }
+
+        let app_exit_events = app.world.get_resource::<Events<AppExit>>().unwrap();
+        if app_exit_reader.iter(app_exit_events).next().is_some() {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ticks_at_fixed_rate() {
+        let start = Instant::now();
+        let mut clock = TickClock::new(50.0, start);
+
+        assert_eq!(clock.next_tick_at(), start);
+
+        // Short updates don't shift the schedule
+        assert_eq!(clock.advance(start + Duration::from_millis(3)), 0);
+        assert_eq!(clock.next_tick_at(), start + Duration::from_millis(20));
+
+        assert_eq!(clock.advance(start + Duration::from_millis(25)), 0);
+        assert_eq!(clock.next_tick_at(), start + Duration::from_millis(40));
+    }
+
+    #[test]
+    fn catches_up_then_skips() {
+        let start = Instant::now();
+        let mut clock = TickClock::new(50.0, start);
+
+        // A little behind, the next ticks run back to back
+        assert_eq!(clock.advance(start + Duration::from_millis(50)), 0);
+        assert_eq!(clock.next_tick_at(), start + Duration::from_millis(20));
+
+        // Far behind, the schedule restarts from now
+        let now = start + Duration::from_millis(1000);
+        assert_eq!(clock.advance(now), 48);
+        assert_eq!(clock.next_tick_at(), now);
+    }
+
+    #[test]
+    fn waits_until_deadline() {
+        let deadline = Instant::now() + Duration::from_millis(5);
+
+        wait_until(deadline);
+
+        assert!(Instant::now() >= deadline);
+    }
+}
@@ -0,0 +1,480 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    net::SocketAddr,
+};
+
+use bevy::{app::AppExit, prelude::*};
+
+use crate::server::{
+    jitter_buffer::InputJitterBuffer,
+    snapshot_budget::{BandwidthBudget, PriorityAccumulator},
+    udp_server::{ServerMessageEvent, UdpServer},
+};
+use crate::shared::{
+    game_message::{
+        generate_salt, ClientId, ClientInputData, ConnectionAcceptedData, ConnectionChallengeData,
+        ConnectionDeniedData, ConnectionDeniedReason, GameMessageType, NetworkId,
+        TimeSyncResponseData,
+    },
+    game_time::GameTime,
+    gameplay::{spawn_player_body, GameTick, NetworkPlayer},
+    network_diagnostics::TrafficSampler,
+    network_events::RateLimiter,
+    replication::Replicated,
+    snapshot_delta::SnapshotHistory,
+};
+
+pub struct PendingConnection {
+    client_salt: u64,
+    server_salt: u64,
+    created_at: f64,
+}
+
+impl PendingConnection {
+    fn challenge(&self) -> u64 {
+        self.client_salt ^ self.server_salt
+    }
+}
+
+pub struct Session {
+    pub client_id: ClientId,
+    pub peer: SocketAddr,
+    // Salt of the handshake that opened the session, requests carrying it are late resends
+    pub client_salt: u64,
+    pub last_received_at: f64,
+    pub last_heartbeat_at: f64,
+    pub player_entity: Option<Entity>,
+    pub input_buffer: InputJitterBuffer,
+    // Input applied on the latest tick
+    pub latest_input: Option<ClientInputData>,
+    pub acknowledged_snapshot: Option<u32>,
+    pub traffic_sampler: TrafficSampler,
+    // Replicated entities the client was told to spawn
+    pub spawned_entities: HashSet<NetworkId>,
+    // Replicated entities in scope of the client and when they last were, see `server::relevancy`
+    pub relevant_entities: HashMap<NetworkId, f64>,
+    // Snapshots sent to the client, deltas are encoded against the one it acknowledged
+    pub snapshot_history: SnapshotHistory,
+    // See `server::snapshot_budget`
+    pub bandwidth: BandwidthBudget,
+    pub snapshot_priorities: PriorityAccumulator,
+    // By event kind, see `server::network_events`
+    pub event_rate_limiters: HashMap<u16, RateLimiter>,
+}
+
+pub struct ServerSessions {
+    pub max_clients: usize,
+    pub heartbeat_interval: f64,
+    pub timeout: f64,
+    pending: HashMap<SocketAddr, PendingConnection>,
+    sessions: HashMap<SocketAddr, Session>,
+    // The player hosting a listen server, simulated in the same world without any networking
+    local_client_id: Option<ClientId>,
+    next_client_id: ClientId,
+}
+
+impl Default for ServerSessions {
+    fn default() -> Self {
+        Self {
+            max_clients: 16,
+            heartbeat_interval: 1.0,
+            timeout: 5.0,
+            pending: HashMap::default(),
+            sessions: HashMap::default(),
+            local_client_id: None,
+            next_client_id: 1,
+        }
+    }
+}
+
+impl ServerSessions {
+    pub fn get(&self, peer: &SocketAddr) -> Option<&Session> {
+        self.sessions.get(peer)
+    }
+
+    pub fn get_mut(&mut self, peer: &SocketAddr) -> Option<&mut Session> {
+        self.sessions.get_mut(peer)
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut Session> {
+        self.sessions.values_mut()
+    }
+
+    pub fn find_by_client_id(&self, client_id: ClientId) -> Option<&Session> {
+        self.sessions
+            .values()
+            .find(|session| session.client_id == client_id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Session> {
+        self.sessions.values()
+    }
+
+    // Counts the local session too, it takes up a slot like everyone else
+    pub fn len(&self) -> usize {
+        self.sessions.len() + self.local_client_id.iter().count()
+    }
+
+    pub fn local_client_id(&self) -> Option<ClientId> {
+        self.local_client_id
+    }
+
+    pub fn connect_local(&mut self) -> ClientId {
+        if let Some(client_id) = self.local_client_id {
+            return client_id;
+        }
+
+        let client_id = self.allocate_client_id();
+        self.local_client_id = Some(client_id);
+
+        client_id
+    }
+
+    fn allocate_client_id(&mut self) -> ClientId {
+        loop {
+            let client_id = self.next_client_id;
+            self.next_client_id = self.next_client_id.wrapping_add(1).max(1);
+
+            if self.find_by_client_id(client_id).is_none()
+                && self.local_client_id != Some(client_id)
+            {
+                return client_id;
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectReason {
+    Requested,
+    TimedOut,
+    Reconnected,
+}
+
+impl fmt::Display for DisconnectReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DisconnectReason::Requested => write!(f, "disconnected"),
+            DisconnectReason::TimedOut => write!(f, "timed out"),
+            DisconnectReason::Reconnected => write!(f, "reconnected"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ClientConnectedEvent {
+    pub client_id: ClientId,
+    pub peer: SocketAddr,
+}
+
+#[derive(Debug, Clone)]
+pub struct ClientDisconnectedEvent {
+    pub client_id: ClientId,
+    pub peer: SocketAddr,
+    pub reason: DisconnectReason,
+    pub player_entity: Option<Entity>,
+}
+
+pub fn session_handle_messages(
+    time: Res<GameTime>,
+    mut udp_server: ResMut<UdpServer>,
+    mut sessions: ResMut<ServerSessions>,
+    mut message_events: EventReader<ServerMessageEvent>,
+    mut connected_events: EventWriter<ClientConnectedEvent>,
+    mut disconnected_events: EventWriter<ClientDisconnectedEvent>,
+) {
+    let now = time.seconds_since_startup();
+
+    for ServerMessageEvent { peer, content } in message_events.iter() {
+        let peer = *peer;
+
+        if let Some(session) = sessions.get_mut(&peer) {
+            session.last_received_at = now;
+            let client_id = session.client_id;
+
+            // Repeated challenge responses need no reply, the acceptance goes over the reliable
+            // channel and is resent until the client acknowledges it
+            match content {
+                GameMessageType::Disconnect => {
+                    let session = sessions.sessions.remove(&peer).unwrap();
+                    udp_server.forget_peer(&peer);
+
+                    disconnected_events.send(ClientDisconnectedEvent {
+                        client_id,
+                        peer,
+                        reason: DisconnectReason::Requested,
+                        player_entity: session.player_entity,
+                    });
+
+                    continue;
+                }
+                // A new salt means the client restarted on the same address. The old session
+                // goes and the request is answered below like any other.
+                GameMessageType::ConnectionRequest(request)
+                    if request.client_salt != session.client_salt =>
+                {
+                    let session = sessions.sessions.remove(&peer).unwrap();
+                    udp_server.reset_peer(peer);
+
+                    disconnected_events.send(ClientDisconnectedEvent {
+                        client_id,
+                        peer,
+                        reason: DisconnectReason::Reconnected,
+                        player_entity: session.player_entity,
+                    });
+                }
+                _ => continue,
+            }
+        }
+
+        let reply = match content {
+            GameMessageType::ConnectionRequest(request) => {
+                if sessions.len() >= sessions.max_clients {
+                    udp_server.forget_peer(&peer);
+
+                    GameMessageType::ConnectionDenied(ConnectionDeniedData {
+                        reason: ConnectionDeniedReason::ServerFull,
+                    })
+                } else {
+                    let pending =
+                        sessions
+                            .pending
+                            .entry(peer)
+                            .or_insert_with(|| PendingConnection {
+                                client_salt: request.client_salt,
+                                server_salt: generate_salt(),
+                                created_at: now,
+                            });
+
+                    // A retransmitted request with a new salt restarts the handshake
+                    pending.client_salt = request.client_salt;
+
+                    GameMessageType::ConnectionChallenge(ConnectionChallengeData {
+                        client_salt: pending.client_salt,
+                        server_salt: pending.server_salt,
+                    })
+                }
+            }
+            GameMessageType::ConnectionChallengeResponse(response) => {
+                let valid = sessions
+                    .pending
+                    .get(&peer)
+                    .is_some_and(|pending| pending.challenge() == response.challenge);
+
+                if !valid {
+                    sessions.pending.remove(&peer);
+                    udp_server.forget_peer(&peer);
+
+                    GameMessageType::ConnectionDenied(ConnectionDeniedData {
+                        reason: ConnectionDeniedReason::InvalidChallenge,
+                    })
+                } else if sessions.len() >= sessions.max_clients {
+                    sessions.pending.remove(&peer);
+                    udp_server.forget_peer(&peer);
+
+                    GameMessageType::ConnectionDenied(ConnectionDeniedData {
+                        reason: ConnectionDeniedReason::ServerFull,
+                    })
+                } else {
+                    let pending = sessions.pending.remove(&peer).unwrap();
+                    let client_id = sessions.allocate_client_id();
+
+                    sessions.sessions.insert(
+                        peer,
+                        Session {
+                            client_id,
+                            peer,
+                            client_salt: pending.client_salt,
+                            last_received_at: now,
+                            last_heartbeat_at: now,
+                            player_entity: None,
+                            input_buffer: InputJitterBuffer::default(),
+                            latest_input: None,
+                            acknowledged_snapshot: None,
+                            traffic_sampler: TrafficSampler::default(),
+                            spawned_entities: HashSet::new(),
+                            relevant_entities: HashMap::new(),
+                            snapshot_history: SnapshotHistory::default(),
+                            bandwidth: BandwidthBudget::default(),
+                            snapshot_priorities: PriorityAccumulator::default(),
+                            event_rate_limiters: HashMap::new(),
+                        },
+                    );
+
+                    info!("Client {} connected from {}", client_id, peer);
+                    connected_events.send(ClientConnectedEvent { client_id, peer });
+
+                    GameMessageType::ConnectionAccepted(ConnectionAcceptedData {
+                        client_id,
+                        heartbeat_interval: sessions.heartbeat_interval as f32,
+                    })
+                }
+            }
+            _ => {
+                // Strangers don't get to keep the channel state `UdpServer` set up for them
+                if !sessions.pending.contains_key(&peer) {
+                    udp_server.forget_peer(&peer);
+                }
+
+                continue;
+            }
+        };
+
+        if let Err(error) = udp_server.send(peer, reply, now) {
+            warn!("Failed to reply to {}: {}", peer, error);
+        }
+    }
+}
+
+// Answered right away, clients estimate our clock and tick from the replies
+pub fn session_time_sync(
+    time: Res<GameTime>,
+    tick: Option<Res<GameTick>>,
+    mut udp_server: ResMut<UdpServer>,
+    sessions: Res<ServerSessions>,
+    mut message_events: EventReader<ServerMessageEvent>,
+) {
+    let now = time.seconds_since_startup();
+    let server_tick = tick.map_or(0, |tick| tick.0);
+
+    for ServerMessageEvent { peer, content } in message_events.iter() {
+        if let GameMessageType::TimeSyncRequest(request) = content {
+            if sessions.get(peer).is_none() {
+                continue;
+            }
+
+            let response = GameMessageType::TimeSyncResponse(TimeSyncResponseData {
+                client_time: request.client_time,
+                server_time: now,
+                server_tick,
+            });
+
+            if let Err(error) = udp_server.send(*peer, response, now) {
+                warn!("Failed to answer time sync from {}: {}", peer, error);
+            }
+        }
+    }
+}
+
+pub fn session_heartbeat(
+    time: Res<GameTime>,
+    mut udp_server: ResMut<UdpServer>,
+    mut sessions: ResMut<ServerSessions>,
+) {
+    let now = time.seconds_since_startup();
+    let heartbeat_interval = sessions.heartbeat_interval;
+
+    for session in sessions.sessions.values_mut() {
+        if now - session.last_heartbeat_at < heartbeat_interval {
+            continue;
+        }
+
+        session.last_heartbeat_at = now;
+
+        if let Err(error) = udp_server.send(session.peer, GameMessageType::Heartbeat, now) {
+            warn!("Failed to send heartbeat to {}: {}", session.peer, error);
+        }
+    }
+}
+
+pub fn session_timeout(
+    time: Res<GameTime>,
+    mut udp_server: ResMut<UdpServer>,
+    mut sessions: ResMut<ServerSessions>,
+    mut disconnected_events: EventWriter<ClientDisconnectedEvent>,
+) {
+    let now = time.seconds_since_startup();
+    let timeout = sessions.timeout;
+
+    let timed_out: Vec<SocketAddr> = sessions
+        .sessions
+        .values()
+        .filter(|session| now - session.last_received_at > timeout)
+        .map(|session| session.peer)
+        .collect();
+
+    for peer in timed_out {
+        let session = sessions.sessions.remove(&peer).unwrap();
+        udp_server.forget_peer(&peer);
+
+        disconnected_events.send(ClientDisconnectedEvent {
+            client_id: session.client_id,
+            peer,
+            reason: DisconnectReason::TimedOut,
+            player_entity: session.player_entity,
+        });
+    }
+
+    // Handshakes that were never completed hold on to a peer slot in the socket
+    let stale_pending: Vec<SocketAddr> = sessions
+        .pending
+        .iter()
+        .filter(|(_, pending)| now - pending.created_at > timeout)
+        .map(|(peer, _)| *peer)
+        .collect();
+
+    for peer in stale_pending {
+        sessions.pending.remove(&peer);
+        udp_server.forget_peer(&peer);
+    }
+}
+
+// Lets clients know right away instead of waiting for them to time out
+pub fn session_disconnect_on_exit(
+    time: Res<GameTime>,
+    mut app_exit_events: EventReader<AppExit>,
+    mut udp_server: ResMut<UdpServer>,
+    sessions: Res<ServerSessions>,
+) {
+    if app_exit_events.iter().next().is_none() {
+        return;
+    }
+
+    let now = time.seconds_since_startup();
+
+    for session in sessions.iter() {
+        if let Err(error) = udp_server.send(session.peer, GameMessageType::Disconnect, now) {
+            warn!("Failed to notify {} of shutdown: {}", session.peer, error);
+        }
+    }
+}
+
+pub fn session_spawn_players(
+    mut commands: Commands,
+    mut sessions: ResMut<ServerSessions>,
+    mut connected_events: EventReader<ClientConnectedEvent>,
+) {
+    for event in connected_events.iter() {
+        if let Some(session) = sessions.get_mut(&event.peer) {
+            let (player_entity, head_entity) =
+                spawn_player_body(&mut commands, Vec3::new(5.0, 20.0, -5.0));
+
+            commands
+                .entity(player_entity)
+                .insert(NetworkPlayer {
+                    client_id: event.client_id,
+                })
+                .insert(Replicated);
+            commands.entity(head_entity).insert(Replicated);
+
+            session.player_entity = Some(player_entity);
+        }
+    }
+}
+
+pub fn session_despawn_players(
+    mut commands: Commands,
+    mut disconnected_events: EventReader<ClientDisconnectedEvent>,
+) {
+    for event in disconnected_events.iter() {
+        info!(
+            "Client {} at {} {}",
+            event.client_id, event.peer, event.reason
+        );
+
+        if let Some(player_entity) = event.player_entity {
+            commands.entity(player_entity).despawn_recursive();
+        }
+    }
+}
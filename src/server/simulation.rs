@@ -0,0 +1,222 @@
+use bevy::{diagnostic::Diagnostics, prelude::*};
+use bevy_rapier3d::{
+    physics::RigidBodyHandleComponent,
+    rapier::dynamics::{RigidBodyHandle, RigidBodySet},
+};
+
+use crate::server::{
+    session::ServerSessions,
+    snapshot_budget::{fit_snapshot, SnapshotBudgetSettings},
+    udp_server::{ServerMessageEvent, UdpServer},
+};
+use crate::shared::{
+    game_message::{
+        sequence_greater_than, GameMessageType, NetworkId, PlayerStateData, PropStateData,
+        ServerGameStateSnapshotData,
+    },
+    game_time::GameTime,
+    gameplay::{GameTick, NetworkPlayer, NetworkProp, PlayerInput},
+    network_diagnostics::ConnectionDiagnostics,
+    snapshot_delta::encode_delta,
+};
+
+pub fn simulation_receive_inputs(
+    mut sessions: ResMut<ServerSessions>,
+    mut message_events: EventReader<ServerMessageEvent>,
+) {
+    for ServerMessageEvent { peer, content } in message_events.iter() {
+        if let GameMessageType::ClientInput(batch) = content {
+            if let Some(session) = sessions.get_mut(peer) {
+                // Packets can arrive out of order, never go back to an older baseline
+                let is_newer = session
+                    .acknowledged_snapshot
+                    .is_none_or(|tick| sequence_greater_than(batch.acknowledged_snapshot, tick));
+
+                if is_newer && batch.acknowledged_snapshot != 0 {
+                    session.acknowledged_snapshot = Some(batch.acknowledged_snapshot);
+                }
+
+                for input in batch.inputs.iter() {
+                    session.input_buffer.insert(input.clone());
+                }
+            }
+        }
+    }
+}
+
+// Runs once per tick in the fixed tick stage, every client gets exactly one input applied
+pub fn simulation_apply_inputs(
+    mut sessions: ResMut<ServerSessions>,
+    mut player_query: Query<&mut PlayerInput, With<NetworkPlayer>>,
+) {
+    for session in sessions.iter_mut() {
+        let input = match session.input_buffer.pop() {
+            Some(input) => input,
+            None => continue,
+        };
+
+        if let Some(player_entity) = session.player_entity {
+            if let Ok(mut player_input) = player_query.get_mut(player_entity) {
+                *player_input = PlayerInput::from(&input);
+            }
+        }
+
+        session.latest_input = Some(input);
+    }
+}
+
+// Translation, rotation and linear velocity of the body as of the latest tick
+fn rigid_body_state(
+    rigid_bodies: &RigidBodySet,
+    handle: RigidBodyHandle,
+) -> Option<(Vec3, Quat, Vec3)> {
+    let rigid_body = rigid_bodies.get(handle)?;
+    let position = rigid_body.position();
+    let (translation, rotation) = (position.translation.vector, position.rotation);
+    let linvel = rigid_body.linvel();
+
+    Some((
+        Vec3::new(translation.x, translation.y, translation.z),
+        Quat::from_xyzw(rotation.i, rotation.j, rotation.k, rotation.w),
+        Vec3::new(linvel.x, linvel.y, linvel.z),
+    ))
+}
+
+pub fn simulation_broadcast_snapshot(
+    mut last_tick: Local<Option<u32>>,
+    tick: Res<GameTick>,
+    time: Res<GameTime>,
+    mut udp_server: ResMut<UdpServer>,
+    mut diagnostics: ResMut<Diagnostics>,
+    mut sessions: ResMut<ServerSessions>,
+    budget_settings: Res<SnapshotBudgetSettings>,
+    rigid_bodies: Res<RigidBodySet>,
+    player_query: Query<(
+        &NetworkPlayer,
+        &RigidBodyHandleComponent,
+        Option<&NetworkId>,
+    )>,
+    prop_query: Query<(&NetworkProp, &RigidBodyHandleComponent, Option<&NetworkId>)>,
+) {
+    // Snapshots are identified by their tick, there is nothing new to send without one
+    if *last_tick == Some(tick.0) {
+        return;
+    }
+    *last_tick = Some(tick.0);
+
+    let now = time.seconds_since_startup();
+
+    let mut players = player_query
+        .iter()
+        .filter_map(|(network_player, rigid_body_handle, network_id)| {
+            let (translation, rotation, linear_velocity) =
+                rigid_body_state(&rigid_bodies, rigid_body_handle.handle())?;
+            let player = PlayerStateData {
+                client_id: network_player.client_id,
+                translation,
+                rotation,
+                linear_velocity,
+            };
+
+            Some((network_id.copied(), player))
+        })
+        .collect::<Vec<_>>();
+
+    let mut props = prop_query
+        .iter()
+        .filter_map(|(network_prop, rigid_body_handle, network_id)| {
+            let (translation, rotation, linear_velocity) =
+                rigid_body_state(&rigid_bodies, rigid_body_handle.handle())?;
+            let prop = PropStateData {
+                prop_id: network_prop.prop_id,
+                translation,
+                rotation,
+                linear_velocity,
+            };
+
+            Some((network_id.copied(), prop))
+        })
+        .collect::<Vec<_>>();
+
+    // Deltas rely on both sides listing entities in the same order
+    players.sort_by_key(|(_, player)| player.client_id);
+    props.sort_by_key(|(_, prop)| prop.prop_id);
+
+    for session in sessions.iter_mut() {
+        let last_processed_input = session
+            .latest_input
+            .as_ref()
+            .map_or(0, |input| input.sequence);
+
+        // Entities the client wasn't told to spawn are out of its scope, see `server::relevancy`
+        let spawned_entities = &session.spawned_entities;
+        let is_spawned = |network_id: &Option<NetworkId>| {
+            network_id.is_none_or(|network_id| spawned_entities.contains(&network_id))
+        };
+
+        let mut snapshot = ServerGameStateSnapshotData {
+            tick: tick.0,
+            time: now,
+            last_processed_input,
+            players: players
+                .iter()
+                .filter(|(network_id, _)| is_spawned(network_id))
+                .map(|(_, player)| player.clone())
+                .collect(),
+            props: props
+                .iter()
+                .filter(|(network_id, _)| is_spawned(network_id))
+                .map(|(_, prop)| prop.clone())
+                .collect(),
+        };
+
+        let snapshot_history = &session.snapshot_history;
+        let baseline = session
+            .acknowledged_snapshot
+            .and_then(|tick| snapshot_history.get(tick));
+
+        let connection_diagnostics = ConnectionDiagnostics::for_client(session.client_id);
+        let available_bytes = session
+            .bandwidth
+            .available(budget_settings.bytes_per_second, now);
+
+        let deferred = fit_snapshot(
+            &mut snapshot,
+            baseline,
+            &mut session.snapshot_priorities,
+            &budget_settings,
+            session.client_id,
+            available_bytes,
+        );
+
+        let deferred = match deferred {
+            Some(deferred) => deferred,
+            // Nothing left of the bandwidth, entities catch up on the next one
+            None => {
+                diagnostics.add_measurement(connection_diagnostics.snapshots_dropped, 1.0);
+                continue;
+            }
+        };
+
+        diagnostics.add_measurement(connection_diagnostics.snapshots_dropped, 0.0);
+        diagnostics.add_measurement(connection_diagnostics.snapshot_deferred, deferred as f64);
+
+        let message = match baseline {
+            Some(baseline) => {
+                GameMessageType::ServerGameStateDelta(encode_delta(baseline, &snapshot))
+            }
+            // The client has no baseline we still remember, send everything
+            None => GameMessageType::ServerGameStateSnapshot(snapshot.clone()),
+        };
+
+        match udp_server.send(session.peer, message, now) {
+            Ok(size) => {
+                session.bandwidth.spend(size);
+                diagnostics.add_measurement(connection_diagnostics.snapshot_size, size as f64);
+            }
+            Err(error) => warn!("Failed to send snapshot to {}: {}", session.peer, error),
+        }
+
+        session.snapshot_history.insert(snapshot);
+    }
+}
@@ -0,0 +1,457 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::shared::{
+    bit_packing::{BitPacked, BitWriter},
+    game_message::{
+        ClientId, ServerGameStateDeltaData, ServerGameStateSnapshotData, PACKET_HEADER_SIZE,
+    },
+    snapshot_delta::encode_delta,
+};
+
+// Unused bandwidth doesn't pile up beyond this many seconds worth of it
+const MAX_CARRY_OVER: f64 = 0.25;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SnapshotBudgetSettings {
+    // Per client, the replication messages sent along with snapshots count too
+    pub bytes_per_second: f64,
+    // Priority gained by every snapshot an entity is left out of, halved at
+    // `priority_falloff_distance` from the client's player
+    pub player_priority: f32,
+    pub prop_priority: f32,
+    pub priority_falloff_distance: f32,
+}
+
+impl Default for SnapshotBudgetSettings {
+    fn default() -> Self {
+        Self {
+            bytes_per_second: 16_000.0,
+            player_priority: 2.0,
+            prop_priority: 1.0,
+            priority_falloff_distance: 20.0,
+        }
+    }
+}
+
+// Token bucket in bytes
+#[derive(Debug, Default, Clone)]
+pub struct BandwidthBudget {
+    available: f64,
+    updated_at: Option<f64>,
+}
+
+impl BandwidthBudget {
+    pub fn available(&mut self, bytes_per_second: f64, now: f64) -> f64 {
+        let elapsed = self
+            .updated_at
+            .map_or(MAX_CARRY_OVER, |updated_at| (now - updated_at).max(0.0));
+        self.available =
+            (self.available + elapsed * bytes_per_second).min(bytes_per_second * MAX_CARRY_OVER);
+        self.updated_at = Some(now);
+
+        self.available
+    }
+
+    // Reliable messages go out either way, the budget can end up below zero
+    pub fn spend(&mut self, bytes: usize) {
+        self.available -= bytes as f64;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum SnapshotEntity {
+    Player(ClientId),
+    Prop(u32),
+}
+
+// Entities left out of a snapshot are more likely to make it into the next one, so none of them
+// starves however tight the budget is
+#[derive(Debug, Default)]
+pub struct PriorityAccumulator {
+    priorities: HashMap<SnapshotEntity, f32>,
+}
+
+impl PriorityAccumulator {
+    // Candidates are an entity, the priority it gains and its cost in bits. Fills the budget in
+    // order of priority and returns the entities that made it in.
+    pub fn select(
+        &mut self,
+        candidates: &[(SnapshotEntity, f32, usize)],
+        budget_bits: usize,
+    ) -> HashSet<SnapshotEntity> {
+        // Entities that aren't candidates anymore start over if they ever come back
+        let mut priorities = candidates
+            .iter()
+            .map(|(entity, gain, cost)| {
+                let priority = self.priorities.get(entity).copied().unwrap_or(0.0) + gain;
+                (*entity, priority, *cost)
+            })
+            .collect::<Vec<_>>();
+
+        priorities.sort_by(|(a, a_priority, _), (b, b_priority, _)| {
+            b_priority
+                .partial_cmp(a_priority)
+                .unwrap_or(std::cmp::Ordering::Equal)
+                .then(a.cmp(b))
+        });
+
+        let mut remaining = budget_bits;
+        let mut selected = HashSet::new();
+        self.priorities.clear();
+
+        // Smaller entities further down still fill what the bigger ones left
+        for (entity, priority, cost) in priorities {
+            if cost <= remaining {
+                remaining -= cost;
+                selected.insert(entity);
+            } else {
+                self.priorities.insert(entity, priority);
+            }
+        }
+
+        selected
+    }
+}
+
+fn packed_bits<T: BitPacked>(value: &T) -> usize {
+    let mut writer = BitWriter::new();
+    value.pack(&mut writer);
+    writer.bits_written()
+}
+
+// Nothing for the entities the client has unchanged in its baseline, they are left out of deltas.
+// Also returns what the message costs without any entities, the ids of removed entities included.
+fn entity_costs(
+    baseline: Option<&ServerGameStateSnapshotData>,
+    snapshot: &ServerGameStateSnapshotData,
+) -> (HashMap<SnapshotEntity, usize>, usize) {
+    let baseline = match baseline {
+        Some(baseline) => baseline,
+        None => {
+            let players = snapshot.players.iter().map(|player| {
+                (
+                    SnapshotEntity::Player(player.client_id),
+                    packed_bits(player),
+                )
+            });
+            let props = snapshot
+                .props
+                .iter()
+                .map(|prop| (SnapshotEntity::Prop(prop.prop_id), packed_bits(prop)));
+
+            // Deltas carry a few more fields than full snapshots
+            let frame_bits = packed_bits(&ServerGameStateDeltaData::default());
+
+            return (players.chain(props).collect(), frame_bits);
+        }
+    };
+
+    let delta = encode_delta(baseline, snapshot);
+
+    let unchanged = snapshot
+        .players
+        .iter()
+        .map(|player| (SnapshotEntity::Player(player.client_id), 0))
+        .chain(
+            snapshot
+                .props
+                .iter()
+                .map(|prop| (SnapshotEntity::Prop(prop.prop_id), 0)),
+        );
+    let players = delta
+        .players
+        .iter()
+        .map(|player| (SnapshotEntity::Player(player.id), packed_bits(player)));
+    let props = delta
+        .props
+        .iter()
+        .map(|prop| (SnapshotEntity::Prop(prop.id), packed_bits(prop)));
+
+    let frame_bits = packed_bits(&ServerGameStateDeltaData {
+        removed_players: delta.removed_players.clone(),
+        removed_props: delta.removed_props.clone(),
+        ..Default::default()
+    });
+
+    (unchanged.chain(players).chain(props).collect(), frame_bits)
+}
+
+// Trims the snapshot down to what the client's bandwidth allows, most important entities first.
+// Returns how many entities were left out, or nothing when not even the client's own player,
+// which prediction needs every time, fits.
+pub fn fit_snapshot(
+    snapshot: &mut ServerGameStateSnapshotData,
+    baseline: Option<&ServerGameStateSnapshotData>,
+    priorities: &mut PriorityAccumulator,
+    settings: &SnapshotBudgetSettings,
+    client_id: ClientId,
+    available_bytes: f64,
+) -> Option<usize> {
+    let (costs, frame_bits) = entity_costs(baseline, snapshot);
+    let own_player = SnapshotEntity::Player(client_id);
+
+    let overhead_bits =
+        PACKET_HEADER_SIZE * 8 + frame_bits + costs.get(&own_player).copied().unwrap_or(0);
+
+    let available_bits = available_bytes * 8.0 - overhead_bits as f64;
+    if available_bits < 0.0 {
+        return None;
+    }
+
+    let viewer = snapshot
+        .players
+        .iter()
+        .find(|player| player.client_id == client_id)
+        .map(|player| player.translation);
+    let priority = |base_priority: f32, translation| {
+        let distance = viewer.map_or(0.0, |viewer| viewer.distance(translation));
+        base_priority / (1.0 + distance / settings.priority_falloff_distance)
+    };
+
+    let players = snapshot
+        .players
+        .iter()
+        .filter(|player| player.client_id != client_id)
+        .map(|player| {
+            let entity = SnapshotEntity::Player(player.client_id);
+            let gain = priority(settings.player_priority, player.translation);
+            (entity, gain, costs[&entity])
+        });
+    let props = snapshot.props.iter().map(|prop| {
+        let entity = SnapshotEntity::Prop(prop.prop_id);
+        let gain = priority(settings.prop_priority, prop.translation);
+        (entity, gain, costs[&entity])
+    });
+    let candidates = players.chain(props).collect::<Vec<_>>();
+
+    let selected = priorities.select(&candidates, available_bits as usize);
+
+    // Left out entities the client already has stay as they are in its baseline, which a delta
+    // doesn't mention at all. Leaving them out of the snapshot would remove them on the client.
+    let (baseline_players, baseline_props) = baseline.map_or((&[][..], &[][..]), |baseline| {
+        (&baseline.players[..], &baseline.props[..])
+    });
+
+    snapshot.players = std::mem::take(&mut snapshot.players)
+        .into_iter()
+        .filter_map(|player| {
+            let entity = SnapshotEntity::Player(player.client_id);
+            if entity == own_player || selected.contains(&entity) {
+                return Some(player);
+            }
+
+            baseline_players
+                .iter()
+                .find(|base| base.client_id == player.client_id)
+                .cloned()
+        })
+        .collect();
+    snapshot.props = std::mem::take(&mut snapshot.props)
+        .into_iter()
+        .filter_map(|prop| {
+            if selected.contains(&SnapshotEntity::Prop(prop.prop_id)) {
+                return Some(prop);
+            }
+
+            baseline_props
+                .iter()
+                .find(|base| base.prop_id == prop.prop_id)
+                .cloned()
+        })
+        .collect();
+
+    Some(candidates.len() - selected.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::math::Vec3;
+
+    use super::*;
+    use crate::shared::game_message::{PlayerStateData, PropStateData};
+
+    #[test]
+    fn left_out_entities_gain_priority() {
+        let mut priorities = PriorityAccumulator::default();
+        let player = SnapshotEntity::Player(1);
+        let prop = SnapshotEntity::Prop(1);
+        let candidates = [(player, 2.0, 100), (prop, 1.0, 100)];
+
+        // Only one fits, the player goes first but the prop catches up
+        let selected = (0..4)
+            .map(|_| priorities.select(&candidates, 150))
+            .collect::<Vec<_>>();
+
+        assert!(selected[0].contains(&player));
+        assert!(selected[1].contains(&player));
+        assert!(selected[2].contains(&prop));
+        assert!(selected[3].contains(&player));
+        assert!(selected.iter().all(|selected| selected.len() == 1));
+    }
+
+    #[test]
+    fn unchanged_entities_are_free() {
+        let mut priorities = PriorityAccumulator::default();
+        let candidates = [
+            (SnapshotEntity::Prop(1), 1.0, 0),
+            (SnapshotEntity::Prop(2), 9.0, 500),
+            (SnapshotEntity::Prop(3), 1.0, 50),
+        ];
+
+        let selected = priorities.select(&candidates, 100);
+        assert!(selected.contains(&SnapshotEntity::Prop(1)));
+        assert!(selected.contains(&SnapshotEntity::Prop(3)));
+        assert_eq!(selected.len(), 2);
+    }
+
+    #[test]
+    fn bandwidth_refills_up_to_the_carry_over() {
+        let mut budget = BandwidthBudget::default();
+
+        assert_eq!(budget.available(1000.0, 1.0), 250.0);
+        budget.spend(400);
+        assert_eq!(budget.available(1000.0, 1.125), -25.0);
+        assert_eq!(budget.available(1000.0, 10.0), 250.0);
+    }
+
+    #[test]
+    fn snapshots_keep_the_own_player() {
+        let snapshot = ServerGameStateSnapshotData {
+            tick: 1,
+            time: 0.05,
+            last_processed_input: 0,
+            players: (1..=3)
+                .map(|client_id| PlayerStateData {
+                    client_id,
+                    translation: Vec3::X * client_id as f32,
+                    ..Default::default()
+                })
+                .collect(),
+            props: (1..=20)
+                .map(|prop_id| PropStateData {
+                    prop_id,
+                    ..Default::default()
+                })
+                .collect(),
+        };
+        let settings = SnapshotBudgetSettings::default();
+
+        let mut fitted = snapshot.clone();
+        let mut priorities = PriorityAccumulator::default();
+        let deferred = fit_snapshot(&mut fitted, None, &mut priorities, &settings, 2, 150.0);
+
+        assert!(deferred.unwrap() > 0);
+        assert!(fitted.players.iter().any(|player| player.client_id == 2));
+        assert!(fitted.players.len() + fitted.props.len() < 23);
+
+        // Everything fits once the client has it all in its baseline
+        let mut fitted = snapshot.clone();
+        let deferred = fit_snapshot(
+            &mut fitted,
+            Some(&snapshot),
+            &mut priorities,
+            &settings,
+            2,
+            150.0,
+        );
+        assert_eq!(deferred, Some(0));
+        assert_eq!(fitted, snapshot);
+
+        let mut fitted = snapshot.clone();
+        assert_eq!(
+            fit_snapshot(&mut fitted, None, &mut priorities, &settings, 2, 10.0),
+            None
+        );
+    }
+
+    #[test]
+    fn deferred_entities_keep_their_baseline_state() {
+        let baseline = ServerGameStateSnapshotData {
+            tick: 1,
+            time: 0.05,
+            last_processed_input: 0,
+            players: vec![PlayerStateData {
+                client_id: 1,
+                ..Default::default()
+            }],
+            props: (1..=20)
+                .map(|prop_id| PropStateData {
+                    prop_id,
+                    ..Default::default()
+                })
+                .collect(),
+        };
+        let snapshot = ServerGameStateSnapshotData {
+            tick: 2,
+            time: 0.1,
+            props: baseline
+                .props
+                .iter()
+                .map(|prop| PropStateData {
+                    translation: Vec3::Y,
+                    ..prop.clone()
+                })
+                .collect(),
+            ..baseline.clone()
+        };
+        let settings = SnapshotBudgetSettings::default();
+
+        let mut fitted = snapshot.clone();
+        let mut priorities = PriorityAccumulator::default();
+        let deferred = fit_snapshot(
+            &mut fitted,
+            Some(&baseline),
+            &mut priorities,
+            &settings,
+            1,
+            150.0,
+        );
+
+        assert!(deferred.unwrap() > 0);
+        assert_eq!(fitted.props.len(), 20);
+        assert!(fitted.props.contains(&baseline.props[19]));
+        assert!(fitted.props.contains(&snapshot.props[0]));
+        assert!(encode_delta(&baseline, &fitted).removed_props.is_empty());
+    }
+
+    #[test]
+    fn removed_entities_count_against_the_budget() {
+        let snapshot = ServerGameStateSnapshotData {
+            tick: 2,
+            time: 0.1,
+            last_processed_input: 0,
+            players: vec![PlayerStateData {
+                client_id: 1,
+                ..Default::default()
+            }],
+            props: Vec::new(),
+        };
+        let baseline = ServerGameStateSnapshotData {
+            tick: 1,
+            props: (1..=200)
+                .map(|prop_id| PropStateData {
+                    prop_id,
+                    ..Default::default()
+                })
+                .collect(),
+            ..snapshot.clone()
+        };
+        let settings = SnapshotBudgetSettings::default();
+        let mut priorities = PriorityAccumulator::default();
+
+        let mut fitted = snapshot.clone();
+        let fits = fit_snapshot(&mut fitted, None, &mut priorities, &settings, 1, 150.0);
+        assert_eq!(fits, Some(0));
+
+        let mut fitted = snapshot.clone();
+        let fits = fit_snapshot(
+            &mut fitted,
+            Some(&baseline),
+            &mut priorities,
+            &settings,
+            1,
+            150.0,
+        );
+        assert_eq!(fits, None);
+    }
+}
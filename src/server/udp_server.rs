@@ -0,0 +1,347 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    error::Error,
+    fmt, io,
+    net::{SocketAddr, UdpSocket},
+};
+
+use bevy::prelude::*;
+
+use crate::shared::{
+    channel::ChannelEndpoint,
+    fragmentation::{is_fragment, FragmentError, FragmentReassembler, Fragmenter, MAX_PACKET_SIZE},
+    game_message::{
+        ConnectionDeniedData, ConnectionDeniedReason, GameMessage, GameMessageError,
+        GameMessageType,
+    },
+    game_time::GameTime,
+    network_conditions::{ConditionedTransport, LinkEnd, NetworkConditioner},
+    transport::Transport,
+};
+
+#[derive(Debug, Clone)]
+pub struct UdpServerBuilder {
+    bind_address: String,
+    receive_buffer_size: usize,
+    max_clients: usize,
+    non_blocking: bool,
+    network_conditioner: Option<NetworkConditioner>,
+}
+
+impl Default for UdpServerBuilder {
+    fn default() -> Self {
+        Self {
+            bind_address: "0.0.0.0:8311".to_string(),
+            receive_buffer_size: 1024,
+            max_clients: 16,
+            non_blocking: true,
+            network_conditioner: None,
+        }
+    }
+}
+
+impl UdpServerBuilder {
+    pub fn with_bind_address(mut self, bind_address: &str) -> Self {
+        self.bind_address = bind_address.to_string();
+        self
+    }
+
+    pub fn with_receive_buffer_size(mut self, receive_buffer_size: usize) -> Self {
+        self.receive_buffer_size = receive_buffer_size;
+        self
+    }
+
+    pub fn with_max_clients(mut self, max_clients: usize) -> Self {
+        self.max_clients = max_clients;
+        self
+    }
+
+    // Blocking sockets stall `server_receive` until a datagram arrives, only use them when
+    // pumping the server manually.
+    pub fn with_non_blocking(mut self, non_blocking: bool) -> Self {
+        self.non_blocking = non_blocking;
+        self
+    }
+
+    // Simulates a bad network on everything the server sends
+    pub fn with_network_conditioner(mut self, network_conditioner: NetworkConditioner) -> Self {
+        self.network_conditioner = Some(network_conditioner);
+        self
+    }
+
+    pub fn build(self) -> io::Result<UdpServer> {
+        let socket = UdpSocket::bind(&self.bind_address)?;
+        socket.set_nonblocking(self.non_blocking)?;
+
+        Ok(self.build_with_transport(Box::new(socket)))
+    }
+
+    // Bind address and blocking mode are up to the transport
+    pub fn build_with_transport(self, transport: Box<dyn Transport>) -> UdpServer {
+        let transport: Box<dyn Transport> = match self.network_conditioner {
+            Some(network_conditioner) => Box::new(ConditionedTransport::new(
+                transport,
+                network_conditioner,
+                LinkEnd::Server,
+            )),
+            None => transport,
+        };
+
+        UdpServer {
+            transport,
+            // Anything smaller would truncate full sized fragments
+            buffer: vec![0; self.receive_buffer_size.max(MAX_PACKET_SIZE)],
+            max_clients: self.max_clients,
+            peers: HashMap::default(),
+            fragmenter: Fragmenter::default(),
+            received: VecDeque::default(),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Peer {
+    endpoint: ChannelEndpoint,
+    reassembler: FragmentReassembler,
+}
+
+pub struct UdpServer {
+    transport: Box<dyn Transport>,
+    buffer: Vec<u8>,
+    max_clients: usize,
+    peers: HashMap<SocketAddr, Peer>,
+    fragmenter: Fragmenter,
+    // Messages already through their channel but not handed out yet
+    received: VecDeque<(SocketAddr, GameMessageType)>,
+}
+
+impl UdpServer {
+    #[cfg(test)]
+    pub fn peers(&self) -> impl Iterator<Item = &SocketAddr> {
+        self.peers.keys()
+    }
+
+    pub fn forget_peer(&mut self, peer: &SocketAddr) {
+        self.peers.remove(peer);
+    }
+
+    // Keeps the slot of a peer but starts its channel over
+    pub fn reset_peer(&mut self, peer: SocketAddr) {
+        self.peers.insert(peer, Peer::default());
+    }
+
+    pub fn endpoint(&self, peer: &SocketAddr) -> Option<&ChannelEndpoint> {
+        self.peers.get(peer).map(|state| &state.endpoint)
+    }
+
+    // Returns the size of the encoded message
+    pub fn send(
+        &mut self,
+        peer: SocketAddr,
+        content: GameMessageType,
+        now: f64,
+    ) -> Result<usize, UdpServerError> {
+        let message = match self.peers.get_mut(&peer) {
+            Some(state) => state.endpoint.send(content, now),
+            // Peers that were just turned away get their reply without keeping any channel state
+            None => ChannelEndpoint::default().send(content, now),
+        };
+
+        self.send_message(peer, &message)
+    }
+
+    fn send_message(
+        &mut self,
+        peer: SocketAddr,
+        message: &GameMessage,
+    ) -> Result<usize, UdpServerError> {
+        let bytes = message
+            .encode()
+            .map_err(|error| UdpServerError::Message { peer, error })?;
+        let size = bytes.len();
+
+        let datagrams = self
+            .fragmenter
+            .split(bytes)
+            .map_err(|error| UdpServerError::Fragment { peer, error })?;
+
+        for datagram in datagrams.iter() {
+            self.transport.send_to(datagram, peer)?;
+
+            if let Some(state) = self.peers.get_mut(&peer) {
+                state.endpoint.record_bytes_sent(datagram.len());
+            }
+        }
+
+        Ok(size)
+    }
+
+    // Resends unacknowledged reliable messages, sends acks that are due and drops fragments
+    // that will never be completed
+    pub fn update(&mut self, now: f64) -> Result<(), UdpServerError> {
+        let messages = self
+            .peers
+            .iter_mut()
+            .flat_map(|(peer, state)| {
+                state.reassembler.expire(now);
+
+                state
+                    .endpoint
+                    .update(now)
+                    .into_iter()
+                    .map(move |message| (*peer, message))
+            })
+            .collect::<Vec<_>>();
+
+        for (peer, message) in messages.iter() {
+            self.send_message(*peer, message)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn receive(
+        &mut self,
+        now: f64,
+    ) -> Result<Option<(SocketAddr, GameMessageType)>, UdpServerError> {
+        loop {
+            if let Some(received) = self.received.pop_front() {
+                return Ok(Some(received));
+            }
+
+            let (length, peer) = match self.transport.receive_from(&mut self.buffer)? {
+                Some(received) => received,
+                None => return Ok(None),
+            };
+
+            let datagram = &self.buffer[..length];
+
+            // Unknown addresses only get a slot for a valid connection request, anything else
+            // from them is dropped without keeping any state
+            if !self.peers.contains_key(&peer) {
+                if is_fragment(datagram) {
+                    continue;
+                }
+
+                match GameMessage::decode(datagram) {
+                    Ok(GameMessage {
+                        content: GameMessageType::ConnectionRequest(_),
+                        ..
+                    }) => {}
+                    _ => continue,
+                }
+
+                // Without a free slot the request is turned away here, so the client hears about
+                // it instead of retrying until it gives up
+                if self.peers.len() >= self.max_clients {
+                    let denied = GameMessageType::ConnectionDenied(ConnectionDeniedData {
+                        reason: ConnectionDeniedReason::ServerFull,
+                    });
+                    self.send(peer, denied, now)?;
+                    continue;
+                }
+            }
+
+            let state = self.peers.entry(peer).or_default();
+            state.endpoint.record_bytes_received(length);
+
+            let message = if is_fragment(datagram) {
+                match state.reassembler.receive(datagram, now) {
+                    Ok(Some(bytes)) => GameMessage::decode(&bytes),
+                    Ok(None) => continue,
+                    Err(error) => return Err(UdpServerError::Fragment { peer, error }),
+                }
+            } else {
+                GameMessage::decode(datagram)
+            }
+            .map_err(|error| UdpServerError::Message { peer, error })?;
+
+            // A client restarted on the same address numbers its messages from scratch, so its
+            // request would look like a duplicate to the channel of the previous connection
+            if let GameMessageType::ConnectionRequest(_) = message.content {
+                self.received.push_back((peer, message.content));
+                continue;
+            }
+
+            for content in state.endpoint.receive(message, now) {
+                self.received.push_back((peer, content));
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum UdpServerError {
+    Io(io::Error),
+    Message {
+        peer: SocketAddr,
+        error: GameMessageError,
+    },
+    Fragment {
+        peer: SocketAddr,
+        error: FragmentError,
+    },
+}
+
+impl From<io::Error> for UdpServerError {
+    fn from(error: io::Error) -> Self {
+        UdpServerError::Io(error)
+    }
+}
+
+impl fmt::Display for UdpServerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UdpServerError::Io(error) => write!(f, "socket error: {}", error),
+            UdpServerError::Message { peer, error } => write!(f, "peer {}: {}", peer, error),
+            UdpServerError::Fragment { peer, error } => write!(f, "peer {}: {}", peer, error),
+        }
+    }
+}
+
+impl Error for UdpServerError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            UdpServerError::Io(error) => Some(error),
+            UdpServerError::Message { error, .. } => Some(error),
+            UdpServerError::Fragment { error, .. } => Some(error),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ServerMessageEvent {
+    pub peer: SocketAddr,
+    pub content: GameMessageType,
+}
+
+pub fn server_receive(
+    time: Res<GameTime>,
+    mut udp_server: ResMut<UdpServer>,
+    mut message_events: EventWriter<ServerMessageEvent>,
+) {
+    let now = time.seconds_since_startup();
+
+    loop {
+        match udp_server.receive(now) {
+            Ok(Some((peer, content))) => message_events.send(ServerMessageEvent { peer, content }),
+            Ok(None) => break,
+            Err(UdpServerError::Message { peer, error }) => {
+                warn!("Discarded packet from {}: {}", peer, error);
+            }
+            Err(UdpServerError::Fragment { peer, error }) => {
+                warn!("Discarded fragment from {}: {}", peer, error);
+            }
+            Err(error) => {
+                error!("Failed to receive packets: {}", error);
+                break;
+            }
+        }
+    }
+}
+
+pub fn server_update_channels(time: Res<GameTime>, mut udp_server: ResMut<UdpServer>) {
+    if let Err(error) = udp_server.update(time.seconds_since_startup()) {
+        warn!("Failed to resend messages: {}", error);
+    }
+}
@@ -0,0 +1,801 @@
+use std::{error::Error, f32::consts::FRAC_1_SQRT_2, fmt};
+
+use bevy::math::{Quat, Vec3};
+
+use crate::shared::game_message::{
+    ClientInputBatchData, ClientInputData, EntityStateDelta, PlayerStateData, PropStateData,
+    ServerGameStateDeltaData, ServerGameStateSnapshotData,
+};
+
+// ~2mm steps inside a 2km cube around the origin
+pub const POSITION_QUANTIZER: FloatQuantizer = FloatQuantizer {
+    min: -1024.0,
+    max: 1024.0,
+    bits: 20,
+};
+
+// ~2mm/s steps up to 64m/s on each axis
+pub const VELOCITY_QUANTIZER: FloatQuantizer = FloatQuantizer {
+    min: -64.0,
+    max: 64.0,
+    bits: 16,
+};
+
+// Mouse deltas are in pixels per frame, 1/64 of a pixel is far below what anyone can notice
+pub const MOUSE_QUANTIZER: FloatQuantizer = FloatQuantizer {
+    min: -512.0,
+    max: 512.0,
+    bits: 16,
+};
+
+pub const ROTATION_QUANTIZER: RotationQuantizer = RotationQuantizer { bits: 11 };
+
+// Collection lengths are sent as 16 bits, a packet can't hold more entries than that anyway
+const LENGTH_BITS: u32 = 16;
+
+#[derive(Debug, Default)]
+pub struct BitWriter {
+    bytes: Vec<u8>,
+    scratch: u64,
+    scratch_bits: u32,
+}
+
+impl BitWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn write_bits(&mut self, value: u32, bits: u32) {
+        debug_assert!(bits <= 32, "can't write {} bits at once", bits);
+
+        let mask = if bits == 32 {
+            u32::MAX
+        } else {
+            (1 << bits) - 1
+        };
+        self.scratch |= u64::from(value & mask) << self.scratch_bits;
+        self.scratch_bits += bits;
+
+        while self.scratch_bits >= 8 {
+            self.bytes.push(self.scratch as u8);
+            self.scratch >>= 8;
+            self.scratch_bits -= 8;
+        }
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.write_bits(value as u32, 1);
+    }
+
+    pub fn write_u16(&mut self, value: u16) {
+        self.write_bits(u32::from(value), 16);
+    }
+
+    pub fn write_u32(&mut self, value: u32) {
+        self.write_bits(value, 32);
+    }
+
+    pub fn write_u64(&mut self, value: u64) {
+        self.write_bits(value as u32, 32);
+        self.write_bits((value >> 32) as u32, 32);
+    }
+
+    pub fn write_f64(&mut self, value: f64) {
+        self.write_u64(value.to_bits());
+    }
+
+    pub fn write_length(&mut self, length: usize) {
+        debug_assert!(length < 1 << LENGTH_BITS, "{} entries don't fit", length);
+        self.write_bits(length as u32, LENGTH_BITS);
+    }
+
+    pub fn bits_written(&self) -> usize {
+        self.bytes.len() * 8 + self.scratch_bits as usize
+    }
+
+    // Pads the last byte with zeroes
+    pub fn finish(mut self) -> Vec<u8> {
+        if self.scratch_bits > 0 {
+            self.bytes.push(self.scratch as u8);
+        }
+
+        self.bytes
+    }
+}
+
+#[derive(Debug)]
+pub struct BitReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, position: 0 }
+    }
+
+    pub fn remaining_bits(&self) -> usize {
+        self.bytes.len() * 8 - self.position
+    }
+
+    pub fn read_bits(&mut self, bits: u32) -> Result<u32, BitReadError> {
+        debug_assert!(bits <= 32, "can't read {} bits at once", bits);
+
+        if bits as usize > self.remaining_bits() {
+            return Err(BitReadError {
+                requested: bits,
+                remaining: self.remaining_bits(),
+            });
+        }
+
+        let mut value = 0u64;
+        let mut read = 0;
+
+        while read < bits {
+            let offset = (self.position % 8) as u32;
+            let take = (8 - offset).min(bits - read);
+            let chunk = (self.bytes[self.position / 8] >> offset) as u64 & ((1 << take) - 1);
+
+            value |= chunk << read;
+            read += take;
+            self.position += take as usize;
+        }
+
+        Ok(value as u32)
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, BitReadError> {
+        Ok(self.read_bits(1)? == 1)
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, BitReadError> {
+        Ok(self.read_bits(16)? as u16)
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, BitReadError> {
+        self.read_bits(32)
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, BitReadError> {
+        let low = u64::from(self.read_bits(32)?);
+        let high = u64::from(self.read_bits(32)?);
+
+        Ok(high << 32 | low)
+    }
+
+    pub fn read_f64(&mut self) -> Result<f64, BitReadError> {
+        Ok(f64::from_bits(self.read_u64()?))
+    }
+
+    pub fn read_length(&mut self) -> Result<usize, BitReadError> {
+        Ok(self.read_bits(LENGTH_BITS)? as usize)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BitReadError {
+    pub requested: u32,
+    pub remaining: usize,
+}
+
+impl fmt::Display for BitReadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "tried to read {} bits with only {} left",
+            self.requested, self.remaining
+        )
+    }
+}
+
+impl Error for BitReadError {}
+
+// Maps [min, max] onto `bits` wide integers, values outside the range are clamped
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FloatQuantizer {
+    pub min: f32,
+    pub max: f32,
+    pub bits: u32,
+}
+
+impl FloatQuantizer {
+    // Picks the smallest bit count with steps no larger than `precision`
+    #[allow(dead_code)]
+    pub fn with_precision(min: f32, max: f32, precision: f32) -> Self {
+        let steps = ((max - min) / precision).ceil() as u64;
+        let bits = (64 - steps.leading_zeros()).clamp(1, 32);
+
+        Self { min, max, bits }
+    }
+
+    fn steps(&self) -> u64 {
+        (1u64 << self.bits) - 1
+    }
+
+    #[cfg(test)]
+    pub fn step_size(&self) -> f32 {
+        (self.max - self.min) / self.steps() as f32
+    }
+
+    pub fn quantize(&self, value: f32) -> u32 {
+        // NaN ends up at `min` through `max`
+        let value = value.max(self.min).min(self.max);
+        let normalized = ((value - self.min) / (self.max - self.min)) as f64;
+
+        (normalized * self.steps() as f64).round() as u32
+    }
+
+    pub fn dequantize(&self, quantized: u32) -> f32 {
+        let normalized = quantized as f64 / self.steps() as f64;
+
+        (self.min as f64 + normalized * (self.max - self.min) as f64) as f32
+    }
+
+    pub fn write(&self, writer: &mut BitWriter, value: f32) {
+        writer.write_bits(self.quantize(value), self.bits);
+    }
+
+    pub fn read(&self, reader: &mut BitReader) -> Result<f32, BitReadError> {
+        Ok(self.dequantize(reader.read_bits(self.bits)?))
+    }
+
+    pub fn write_vec3(&self, writer: &mut BitWriter, value: Vec3) {
+        self.write(writer, value.x);
+        self.write(writer, value.y);
+        self.write(writer, value.z);
+    }
+
+    pub fn read_vec3(&self, reader: &mut BitReader) -> Result<Vec3, BitReadError> {
+        Ok(Vec3::new(
+            self.read(reader)?,
+            self.read(reader)?,
+            self.read(reader)?,
+        ))
+    }
+}
+
+// Smallest three: the largest component of a unit quaternion is implied by the other three,
+// which can't exceed 1/sqrt(2), so only its index and the three smaller components are sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RotationQuantizer {
+    // Bits per sent component
+    pub bits: u32,
+}
+
+impl RotationQuantizer {
+    fn component_quantizer(&self) -> FloatQuantizer {
+        FloatQuantizer {
+            min: -FRAC_1_SQRT_2,
+            max: FRAC_1_SQRT_2,
+            bits: self.bits,
+        }
+    }
+
+    pub fn write(&self, writer: &mut BitWriter, rotation: Quat) {
+        let rotation = if rotation.length_squared() > 0.0 {
+            rotation.normalize()
+        } else {
+            Quat::IDENTITY
+        };
+
+        let components = [rotation.x, rotation.y, rotation.z, rotation.w];
+        let largest = (1..4).fold(0, |largest, index| {
+            if components[index].abs() > components[largest].abs() {
+                index
+            } else {
+                largest
+            }
+        });
+
+        // q and -q are the same rotation, flip so the implied component is positive
+        let sign = if components[largest] < 0.0 { -1.0 } else { 1.0 };
+        let component_quantizer = self.component_quantizer();
+
+        writer.write_bits(largest as u32, 2);
+        for (index, component) in components.iter().enumerate() {
+            if index != largest {
+                component_quantizer.write(writer, component * sign);
+            }
+        }
+    }
+
+    pub fn read(&self, reader: &mut BitReader) -> Result<Quat, BitReadError> {
+        let largest = reader.read_bits(2)? as usize;
+        let component_quantizer = self.component_quantizer();
+
+        let mut components = [0.0; 4];
+        for (index, component) in components.iter_mut().enumerate() {
+            if index != largest {
+                *component = component_quantizer.read(reader)?;
+            }
+        }
+
+        let sum_of_squares: f32 = components
+            .iter()
+            .map(|component| component * component)
+            .sum();
+        components[largest] = (1.0 - sum_of_squares).max(0.0).sqrt();
+
+        Ok(Quat::from_xyzw(components[0], components[1], components[2], components[3]).normalize())
+    }
+}
+
+pub trait BitPacked: Sized {
+    fn pack(&self, writer: &mut BitWriter);
+    fn unpack(reader: &mut BitReader) -> Result<Self, BitReadError>;
+}
+
+// What the receiving side will see after the value went over the wire
+pub fn quantized<T: BitPacked>(value: &T) -> T {
+    let mut writer = BitWriter::new();
+    value.pack(&mut writer);
+
+    T::unpack(&mut BitReader::new(&writer.finish())).expect("reading back what was just written")
+}
+
+impl BitPacked for u16 {
+    fn pack(&self, writer: &mut BitWriter) {
+        writer.write_u16(*self);
+    }
+
+    fn unpack(reader: &mut BitReader) -> Result<Self, BitReadError> {
+        reader.read_u16()
+    }
+}
+
+impl BitPacked for u32 {
+    fn pack(&self, writer: &mut BitWriter) {
+        writer.write_u32(*self);
+    }
+
+    fn unpack(reader: &mut BitReader) -> Result<Self, BitReadError> {
+        reader.read_u32()
+    }
+}
+
+impl<T: BitPacked> BitPacked for Vec<T> {
+    fn pack(&self, writer: &mut BitWriter) {
+        writer.write_length(self.len());
+        for value in self.iter() {
+            value.pack(writer);
+        }
+    }
+
+    fn unpack(reader: &mut BitReader) -> Result<Self, BitReadError> {
+        let length = reader.read_length()?;
+
+        (0..length).map(|_| T::unpack(reader)).collect()
+    }
+}
+
+impl BitPacked for ClientInputData {
+    fn pack(&self, writer: &mut BitWriter) {
+        writer.write_u32(self.sequence);
+        writer.write_u32(self.tick);
+
+        let buttons = self.move_forward as u32
+            | (self.move_left as u32) << 1
+            | (self.move_back as u32) << 2
+            | (self.move_right as u32) << 3;
+        writer.write_bits(buttons, 4);
+
+        MOUSE_QUANTIZER.write(writer, self.mouse_horizontal);
+        MOUSE_QUANTIZER.write(writer, self.mouse_vertical);
+    }
+
+    fn unpack(reader: &mut BitReader) -> Result<Self, BitReadError> {
+        let sequence = reader.read_u32()?;
+        let tick = reader.read_u32()?;
+        let buttons = reader.read_bits(4)?;
+
+        Ok(Self {
+            sequence,
+            tick,
+            move_forward: buttons & 1 != 0,
+            move_left: buttons & 1 << 1 != 0,
+            move_back: buttons & 1 << 2 != 0,
+            move_right: buttons & 1 << 3 != 0,
+            mouse_horizontal: MOUSE_QUANTIZER.read(reader)?,
+            mouse_vertical: MOUSE_QUANTIZER.read(reader)?,
+        })
+    }
+}
+
+impl BitPacked for ClientInputBatchData {
+    fn pack(&self, writer: &mut BitWriter) {
+        writer.write_u32(self.acknowledged_snapshot);
+        self.inputs.pack(writer);
+    }
+
+    fn unpack(reader: &mut BitReader) -> Result<Self, BitReadError> {
+        Ok(Self {
+            acknowledged_snapshot: reader.read_u32()?,
+            inputs: Vec::unpack(reader)?,
+        })
+    }
+}
+
+impl BitPacked for PlayerStateData {
+    fn pack(&self, writer: &mut BitWriter) {
+        writer.write_u16(self.client_id);
+        POSITION_QUANTIZER.write_vec3(writer, self.translation);
+        ROTATION_QUANTIZER.write(writer, self.rotation);
+        VELOCITY_QUANTIZER.write_vec3(writer, self.linear_velocity);
+    }
+
+    fn unpack(reader: &mut BitReader) -> Result<Self, BitReadError> {
+        Ok(Self {
+            client_id: reader.read_u16()?,
+            translation: POSITION_QUANTIZER.read_vec3(reader)?,
+            rotation: ROTATION_QUANTIZER.read(reader)?,
+            linear_velocity: VELOCITY_QUANTIZER.read_vec3(reader)?,
+        })
+    }
+}
+
+impl BitPacked for PropStateData {
+    fn pack(&self, writer: &mut BitWriter) {
+        writer.write_u32(self.prop_id);
+        POSITION_QUANTIZER.write_vec3(writer, self.translation);
+        ROTATION_QUANTIZER.write(writer, self.rotation);
+        VELOCITY_QUANTIZER.write_vec3(writer, self.linear_velocity);
+    }
+
+    fn unpack(reader: &mut BitReader) -> Result<Self, BitReadError> {
+        Ok(Self {
+            prop_id: reader.read_u32()?,
+            translation: POSITION_QUANTIZER.read_vec3(reader)?,
+            rotation: ROTATION_QUANTIZER.read(reader)?,
+            linear_velocity: VELOCITY_QUANTIZER.read_vec3(reader)?,
+        })
+    }
+}
+
+// Every field is prefixed with a presence bit
+impl<Id: BitPacked> BitPacked for EntityStateDelta<Id> {
+    fn pack(&self, writer: &mut BitWriter) {
+        self.id.pack(writer);
+
+        writer.write_bool(self.translation.is_some());
+        if let Some(translation) = self.translation {
+            POSITION_QUANTIZER.write_vec3(writer, translation);
+        }
+
+        writer.write_bool(self.rotation.is_some());
+        if let Some(rotation) = self.rotation {
+            ROTATION_QUANTIZER.write(writer, rotation);
+        }
+
+        writer.write_bool(self.linear_velocity.is_some());
+        if let Some(linear_velocity) = self.linear_velocity {
+            VELOCITY_QUANTIZER.write_vec3(writer, linear_velocity);
+        }
+    }
+
+    fn unpack(reader: &mut BitReader) -> Result<Self, BitReadError> {
+        let id = Id::unpack(reader)?;
+
+        let translation = match reader.read_bool()? {
+            true => Some(POSITION_QUANTIZER.read_vec3(reader)?),
+            false => None,
+        };
+        let rotation = match reader.read_bool()? {
+            true => Some(ROTATION_QUANTIZER.read(reader)?),
+            false => None,
+        };
+        let linear_velocity = match reader.read_bool()? {
+            true => Some(VELOCITY_QUANTIZER.read_vec3(reader)?),
+            false => None,
+        };
+
+        Ok(Self {
+            id,
+            translation,
+            rotation,
+            linear_velocity,
+        })
+    }
+}
+
+impl BitPacked for ServerGameStateSnapshotData {
+    fn pack(&self, writer: &mut BitWriter) {
+        writer.write_u32(self.tick);
+        writer.write_f64(self.time);
+        writer.write_u32(self.last_processed_input);
+        self.players.pack(writer);
+        self.props.pack(writer);
+    }
+
+    fn unpack(reader: &mut BitReader) -> Result<Self, BitReadError> {
+        Ok(Self {
+            tick: reader.read_u32()?,
+            time: reader.read_f64()?,
+            last_processed_input: reader.read_u32()?,
+            players: Vec::unpack(reader)?,
+            props: Vec::unpack(reader)?,
+        })
+    }
+}
+
+impl BitPacked for ServerGameStateDeltaData {
+    fn pack(&self, writer: &mut BitWriter) {
+        writer.write_u32(self.tick);
+        writer.write_u32(self.baseline_tick);
+        writer.write_f64(self.time);
+        writer.write_u32(self.last_processed_input);
+        self.players.pack(writer);
+        self.removed_players.pack(writer);
+        self.props.pack(writer);
+        self.removed_props.pack(writer);
+    }
+
+    fn unpack(reader: &mut BitReader) -> Result<Self, BitReadError> {
+        Ok(Self {
+            tick: reader.read_u32()?,
+            baseline_tick: reader.read_u32()?,
+            time: reader.read_f64()?,
+            last_processed_input: reader.read_u32()?,
+            players: Vec::unpack(reader)?,
+            removed_players: Vec::unpack(reader)?,
+            props: Vec::unpack(reader)?,
+            removed_props: Vec::unpack(reader)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn vec3(range: f32) -> impl Strategy<Value = Vec3> {
+        (-range..range, -range..range, -range..range).prop_map(|(x, y, z)| Vec3::new(x, y, z))
+    }
+
+    fn rotation() -> impl Strategy<Value = Quat> {
+        (-1.0f32..1.0, -1.0f32..1.0, -1.0f32..1.0, -1.0f32..1.0)
+            .prop_filter("degenerate quaternion", |(x, y, z, w)| {
+                x * x + y * y + z * z + w * w > 0.01
+            })
+            .prop_map(|(x, y, z, w)| Quat::from_xyzw(x, y, z, w).normalize())
+    }
+
+    // Half a step plus some slack for f32 rounding
+    fn max_error(quantizer: &FloatQuantizer) -> f32 {
+        quantizer.step_size() * 0.5 + 1e-4
+    }
+
+    fn assert_vec3_close(actual: Vec3, expected: Vec3, tolerance: f32) {
+        let error = (actual - expected).abs().max_element();
+        assert!(
+            error <= tolerance,
+            "{:?} vs {:?}, error {} over {}",
+            actual,
+            expected,
+            error,
+            tolerance
+        );
+    }
+
+    fn assert_rotation_close(actual: Quat, expected: Quat) {
+        // Radians, 11 bits per component stays well within a third of a degree
+        let angle = 2.0 * actual.dot(expected).abs().min(1.0).acos();
+        assert!(
+            angle < 0.006,
+            "{:?} vs {:?}, {} rad apart",
+            actual,
+            expected,
+            angle
+        );
+    }
+
+    fn round_trip<T: BitPacked>(value: &T) -> (T, usize) {
+        let mut writer = BitWriter::new();
+        value.pack(&mut writer);
+        let bits = writer.bits_written();
+        let bytes = writer.finish();
+
+        let mut reader = BitReader::new(&bytes);
+        let unpacked = T::unpack(&mut reader).unwrap();
+        assert!(reader.remaining_bits() < 8);
+
+        (unpacked, bits)
+    }
+
+    proptest! {
+        #[test]
+        fn raw_bits_round_trip(values in prop::collection::vec((any::<u32>(), 1u32..=32), 0..64)) {
+            let mut writer = BitWriter::new();
+            for (value, bits) in values.iter() {
+                writer.write_bits(*value, *bits);
+            }
+            let bytes = writer.finish();
+
+            let mut reader = BitReader::new(&bytes);
+            for (value, bits) in values.iter() {
+                let mask = if *bits == 32 { u32::MAX } else { (1 << bits) - 1 };
+                prop_assert_eq!(reader.read_bits(*bits).unwrap(), value & mask);
+            }
+        }
+
+        #[test]
+        fn positions_stay_within_precision(translation in vec3(1024.0)) {
+            let mut writer = BitWriter::new();
+            POSITION_QUANTIZER.write_vec3(&mut writer, translation);
+            let bytes = writer.finish();
+
+            let read = POSITION_QUANTIZER.read_vec3(&mut BitReader::new(&bytes)).unwrap();
+            assert_vec3_close(read, translation, max_error(&POSITION_QUANTIZER));
+        }
+
+        #[test]
+        fn quantizer_honours_requested_precision(
+            range in 1.0f32..4096.0,
+            precision in 0.0005f32..1.0,
+            fraction in 0.0f32..=1.0,
+        ) {
+            let quantizer = FloatQuantizer::with_precision(-range, range, precision);
+            let value = -range + fraction * 2.0 * range;
+
+            prop_assert!(quantizer.step_size() <= precision);
+            let error = (quantizer.dequantize(quantizer.quantize(value)) - value).abs();
+            prop_assert!(error <= max_error(&quantizer), "error {}", error);
+        }
+
+        #[test]
+        fn rotations_stay_within_precision(rotation in rotation()) {
+            let mut writer = BitWriter::new();
+            ROTATION_QUANTIZER.write(&mut writer, rotation);
+            prop_assert_eq!(writer.bits_written(), 2 + 3 * ROTATION_QUANTIZER.bits as usize);
+            let bytes = writer.finish();
+
+            let read = ROTATION_QUANTIZER.read(&mut BitReader::new(&bytes)).unwrap();
+            assert_rotation_close(read, rotation);
+        }
+
+        #[test]
+        fn inputs_round_trip(
+            sequence in any::<u32>(),
+            tick in any::<u32>(),
+            buttons in any::<[bool; 4]>(),
+            mouse_horizontal in -512.0f32..512.0,
+            mouse_vertical in -512.0f32..512.0,
+        ) {
+            let input = ClientInputData {
+                sequence,
+                tick,
+                move_forward: buttons[0],
+                move_left: buttons[1],
+                move_back: buttons[2],
+                move_right: buttons[3],
+                mouse_horizontal,
+                mouse_vertical,
+            };
+
+            let (read, bits) = round_trip(&input);
+
+            prop_assert_eq!(bits, 32 + 32 + 4 + 2 * MOUSE_QUANTIZER.bits as usize);
+            prop_assert_eq!(read.sequence, input.sequence);
+            prop_assert_eq!(read.tick, input.tick);
+            prop_assert_eq!(
+                [read.move_forward, read.move_left, read.move_back, read.move_right],
+                buttons
+            );
+            prop_assert!((read.mouse_horizontal - mouse_horizontal).abs() <= max_error(&MOUSE_QUANTIZER));
+            prop_assert!((read.mouse_vertical - mouse_vertical).abs() <= max_error(&MOUSE_QUANTIZER));
+
+            // Quantizing twice changes nothing, the client predicts with exactly what the server gets
+            prop_assert_eq!(quantized(&read), read);
+        }
+
+        #[test]
+        fn snapshots_round_trip(
+            players in prop::collection::vec((any::<u16>(), vec3(1024.0), rotation(), vec3(64.0)), 0..8),
+            props in prop::collection::vec((any::<u32>(), vec3(1024.0), rotation(), vec3(64.0)), 0..32),
+        ) {
+            let snapshot = ServerGameStateSnapshotData {
+                tick: 42,
+                time: 2.1,
+                last_processed_input: 7,
+                players: players
+                    .into_iter()
+                    .map(|(client_id, translation, rotation, linear_velocity)| PlayerStateData {
+                        client_id,
+                        translation,
+                        rotation,
+                        linear_velocity,
+                    })
+                    .collect(),
+                props: props
+                    .into_iter()
+                    .map(|(prop_id, translation, rotation, linear_velocity)| PropStateData {
+                        prop_id,
+                        translation,
+                        rotation,
+                        linear_velocity,
+                    })
+                    .collect(),
+            };
+
+            let (read, _) = round_trip(&snapshot);
+
+            prop_assert_eq!(read.tick, snapshot.tick);
+            prop_assert_eq!(read.time, snapshot.time);
+            prop_assert_eq!(read.last_processed_input, snapshot.last_processed_input);
+            prop_assert_eq!(read.players.len(), snapshot.players.len());
+            prop_assert_eq!(read.props.len(), snapshot.props.len());
+
+            for (read, player) in read.players.iter().zip(snapshot.players.iter()) {
+                prop_assert_eq!(read.client_id, player.client_id);
+                assert_vec3_close(read.translation, player.translation, max_error(&POSITION_QUANTIZER));
+                assert_rotation_close(read.rotation, player.rotation);
+                assert_vec3_close(read.linear_velocity, player.linear_velocity, max_error(&VELOCITY_QUANTIZER));
+            }
+
+            for (read, prop) in read.props.iter().zip(snapshot.props.iter()) {
+                prop_assert_eq!(read.prop_id, prop.prop_id);
+                assert_vec3_close(read.translation, prop.translation, max_error(&POSITION_QUANTIZER));
+                assert_rotation_close(read.rotation, prop.rotation);
+                assert_vec3_close(read.linear_velocity, prop.linear_velocity, max_error(&VELOCITY_QUANTIZER));
+            }
+        }
+
+        #[test]
+        fn deltas_keep_missing_fields_missing(
+            translation in prop::option::of(vec3(1024.0)),
+            rotation in prop::option::of(rotation()),
+            linear_velocity in prop::option::of(vec3(64.0)),
+            removed_props in prop::collection::vec(any::<u32>(), 0..8),
+        ) {
+            let delta = ServerGameStateDeltaData {
+                tick: 12,
+                baseline_tick: 10,
+                time: 0.6,
+                last_processed_input: 3,
+                players: vec![EntityStateDelta { id: 2, translation, rotation, linear_velocity }],
+                removed_players: vec![5],
+                props: Vec::new(),
+                removed_props: removed_props.clone(),
+            };
+
+            let (read, _) = round_trip(&delta);
+            let player = &read.players[0];
+
+            prop_assert_eq!(player.id, 2);
+            prop_assert_eq!(player.translation.is_some(), translation.is_some());
+            prop_assert_eq!(player.rotation.is_some(), rotation.is_some());
+            prop_assert_eq!(player.linear_velocity.is_some(), linear_velocity.is_some());
+            prop_assert_eq!(read.removed_players, vec![5]);
+            prop_assert_eq!(read.removed_props, removed_props);
+        }
+    }
+
+    #[test]
+    fn out_of_range_values_are_clamped() {
+        let mut writer = BitWriter::new();
+        POSITION_QUANTIZER.write_vec3(&mut writer, Vec3::new(5000.0, -5000.0, f32::NAN));
+        let bytes = writer.finish();
+
+        let read = POSITION_QUANTIZER
+            .read_vec3(&mut BitReader::new(&bytes))
+            .unwrap();
+        assert_eq!(read, Vec3::new(1024.0, -1024.0, -1024.0));
+    }
+
+    #[test]
+    fn reading_past_the_end_fails() {
+        let mut writer = BitWriter::new();
+        writer.write_bits(0b101, 3);
+        let bytes = writer.finish();
+
+        let mut reader = BitReader::new(&bytes);
+        assert_eq!(reader.read_bits(3), Ok(0b101));
+        assert_eq!(
+            reader.read_bits(6),
+            Err(BitReadError {
+                requested: 6,
+                remaining: 5
+            })
+        );
+    }
+}
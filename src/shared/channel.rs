@@ -0,0 +1,578 @@
+use std::collections::{HashMap, VecDeque};
+
+use crate::shared::game_message::{sequence_greater_than, GameMessage, GameMessageType};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelKind {
+    // Delivered as it arrives, possibly never
+    Unreliable,
+    // Like unreliable, but anything older than the newest message already delivered is dropped
+    UnreliableSequenced,
+    // Resent until acknowledged and delivered exactly once, in the order it was sent
+    ReliableOrdered,
+}
+
+#[derive(Debug, Clone)]
+pub struct ChannelSettings {
+    // Resend timeout bounds, the actual timeout follows the measured round trip time
+    pub min_resend_timeout: f64,
+    pub max_resend_timeout: f64,
+    // How long received packets may go without an ack when there's nothing to piggyback it on
+    pub ack_delay: f64,
+    // Reliable messages further ahead of the next expected one are dropped and resent later
+    pub reliable_window: u16,
+}
+
+impl Default for ChannelSettings {
+    fn default() -> Self {
+        Self {
+            min_resend_timeout: 0.1,
+            max_resend_timeout: 1.0,
+            ack_delay: 0.05,
+            reliable_window: 256,
+        }
+    }
+}
+
+// Number of older packets acknowledged next to the latest one
+const ACK_BITS: u32 = 32;
+// Sent packets are remembered a bit longer than they can possibly be acknowledged for
+const SENT_PACKET_HISTORY: usize = 256;
+
+fn message_id_greater_than(a: u16, b: u16) -> bool {
+    a != b && a.wrapping_sub(b) < u16::MAX / 2
+}
+
+#[derive(Debug, Clone)]
+struct SentPacket {
+    sequence: u32,
+    sent_at: f64,
+    message_id: Option<u16>,
+    acked: bool,
+    lost: bool,
+}
+
+// Running totals, diagnostics turn them into rates
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionStats {
+    pub packets_acked: u64,
+    // Sent packets that fell out of the ack window without being acknowledged
+    pub packets_lost: u64,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+}
+
+#[derive(Debug, Clone)]
+struct PendingReliable {
+    message_id: u16,
+    content: GameMessageType,
+    last_sent_at: f64,
+}
+
+// Channel state for one remote peer. Every outgoing packet is stamped with a sequence number
+// and acknowledges the packets received from the peer, reliable messages are resent until one
+// of the packets carrying them gets acknowledged.
+#[derive(Debug, Default)]
+pub struct ChannelEndpoint {
+    settings: ChannelSettings,
+
+    local_sequence: u32,
+    sent_packets: VecDeque<SentPacket>,
+    next_message_id: u16,
+    reliable_queue: VecDeque<PendingReliable>,
+    round_trip_time: Option<f64>,
+    // Mean deviation of the round trip time samples
+    round_trip_jitter: f64,
+    stats: ConnectionStats,
+
+    remote_sequence: Option<u32>,
+    received_bits: u32,
+    ack_pending_since: Option<f64>,
+    latest_sequenced: Option<u32>,
+    next_expected_message_id: u16,
+    reliable_received: HashMap<u16, GameMessageType>,
+}
+
+impl ChannelEndpoint {
+    pub fn round_trip_time(&self) -> Option<f64> {
+        self.round_trip_time
+    }
+
+    pub fn round_trip_jitter(&self) -> f64 {
+        self.round_trip_jitter
+    }
+
+    pub fn stats(&self) -> ConnectionStats {
+        self.stats
+    }
+
+    // Datagrams are counted by whoever owns the socket, the channel only sees messages
+    pub fn record_bytes_sent(&mut self, bytes: usize) {
+        self.stats.bytes_sent += bytes as u64;
+    }
+
+    pub fn record_bytes_received(&mut self, bytes: usize) {
+        self.stats.bytes_received += bytes as u64;
+    }
+
+    pub fn resend_timeout(&self) -> f64 {
+        match self.round_trip_time {
+            Some(round_trip_time) => (round_trip_time * 2.0)
+                .max(self.settings.min_resend_timeout)
+                .min(self.settings.max_resend_timeout),
+            None => self.settings.max_resend_timeout,
+        }
+    }
+
+    // Reliable messages sent but not acknowledged yet
+    #[cfg(test)]
+    pub fn pending_reliable(&self) -> usize {
+        self.reliable_queue.len()
+    }
+
+    pub fn send(&mut self, content: GameMessageType, now: f64) -> GameMessage {
+        let message_id = match content.channel() {
+            ChannelKind::ReliableOrdered => {
+                let message_id = self.next_message_id;
+                self.next_message_id = self.next_message_id.wrapping_add(1);
+
+                self.reliable_queue.push_back(PendingReliable {
+                    message_id,
+                    content: content.clone(),
+                    last_sent_at: now,
+                });
+
+                Some(message_id)
+            }
+            _ => None,
+        };
+
+        self.stamp(content, message_id, now)
+    }
+
+    fn stamp(
+        &mut self,
+        content: GameMessageType,
+        message_id: Option<u16>,
+        now: f64,
+    ) -> GameMessage {
+        self.local_sequence = self.local_sequence.wrapping_add(1);
+
+        let mut message = GameMessage::new(self.local_sequence, content);
+        message.header.ack = self.remote_sequence.unwrap_or_default();
+        message.header.ack_bits = self.received_bits;
+        message.header.message_id = message_id.unwrap_or_default();
+
+        if self.sent_packets.len() == SENT_PACKET_HISTORY {
+            self.sent_packets.pop_front();
+        }
+
+        self.sent_packets.push_back(SentPacket {
+            sequence: self.local_sequence,
+            sent_at: now,
+            message_id,
+            acked: false,
+            lost: false,
+        });
+
+        // Every packet carries the acks, no separate one is needed anymore
+        self.ack_pending_since = None;
+
+        message
+    }
+
+    // Returns the messages that are ready to be handled, which can be none (duplicates,
+    // outdated or out of order reliable messages) or several (a gap in the reliable channel
+    // got filled)
+    pub fn receive(&mut self, message: GameMessage, now: f64) -> Vec<GameMessageType> {
+        self.process_acks(message.header.ack, message.header.ack_bits, now);
+
+        let sequence = message.header.sequence;
+        match self.remote_sequence {
+            Some(remote_sequence) if sequence_greater_than(sequence, remote_sequence) => {
+                let shift = sequence.wrapping_sub(remote_sequence);
+                self.received_bits = if shift > ACK_BITS {
+                    0
+                } else {
+                    // The previous latest packet becomes bit `shift - 1`
+                    (((self.received_bits as u64) << shift) | 1 << (shift - 1)) as u32
+                };
+                self.remote_sequence = Some(sequence);
+            }
+            Some(remote_sequence) => {
+                let age = remote_sequence.wrapping_sub(sequence);
+                if age == 0 {
+                    return Vec::new();
+                }
+
+                if age <= ACK_BITS {
+                    let bit = 1 << (age - 1);
+                    if self.received_bits & bit != 0 {
+                        return Vec::new();
+                    }
+
+                    self.received_bits |= bit;
+                }
+            }
+            None => self.remote_sequence = Some(sequence),
+        }
+
+        let content = message.content;
+        match content.channel() {
+            ChannelKind::Unreliable => {
+                if let GameMessageType::Ack = content {
+                    return Vec::new();
+                }
+
+                vec![content]
+            }
+            ChannelKind::UnreliableSequenced => {
+                let is_newer = self
+                    .latest_sequenced
+                    .is_none_or(|latest| sequence_greater_than(sequence, latest));
+
+                if !is_newer {
+                    return Vec::new();
+                }
+
+                self.latest_sequenced = Some(sequence);
+                vec![content]
+            }
+            ChannelKind::ReliableOrdered => {
+                // Acked even when it's a duplicate, the ack for the first copy may have been lost
+                self.ack_pending_since.get_or_insert(now);
+
+                self.receive_reliable(message.header.message_id, content)
+            }
+        }
+    }
+
+    fn receive_reliable(
+        &mut self,
+        message_id: u16,
+        content: GameMessageType,
+    ) -> Vec<GameMessageType> {
+        let expected = self.next_expected_message_id;
+
+        if message_id_greater_than(expected, message_id)
+            || message_id.wrapping_sub(expected) >= self.settings.reliable_window
+        {
+            return Vec::new();
+        }
+
+        self.reliable_received.entry(message_id).or_insert(content);
+
+        let mut delivered = Vec::new();
+        while let Some(content) = self
+            .reliable_received
+            .remove(&self.next_expected_message_id)
+        {
+            delivered.push(content);
+            self.next_expected_message_id = self.next_expected_message_id.wrapping_add(1);
+        }
+
+        delivered
+    }
+
+    fn process_acks(&mut self, ack: u32, ack_bits: u32, now: f64) {
+        let mut acked_messages = Vec::new();
+
+        for packet in self
+            .sent_packets
+            .iter_mut()
+            .filter(|packet| !packet.acked && !packet.lost)
+        {
+            let age = ack.wrapping_sub(packet.sequence);
+            let is_acked = age == 0 || (age <= ACK_BITS && ack_bits & 1 << (age - 1) != 0);
+
+            if !is_acked {
+                // Too old to show up in any ack from now on
+                if sequence_greater_than(ack, packet.sequence) && age > ACK_BITS {
+                    packet.lost = true;
+                    self.stats.packets_lost += 1;
+                }
+
+                continue;
+            }
+
+            packet.acked = true;
+            self.stats.packets_acked += 1;
+
+            let sample = now - packet.sent_at;
+            self.round_trip_time = Some(match self.round_trip_time {
+                Some(round_trip_time) => {
+                    self.round_trip_jitter +=
+                        ((sample - round_trip_time).abs() - self.round_trip_jitter) * 0.1;
+
+                    round_trip_time + (sample - round_trip_time) * 0.1
+                }
+                None => sample,
+            });
+
+            if let Some(message_id) = packet.message_id {
+                acked_messages.push(message_id);
+            }
+        }
+
+        if !acked_messages.is_empty() {
+            self.reliable_queue
+                .retain(|pending| !acked_messages.contains(&pending.message_id));
+        }
+    }
+
+    // Resends reliable messages that went unacknowledged for too long and acknowledges
+    // received reliable messages if nothing else went out in the meantime
+    pub fn update(&mut self, now: f64) -> Vec<GameMessage> {
+        let resend_timeout = self.resend_timeout();
+        let mut resends = Vec::new();
+
+        for pending in self.reliable_queue.iter_mut() {
+            if now - pending.last_sent_at >= resend_timeout {
+                pending.last_sent_at = now;
+                resends.push((pending.message_id, pending.content.clone()));
+            }
+        }
+
+        let mut messages = resends
+            .into_iter()
+            .map(|(message_id, content)| self.stamp(content, Some(message_id), now))
+            .collect::<Vec<_>>();
+
+        if let Some(ack_pending_since) = self.ack_pending_since {
+            if now - ack_pending_since >= self.settings.ack_delay {
+                messages.push(self.stamp(GameMessageType::Ack, None, now));
+            }
+        }
+
+        messages
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::UdpSocket, time::Duration};
+
+    use super::*;
+    use crate::shared::game_message::ConnectionAcceptedData;
+
+    fn reliable(index: u16) -> GameMessageType {
+        GameMessageType::ConnectionAccepted(ConnectionAcceptedData {
+            client_id: index,
+            heartbeat_interval: 1.0,
+        })
+    }
+
+    // A real socket that drops and duplicates outgoing datagrams in a fixed pattern
+    struct LossySocket {
+        socket: UdpSocket,
+        sent: usize,
+        drop_every: usize,
+        duplicate_every: usize,
+    }
+
+    impl LossySocket {
+        fn bind(drop_every: usize, duplicate_every: usize) -> Self {
+            let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+            socket
+                .set_read_timeout(Some(Duration::from_millis(1)))
+                .unwrap();
+
+            Self {
+                socket,
+                sent: 0,
+                drop_every,
+                duplicate_every,
+            }
+        }
+
+        fn send(&mut self, message: &GameMessage, to: &LossySocket) {
+            self.sent += 1;
+
+            if self.sent.is_multiple_of(self.drop_every) {
+                return;
+            }
+
+            let bytes = message.encode().unwrap();
+            let address = to.socket.local_addr().unwrap();
+
+            self.socket.send_to(&bytes, address).unwrap();
+            if self.sent.is_multiple_of(self.duplicate_every) {
+                self.socket.send_to(&bytes, address).unwrap();
+            }
+        }
+
+        fn receive(&self) -> Vec<GameMessage> {
+            let mut buffer = [0; 1024];
+            let mut messages = Vec::new();
+
+            while let Ok(length) = self.socket.recv(&mut buffer) {
+                messages.push(GameMessage::decode(&buffer[..length]).unwrap());
+            }
+
+            messages
+        }
+    }
+
+    // Moves everything in flight one step forward, returning the reliable messages delivered
+    // to the receiver
+    fn pump(
+        now: f64,
+        sender: &mut ChannelEndpoint,
+        sender_socket: &mut LossySocket,
+        receiver: &mut ChannelEndpoint,
+        receiver_socket: &mut LossySocket,
+    ) -> Vec<GameMessageType> {
+        let mut delivered = Vec::new();
+
+        for message in receiver_socket.receive() {
+            delivered.extend(
+                receiver
+                    .receive(message, now)
+                    .into_iter()
+                    .filter(|content| content.channel() == ChannelKind::ReliableOrdered),
+            );
+        }
+        for message in receiver.update(now) {
+            receiver_socket.send(&message, sender_socket);
+        }
+
+        for message in sender_socket.receive() {
+            sender.receive(message, now);
+        }
+        for message in sender.update(now) {
+            sender_socket.send(&message, receiver_socket);
+        }
+
+        delivered
+    }
+
+    #[test]
+    fn reliable_messages_survive_lossy_sockets() {
+        let mut sender_socket = LossySocket::bind(3, 7);
+        let mut receiver_socket = LossySocket::bind(4, 5);
+        let mut sender = ChannelEndpoint::default();
+        let mut receiver = ChannelEndpoint::default();
+
+        let mut delivered = Vec::new();
+        let mut now = 0.0;
+
+        for index in 0..100 {
+            let message = sender.send(reliable(index), now);
+            sender_socket.send(&message, &receiver_socket);
+
+            // Unreliable traffic in between, some of it gets lost for good
+            let message = sender.send(GameMessageType::Heartbeat, now);
+            sender_socket.send(&message, &receiver_socket);
+
+            now += 0.02;
+            delivered.extend(pump(
+                now,
+                &mut sender,
+                &mut sender_socket,
+                &mut receiver,
+                &mut receiver_socket,
+            ));
+        }
+
+        for _ in 0..500 {
+            if sender.pending_reliable() == 0 {
+                break;
+            }
+
+            now += 0.02;
+            delivered.extend(pump(
+                now,
+                &mut sender,
+                &mut sender_socket,
+                &mut receiver,
+                &mut receiver_socket,
+            ));
+        }
+
+        assert_eq!(sender.pending_reliable(), 0);
+        assert_eq!(delivered, (0..100).map(reliable).collect::<Vec<_>>());
+        assert!(sender.round_trip_time().is_some());
+    }
+
+    #[test]
+    fn duplicates_and_outdated_sequenced_messages_are_dropped() {
+        let mut sender = ChannelEndpoint::default();
+        let mut receiver = ChannelEndpoint::default();
+
+        let heartbeat = sender.send(GameMessageType::Heartbeat, 0.0);
+        let older_input = sender.send(GameMessageType::ClientInput(Default::default()), 0.0);
+        let newer_input = sender.send(GameMessageType::ClientInput(Default::default()), 0.0);
+
+        assert_eq!(receiver.receive(heartbeat.clone(), 0.0).len(), 1);
+        assert_eq!(receiver.receive(heartbeat, 0.0).len(), 0);
+        assert_eq!(receiver.receive(newer_input, 0.0).len(), 1);
+        assert_eq!(receiver.receive(older_input, 0.0).len(), 0);
+    }
+
+    #[test]
+    fn reliable_messages_are_delivered_in_order() {
+        let mut sender = ChannelEndpoint::default();
+        let mut receiver = ChannelEndpoint::default();
+
+        let first = sender.send(reliable(0), 0.0);
+        let second = sender.send(reliable(1), 0.0);
+        let third = sender.send(reliable(2), 0.0);
+
+        assert!(receiver.receive(third.clone(), 0.0).is_empty());
+        assert!(receiver.receive(second, 0.0).is_empty());
+        assert_eq!(
+            receiver.receive(first.clone(), 0.0),
+            vec![reliable(0), reliable(1), reliable(2)]
+        );
+
+        // A resent copy of something already delivered
+        let mut resent = first;
+        resent.header.sequence = 10;
+        assert!(receiver.receive(resent, 0.0).is_empty());
+    }
+
+    #[test]
+    fn acks_clear_reliable_messages_and_measure_round_trip_time() {
+        let mut sender = ChannelEndpoint::default();
+        let mut receiver = ChannelEndpoint::default();
+
+        let message = sender.send(reliable(0), 0.0);
+        receiver.receive(message, 0.05);
+
+        // Nothing else to send, so the receiver acks on its own once the delay is up
+        assert!(receiver.update(0.06).is_empty());
+        let acks = receiver.update(0.12);
+        assert_eq!(acks.len(), 1);
+
+        for ack in acks {
+            sender.receive(ack, 0.12);
+        }
+
+        assert_eq!(sender.pending_reliable(), 0);
+        assert!((sender.round_trip_time().unwrap() - 0.12).abs() < 1e-9);
+        assert!(sender.update(5.0).is_empty());
+    }
+
+    #[test]
+    fn packets_outside_the_ack_window_count_as_lost() {
+        let mut sender = ChannelEndpoint::default();
+        let mut receiver = ChannelEndpoint::default();
+
+        // Only every other packet makes it, each one is answered right away
+        for index in 0..80 {
+            let message = sender.send(GameMessageType::Heartbeat, 0.0);
+
+            if index % 2 == 0 {
+                receiver.receive(message, 0.0);
+
+                let reply = receiver.send(GameMessageType::Heartbeat, 0.0);
+                sender.receive(reply, 0.0);
+            }
+        }
+
+        let stats = sender.stats();
+        assert_eq!(stats.packets_acked, 40);
+        // The last few missing ones could still be acknowledged by a late ack
+        assert_eq!(stats.packets_lost, 23);
+    }
+}
@@ -0,0 +1,553 @@
+use std::{collections::HashMap, convert::TryInto, error::Error, fmt};
+
+use crate::shared::game_message::PROTOCOL_VERSION;
+
+// "RADF" in ASCII, fragments are told apart from whole messages by their magic
+pub const FRAGMENT_MAGIC: u32 = 0x5241_4446;
+
+// Largest datagram ever sent, anything bigger is split. Stays below the usual 1500 byte MTU so
+// routers don't fragment on our behalf.
+pub const MAX_PACKET_SIZE: usize = 1024;
+
+// magic (4) + version (2) + group (2) + index (1) + count (1)
+pub const FRAGMENT_HEADER_SIZE: usize = 10;
+pub const FRAGMENT_PAYLOAD_SIZE: usize = MAX_PACKET_SIZE - FRAGMENT_HEADER_SIZE;
+pub const MAX_FRAGMENTS: usize = u8::MAX as usize;
+pub const MAX_MESSAGE_SIZE: usize = MAX_FRAGMENTS * FRAGMENT_PAYLOAD_SIZE;
+
+pub fn is_fragment(bytes: &[u8]) -> bool {
+    bytes.len() >= 4 && bytes[0..4] == FRAGMENT_MAGIC.to_le_bytes()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FragmentHeader {
+    pub group: u16,
+    pub index: u8,
+    pub count: u8,
+}
+
+impl FragmentHeader {
+    fn write(&self, bytes: &mut Vec<u8>) {
+        bytes.extend_from_slice(&FRAGMENT_MAGIC.to_le_bytes());
+        bytes.extend_from_slice(&PROTOCOL_VERSION.to_le_bytes());
+        bytes.extend_from_slice(&self.group.to_le_bytes());
+        bytes.push(self.index);
+        bytes.push(self.count);
+    }
+
+    pub fn read(bytes: &[u8]) -> Result<Self, FragmentError> {
+        if bytes.len() < FRAGMENT_HEADER_SIZE {
+            return Err(FragmentError::Truncated {
+                received: bytes.len(),
+            });
+        }
+
+        if !is_fragment(bytes) {
+            return Err(FragmentError::NotAFragment);
+        }
+
+        let version = u16::from_le_bytes(bytes[4..6].try_into().unwrap());
+        if version != PROTOCOL_VERSION {
+            return Err(FragmentError::VersionMismatch {
+                expected: PROTOCOL_VERSION,
+                received: version,
+            });
+        }
+
+        let header = Self {
+            group: u16::from_le_bytes(bytes[6..8].try_into().unwrap()),
+            index: bytes[8],
+            count: bytes[9],
+        };
+
+        // A single fragment would have been sent whole
+        if header.count < 2 || header.index >= header.count {
+            return Err(FragmentError::InvalidIndex {
+                index: header.index,
+                count: header.count,
+            });
+        }
+
+        let payload_size = bytes.len() - FRAGMENT_HEADER_SIZE;
+        let is_last = header.index == header.count - 1;
+
+        // Every fragment but the last one is full
+        if payload_size > FRAGMENT_PAYLOAD_SIZE
+            || payload_size == 0
+            || (!is_last && payload_size != FRAGMENT_PAYLOAD_SIZE)
+        {
+            return Err(FragmentError::InvalidPayloadSize {
+                index: header.index,
+                size: payload_size,
+            });
+        }
+
+        Ok(header)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Fragmenter {
+    next_group: u16,
+}
+
+impl Fragmenter {
+    // Returns the datagrams to send, which is the message itself when it fits in one
+    pub fn split(&mut self, bytes: Vec<u8>) -> Result<Vec<Vec<u8>>, FragmentError> {
+        if bytes.len() <= MAX_PACKET_SIZE {
+            return Ok(vec![bytes]);
+        }
+
+        if bytes.len() > MAX_MESSAGE_SIZE {
+            return Err(FragmentError::MessageTooLarge { size: bytes.len() });
+        }
+
+        let group = self.next_group;
+        self.next_group = self.next_group.wrapping_add(1);
+
+        let chunks = bytes.chunks(FRAGMENT_PAYLOAD_SIZE);
+        let count = chunks.len() as u8;
+
+        Ok(chunks
+            .enumerate()
+            .map(|(index, chunk)| {
+                let mut fragment = Vec::with_capacity(FRAGMENT_HEADER_SIZE + chunk.len());
+                FragmentHeader {
+                    group,
+                    index: index as u8,
+                    count,
+                }
+                .write(&mut fragment);
+                fragment.extend_from_slice(chunk);
+                fragment
+            })
+            .collect())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ReassemblySettings {
+    // Incomplete groups are dropped after this many seconds, one of their fragments got lost
+    pub timeout: f64,
+    pub max_groups: usize,
+    // Bytes held by incomplete groups, the oldest groups are dropped to stay below it
+    pub max_buffered_bytes: usize,
+}
+
+impl Default for ReassemblySettings {
+    fn default() -> Self {
+        Self {
+            timeout: 1.0,
+            max_groups: 8,
+            max_buffered_bytes: 256 * 1024,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct FragmentGroup {
+    created_at: f64,
+    fragments: Vec<Option<Vec<u8>>>,
+    received: usize,
+    buffered_bytes: usize,
+}
+
+// Collects the fragments of one sender
+#[derive(Debug, Default)]
+pub struct FragmentReassembler {
+    settings: ReassemblySettings,
+    groups: HashMap<u16, FragmentGroup>,
+    buffered_bytes: usize,
+}
+
+impl FragmentReassembler {
+    #[cfg(test)]
+    pub fn new(settings: ReassemblySettings) -> Self {
+        Self {
+            settings,
+            ..Default::default()
+        }
+    }
+
+    #[cfg(test)]
+    pub fn incomplete_groups(&self) -> usize {
+        self.groups.len()
+    }
+
+    #[cfg(test)]
+    pub fn buffered_bytes(&self) -> usize {
+        self.buffered_bytes
+    }
+
+    // Returns the whole message once its last missing fragment arrives
+    pub fn receive(&mut self, bytes: &[u8], now: f64) -> Result<Option<Vec<u8>>, FragmentError> {
+        let header = FragmentHeader::read(bytes)?;
+        let payload = &bytes[FRAGMENT_HEADER_SIZE..];
+
+        self.expire(now);
+
+        match self.groups.get(&header.group) {
+            Some(group) => {
+                if group.fragments.len() != header.count as usize {
+                    return Err(FragmentError::CountMismatch {
+                        group: header.group,
+                        expected: group.fragments.len() as u8,
+                        received: header.count,
+                    });
+                }
+
+                if group.fragments[header.index as usize].is_some() {
+                    // Duplicate
+                    return Ok(None);
+                }
+            }
+            None => {
+                let size = header.count as usize * FRAGMENT_PAYLOAD_SIZE;
+                if size > self.settings.max_buffered_bytes {
+                    return Err(FragmentError::MessageTooLarge { size });
+                }
+            }
+        }
+
+        self.make_room(header.group, payload.len());
+
+        let group = self
+            .groups
+            .entry(header.group)
+            .or_insert_with(|| FragmentGroup {
+                created_at: now,
+                fragments: vec![None; header.count as usize],
+                received: 0,
+                buffered_bytes: 0,
+            });
+
+        group.fragments[header.index as usize] = Some(payload.to_vec());
+        group.received += 1;
+        group.buffered_bytes += payload.len();
+        self.buffered_bytes += payload.len();
+
+        if group.received < group.fragments.len() {
+            return Ok(None);
+        }
+
+        let group = self.groups.remove(&header.group).unwrap();
+        self.buffered_bytes -= group.buffered_bytes;
+
+        Ok(Some(
+            group.fragments.into_iter().flatten().flatten().collect(),
+        ))
+    }
+
+    pub fn expire(&mut self, now: f64) {
+        let timeout = self.settings.timeout;
+        let mut expired_bytes = 0;
+
+        self.groups.retain(|_, group| {
+            let keep = now - group.created_at < timeout;
+            if !keep {
+                expired_bytes += group.buffered_bytes;
+            }
+
+            keep
+        });
+
+        self.buffered_bytes -= expired_bytes;
+    }
+
+    // Drops the oldest other groups until the incoming fragment fits within the limits
+    fn make_room(&mut self, receiving_group: u16, incoming_bytes: usize) {
+        let group_count = if self.groups.contains_key(&receiving_group) {
+            self.groups.len()
+        } else {
+            self.groups.len() + 1
+        };
+
+        if group_count <= self.settings.max_groups
+            && self.buffered_bytes + incoming_bytes <= self.settings.max_buffered_bytes
+        {
+            return;
+        }
+
+        let oldest = self
+            .groups
+            .iter()
+            .filter(|(group, _)| **group != receiving_group)
+            .min_by(|(_, a), (_, b)| a.created_at.partial_cmp(&b.created_at).unwrap())
+            .map(|(group, _)| *group);
+
+        if let Some(oldest) = oldest {
+            let group = self.groups.remove(&oldest).unwrap();
+            self.buffered_bytes -= group.buffered_bytes;
+
+            self.make_room(receiving_group, incoming_bytes);
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FragmentError {
+    Truncated {
+        received: usize,
+    },
+    NotAFragment,
+    VersionMismatch {
+        expected: u16,
+        received: u16,
+    },
+    InvalidIndex {
+        index: u8,
+        count: u8,
+    },
+    InvalidPayloadSize {
+        index: u8,
+        size: usize,
+    },
+    CountMismatch {
+        group: u16,
+        expected: u8,
+        received: u8,
+    },
+    MessageTooLarge {
+        size: usize,
+    },
+}
+
+impl fmt::Display for FragmentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FragmentError::Truncated { received } => write!(
+                f,
+                "fragment header truncated: expected {} bytes, received {}",
+                FRAGMENT_HEADER_SIZE, received
+            ),
+            FragmentError::NotAFragment => write!(f, "packet is not a fragment"),
+            FragmentError::VersionMismatch { expected, received } => write!(
+                f,
+                "protocol version mismatch: expected {}, received {}",
+                expected, received
+            ),
+            FragmentError::InvalidIndex { index, count } => {
+                write!(f, "invalid fragment index {} of {}", index, count)
+            }
+            FragmentError::InvalidPayloadSize { index, size } => {
+                write!(
+                    f,
+                    "fragment {} has an invalid size of {} bytes",
+                    index, size
+                )
+            }
+            FragmentError::CountMismatch {
+                group,
+                expected,
+                received,
+            } => write!(
+                f,
+                "fragment group {} has {} fragments, received one claiming {}",
+                group, expected, received
+            ),
+            FragmentError::MessageTooLarge { size } => write!(
+                f,
+                "message of {} bytes exceeds the {} byte limit",
+                size, MAX_MESSAGE_SIZE
+            ),
+        }
+    }
+}
+
+impl Error for FragmentError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared::game_message::{
+        GameMessage, GameMessageType, PropStateData, ServerGameStateSnapshotData,
+    };
+    use bevy::math::{Quat, Vec3};
+
+    fn message(size: usize) -> Vec<u8> {
+        (0..size).map(|index| (index % 251) as u8).collect()
+    }
+
+    #[test]
+    fn small_messages_are_sent_whole() {
+        let bytes = message(MAX_PACKET_SIZE);
+        let datagrams = Fragmenter::default().split(bytes.clone()).unwrap();
+
+        assert_eq!(datagrams, vec![bytes]);
+    }
+
+    #[test]
+    fn out_of_order_fragments_are_reassembled() {
+        let bytes = message(5000);
+        let mut datagrams = Fragmenter::default().split(bytes.clone()).unwrap();
+        assert_eq!(datagrams.len(), 5);
+        assert!(datagrams
+            .iter()
+            .all(|datagram| datagram.len() <= MAX_PACKET_SIZE));
+
+        datagrams.swap(0, 3);
+        datagrams.reverse();
+        // Duplicates are ignored
+        datagrams.insert(2, datagrams[1].clone());
+
+        let mut reassembler = FragmentReassembler::default();
+        let mut reassembled = Vec::new();
+        for datagram in datagrams.iter() {
+            if let Some(message) = reassembler.receive(datagram, 0.0).unwrap() {
+                reassembled.push(message);
+            }
+        }
+
+        assert_eq!(reassembled, vec![bytes]);
+        assert_eq!(reassembler.incomplete_groups(), 0);
+        assert_eq!(reassembler.buffered_bytes(), 0);
+    }
+
+    #[test]
+    fn partially_lost_groups_time_out() {
+        let mut fragmenter = Fragmenter::default();
+        let mut reassembler = FragmentReassembler::default();
+
+        let lost = fragmenter.split(message(3000)).unwrap();
+        for datagram in lost.iter().skip(1) {
+            assert_eq!(reassembler.receive(datagram, 0.0), Ok(None));
+        }
+        assert_eq!(reassembler.incomplete_groups(), 1);
+
+        // The next message arrives completely after the first one was given up on
+        let bytes = message(2000);
+        let mut reassembled = None;
+        for datagram in fragmenter.split(bytes.clone()).unwrap().iter() {
+            reassembled = reassembler.receive(datagram, 2.0).unwrap();
+        }
+
+        assert_eq!(reassembled, Some(bytes));
+        assert_eq!(reassembler.incomplete_groups(), 0);
+
+        // The missing fragment showing up late only starts a new group that expires as well
+        assert_eq!(reassembler.receive(&lost[0], 2.0), Ok(None));
+        reassembler.expire(4.0);
+        assert_eq!(reassembler.buffered_bytes(), 0);
+    }
+
+    #[test]
+    fn incomplete_groups_are_capped() {
+        let mut fragmenter = Fragmenter::default();
+        let mut reassembler = FragmentReassembler::new(ReassemblySettings {
+            max_groups: 3,
+            max_buffered_bytes: 5 * FRAGMENT_PAYLOAD_SIZE,
+            ..Default::default()
+        });
+
+        for time in 0..5 {
+            let datagrams = fragmenter
+                .split(message(3 * FRAGMENT_PAYLOAD_SIZE))
+                .unwrap();
+            reassembler.receive(&datagrams[0], time as f64).unwrap();
+            reassembler.receive(&datagrams[1], time as f64).unwrap();
+
+            assert!(reassembler.incomplete_groups() <= 2);
+            assert!(reassembler.buffered_bytes() <= 5 * FRAGMENT_PAYLOAD_SIZE);
+        }
+
+        // Groups that could never fit are refused outright
+        let datagrams = fragmenter
+            .split(message(6 * FRAGMENT_PAYLOAD_SIZE))
+            .unwrap();
+        assert!(matches!(
+            reassembler.receive(&datagrams[0], 5.0),
+            Err(FragmentError::MessageTooLarge { .. })
+        ));
+    }
+
+    #[test]
+    fn malformed_fragments_are_rejected() {
+        let datagrams = Fragmenter::default().split(message(2500)).unwrap();
+        let mut reassembler = FragmentReassembler::default();
+
+        let mut out_of_range = datagrams[0].clone();
+        out_of_range[8] = 3;
+        assert_eq!(
+            reassembler.receive(&out_of_range, 0.0),
+            Err(FragmentError::InvalidIndex { index: 3, count: 3 })
+        );
+
+        let mut single = datagrams[0].clone();
+        single[9] = 1;
+        single[8] = 0;
+        assert!(matches!(
+            reassembler.receive(&single, 0.0),
+            Err(FragmentError::InvalidIndex { .. })
+        ));
+
+        let short = &datagrams[0][..FRAGMENT_HEADER_SIZE + 10];
+        assert!(matches!(
+            reassembler.receive(short, 0.0),
+            Err(FragmentError::InvalidPayloadSize { .. })
+        ));
+
+        assert!(matches!(
+            reassembler.receive(&datagrams[0][..6], 0.0),
+            Err(FragmentError::Truncated { received: 6 })
+        ));
+
+        let mut wrong_version = datagrams[0].clone();
+        wrong_version[4] = wrong_version[4].wrapping_add(1);
+        assert!(matches!(
+            reassembler.receive(&wrong_version, 0.0),
+            Err(FragmentError::VersionMismatch { .. })
+        ));
+
+        reassembler.receive(&datagrams[0], 0.0).unwrap();
+        let mut other_count = datagrams[1].clone();
+        other_count[9] = 4;
+        assert!(matches!(
+            reassembler.receive(&other_count, 0.0),
+            Err(FragmentError::CountMismatch { .. })
+        ));
+
+        assert_eq!(
+            Fragmenter::default().split(message(MAX_MESSAGE_SIZE + 1)),
+            Err(FragmentError::MessageTooLarge {
+                size: MAX_MESSAGE_SIZE + 1
+            })
+        );
+    }
+
+    #[test]
+    fn oversized_snapshots_survive_the_round_trip() {
+        let snapshot = ServerGameStateSnapshotData {
+            tick: 1,
+            time: 0.05,
+            last_processed_input: 0,
+            players: Vec::new(),
+            props: (0..200)
+                .map(|prop_id| PropStateData {
+                    prop_id,
+                    translation: Vec3::new(prop_id as f32, 1.0, 0.0),
+                    rotation: Quat::IDENTITY,
+                    linear_velocity: Vec3::ZERO,
+                })
+                .collect(),
+        };
+
+        let bytes = GameMessage::new(1, GameMessageType::ServerGameStateSnapshot(snapshot))
+            .encode()
+            .unwrap();
+        let datagrams = Fragmenter::default().split(bytes.clone()).unwrap();
+        assert!(datagrams.len() > 1);
+
+        let mut reassembler = FragmentReassembler::default();
+        let reassembled = datagrams
+            .iter()
+            .rev()
+            .find_map(|datagram| reassembler.receive(datagram, 0.0).unwrap())
+            .unwrap();
+
+        assert_eq!(reassembled, bytes);
+        assert_eq!(
+            GameMessage::decode(&reassembled).unwrap(),
+            GameMessage::decode(&bytes).unwrap()
+        );
+    }
+}
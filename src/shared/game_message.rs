@@ -0,0 +1,627 @@
+use std::{
+    collections::hash_map::RandomState,
+    convert::TryInto,
+    error::Error,
+    fmt,
+    hash::{BuildHasher, Hasher},
+    time::SystemTime,
+};
+
+use bevy::math::{Quat, Vec3};
+use serde::{Deserialize, Serialize};
+
+use crate::shared::{
+    bit_packing::{BitPacked, BitReader, BitWriter},
+    channel::ChannelKind,
+};
+
+// "RADW" in ASCII, used to discard datagrams that don't belong to us
+pub const PROTOCOL_MAGIC: u32 = 0x5241_4457;
+pub const PROTOCOL_VERSION: u16 = 14;
+
+// magic (4) + version (2) + sequence (4) + ack (4) + ack bits (4) + message id (2) + kind (1)
+pub const PACKET_HEADER_SIZE: usize = 21;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GameMessageKind {
+    ClientInput = 0,
+    ServerGameStateSnapshot = 1,
+    ConnectionRequest = 2,
+    ConnectionChallenge = 3,
+    ConnectionChallengeResponse = 4,
+    ConnectionAccepted = 5,
+    ConnectionDenied = 6,
+    Heartbeat = 7,
+    Disconnect = 8,
+    ServerGameStateDelta = 9,
+    Ack = 10,
+    TimeSyncRequest = 11,
+    TimeSyncResponse = 12,
+    ComponentUpdates = 13,
+    EntitiesSpawned = 14,
+    EntitiesDespawned = 15,
+    NetworkEvent = 16,
+}
+
+impl GameMessageKind {
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(GameMessageKind::ClientInput),
+            1 => Some(GameMessageKind::ServerGameStateSnapshot),
+            2 => Some(GameMessageKind::ConnectionRequest),
+            3 => Some(GameMessageKind::ConnectionChallenge),
+            4 => Some(GameMessageKind::ConnectionChallengeResponse),
+            5 => Some(GameMessageKind::ConnectionAccepted),
+            6 => Some(GameMessageKind::ConnectionDenied),
+            7 => Some(GameMessageKind::Heartbeat),
+            8 => Some(GameMessageKind::Disconnect),
+            9 => Some(GameMessageKind::ServerGameStateDelta),
+            10 => Some(GameMessageKind::Ack),
+            11 => Some(GameMessageKind::TimeSyncRequest),
+            12 => Some(GameMessageKind::TimeSyncResponse),
+            13 => Some(GameMessageKind::ComponentUpdates),
+            14 => Some(GameMessageKind::EntitiesSpawned),
+            15 => Some(GameMessageKind::EntitiesDespawned),
+            16 => Some(GameMessageKind::NetworkEvent),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PacketHeader {
+    pub magic: u32,
+    pub version: u16,
+    pub sequence: u32,
+    // Latest sequence received from the other side, bit n of `ack_bits` acknowledges `ack - n - 1`
+    pub ack: u32,
+    pub ack_bits: u32,
+    // Only meaningful on the reliable channel
+    pub message_id: u16,
+    pub kind: GameMessageKind,
+}
+
+impl PacketHeader {
+    pub fn new(sequence: u32, kind: GameMessageKind) -> Self {
+        Self {
+            magic: PROTOCOL_MAGIC,
+            version: PROTOCOL_VERSION,
+            sequence,
+            ack: 0,
+            ack_bits: 0,
+            message_id: 0,
+            kind,
+        }
+    }
+
+    fn write(&self, bytes: &mut Vec<u8>) {
+        bytes.extend_from_slice(&self.magic.to_le_bytes());
+        bytes.extend_from_slice(&self.version.to_le_bytes());
+        bytes.extend_from_slice(&self.sequence.to_le_bytes());
+        bytes.extend_from_slice(&self.ack.to_le_bytes());
+        bytes.extend_from_slice(&self.ack_bits.to_le_bytes());
+        bytes.extend_from_slice(&self.message_id.to_le_bytes());
+        bytes.push(self.kind as u8);
+    }
+
+    fn read(bytes: &[u8]) -> Result<Self, GameMessageError> {
+        if bytes.len() < PACKET_HEADER_SIZE {
+            return Err(GameMessageError::TruncatedHeader {
+                received: bytes.len(),
+            });
+        }
+
+        let magic = u32::from_le_bytes(bytes[0..4].try_into().unwrap());
+        if magic != PROTOCOL_MAGIC {
+            return Err(GameMessageError::InvalidMagic(magic));
+        }
+
+        let version = u16::from_le_bytes(bytes[4..6].try_into().unwrap());
+        if version != PROTOCOL_VERSION {
+            return Err(GameMessageError::VersionMismatch {
+                expected: PROTOCOL_VERSION,
+                received: version,
+            });
+        }
+
+        let sequence = u32::from_le_bytes(bytes[6..10].try_into().unwrap());
+        let ack = u32::from_le_bytes(bytes[10..14].try_into().unwrap());
+        let ack_bits = u32::from_le_bytes(bytes[14..18].try_into().unwrap());
+        let message_id = u16::from_le_bytes(bytes[18..20].try_into().unwrap());
+        let kind =
+            GameMessageKind::from_u8(bytes[20]).ok_or(GameMessageError::UnknownKind(bytes[20]))?;
+
+        Ok(Self {
+            magic,
+            version,
+            sequence,
+            ack,
+            ack_bits,
+            message_id,
+            kind,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum GameMessageType {
+    ClientInput(ClientInputBatchData),
+    ServerGameStateSnapshot(ServerGameStateSnapshotData),
+    ConnectionRequest(ConnectionRequestData),
+    ConnectionChallenge(ConnectionChallengeData),
+    ConnectionChallengeResponse(ConnectionChallengeResponseData),
+    ConnectionAccepted(ConnectionAcceptedData),
+    ConnectionDenied(ConnectionDeniedData),
+    Heartbeat,
+    Disconnect,
+    ServerGameStateDelta(ServerGameStateDeltaData),
+    // Carries nothing but the header, sent when acks are due and there's no other traffic
+    Ack,
+    TimeSyncRequest(TimeSyncRequestData),
+    TimeSyncResponse(TimeSyncResponseData),
+    ComponentUpdates(ComponentUpdatesData),
+    EntitiesSpawned(EntitiesSpawnedData),
+    EntitiesDespawned(EntitiesDespawnedData),
+    NetworkEvent(NetworkEventData),
+}
+
+impl GameMessageType {
+    pub fn kind(&self) -> GameMessageKind {
+        match self {
+            GameMessageType::ClientInput(_) => GameMessageKind::ClientInput,
+            GameMessageType::ServerGameStateSnapshot(_) => GameMessageKind::ServerGameStateSnapshot,
+            GameMessageType::ConnectionRequest(_) => GameMessageKind::ConnectionRequest,
+            GameMessageType::ConnectionChallenge(_) => GameMessageKind::ConnectionChallenge,
+            GameMessageType::ConnectionChallengeResponse(_) => {
+                GameMessageKind::ConnectionChallengeResponse
+            }
+            GameMessageType::ConnectionAccepted(_) => GameMessageKind::ConnectionAccepted,
+            GameMessageType::ConnectionDenied(_) => GameMessageKind::ConnectionDenied,
+            GameMessageType::Heartbeat => GameMessageKind::Heartbeat,
+            GameMessageType::Disconnect => GameMessageKind::Disconnect,
+            GameMessageType::ServerGameStateDelta(_) => GameMessageKind::ServerGameStateDelta,
+            GameMessageType::Ack => GameMessageKind::Ack,
+            GameMessageType::TimeSyncRequest(_) => GameMessageKind::TimeSyncRequest,
+            GameMessageType::TimeSyncResponse(_) => GameMessageKind::TimeSyncResponse,
+            GameMessageType::ComponentUpdates(_) => GameMessageKind::ComponentUpdates,
+            GameMessageType::EntitiesSpawned(_) => GameMessageKind::EntitiesSpawned,
+            GameMessageType::EntitiesDespawned(_) => GameMessageKind::EntitiesDespawned,
+            GameMessageType::NetworkEvent(_) => GameMessageKind::NetworkEvent,
+        }
+    }
+
+    pub fn channel(&self) -> ChannelKind {
+        match self {
+            // Only the newest state matters, anything older than what was already received
+            // is dropped
+            GameMessageType::ClientInput(_)
+            | GameMessageType::ServerGameStateSnapshot(_)
+            | GameMessageType::ServerGameStateDelta(_) => ChannelKind::UnreliableSequenced,
+            // Only changes are sent, every one of them has to arrive
+            GameMessageType::ConnectionAccepted(_)
+            | GameMessageType::ComponentUpdates(_)
+            | GameMessageType::EntitiesSpawned(_)
+            | GameMessageType::EntitiesDespawned(_)
+            | GameMessageType::NetworkEvent(_) => ChannelKind::ReliableOrdered,
+            // The handshake retries on its own until a session exists
+            GameMessageType::ConnectionRequest(_)
+            | GameMessageType::ConnectionChallenge(_)
+            | GameMessageType::ConnectionChallengeResponse(_)
+            | GameMessageType::ConnectionDenied(_)
+            | GameMessageType::Heartbeat
+            | GameMessageType::Disconnect
+            | GameMessageType::Ack => ChannelKind::Unreliable,
+            // A resent request would carry a stale timestamp, the next one is sent soon enough
+            GameMessageType::TimeSyncRequest(_) | GameMessageType::TimeSyncResponse(_) => {
+                ChannelKind::Unreliable
+            }
+        }
+    }
+}
+
+pub type ClientId = u16;
+
+// Compares sequence numbers while tolerating wrap around
+pub fn sequence_greater_than(a: u32, b: u32) -> bool {
+    a != b && a.wrapping_sub(b) < u32::MAX / 2
+}
+
+// Salts make sure a peer can only complete the handshake if it actually receives packets at the
+// address it claims to be sending from.
+pub fn generate_salt() -> u64 {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|duration| duration.as_nanos())
+        .unwrap_or_default();
+
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(now);
+    hasher.finish()
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ConnectionRequestData {
+    pub client_salt: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ConnectionChallengeData {
+    pub client_salt: u64,
+    pub server_salt: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ConnectionChallengeResponseData {
+    pub challenge: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ConnectionAcceptedData {
+    pub client_id: ClientId,
+    pub heartbeat_interval: f32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ConnectionDeniedData {
+    pub reason: ConnectionDeniedReason,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionDeniedReason {
+    ServerFull,
+    InvalidChallenge,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TimeSyncRequestData {
+    // Client clock when the request was sent, echoed back in the response
+    pub client_time: f64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TimeSyncResponseData {
+    pub client_time: f64,
+    // Server clock and tick when the request was handled
+    pub server_time: f64,
+    pub server_tick: u32,
+}
+
+// The newest inputs, oldest first. Each one is repeated in the next few packets, so a lost packet
+// costs nothing as long as one of those arrives.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct ClientInputBatchData {
+    // Tick of the newest snapshot the client received, the server encodes deltas against it
+    pub acknowledged_snapshot: u32,
+    pub inputs: Vec<ClientInputData>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct ClientInputData {
+    pub sequence: u32,
+    // Client tick the input was sampled on
+    pub tick: u32,
+
+    pub move_forward: bool,
+    pub move_left: bool,
+    pub move_back: bool,
+    pub move_right: bool,
+
+    pub mouse_horizontal: f32,
+    pub mouse_vertical: f32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct ServerGameStateSnapshotData {
+    pub tick: u32,
+    // Server clock in seconds when the snapshot was taken
+    pub time: f64,
+    // Sequence of the newest input from the receiving client the server has applied
+    pub last_processed_input: u32,
+    pub players: Vec<PlayerStateData>,
+    pub props: Vec<PropStateData>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct PlayerStateData {
+    pub client_id: ClientId,
+    pub translation: Vec3,
+    pub rotation: Quat,
+    pub linear_velocity: Vec3,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct PropStateData {
+    pub prop_id: u32,
+    pub translation: Vec3,
+    pub rotation: Quat,
+    pub linear_velocity: Vec3,
+}
+
+// Only the entities and fields that changed since `baseline_tick`, see `shared::snapshot_delta`
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct ServerGameStateDeltaData {
+    pub tick: u32,
+    pub baseline_tick: u32,
+    pub time: f64,
+    pub last_processed_input: u32,
+    pub players: Vec<EntityStateDelta<ClientId>>,
+    pub removed_players: Vec<ClientId>,
+    pub props: Vec<EntityStateDelta<u32>>,
+    pub removed_props: Vec<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct EntityStateDelta<Id> {
+    pub id: Id,
+    pub translation: Option<Vec3>,
+    pub rotation: Option<Quat>,
+    pub linear_velocity: Option<Vec3>,
+}
+
+// Allocated by the server for every replicated entity, `Entity` ids differ between processes
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NetworkId(pub u32);
+
+// What a client spawns for a replicated entity, entities without one are spawned bare
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NetworkPrefab {
+    Player { client_id: ClientId },
+    PlayerHead,
+    Prop { prop_id: u32 },
+}
+
+// Parents are always spawned before their children
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct EntitiesSpawnedData {
+    pub entities: Vec<EntitySpawnData>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct EntitySpawnData {
+    pub network_id: NetworkId,
+    pub prefab: Option<NetworkPrefab>,
+    pub parent: Option<NetworkId>,
+    // Relative to the parent, if there is one
+    pub translation: Vec3,
+    pub rotation: Quat,
+    pub components: Vec<ReplicatedComponentData>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct EntitiesDespawnedData {
+    pub network_ids: Vec<NetworkId>,
+}
+
+// Components of replicated entities, see `shared::replication`
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct ComponentUpdatesData {
+    pub entities: Vec<EntityComponentsData>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct EntityComponentsData {
+    pub network_id: NetworkId,
+    pub components: Vec<ReplicatedComponentData>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ReplicatedComponentData {
+    // Index of the component type in the `ReplicationRegistry`
+    pub kind: u16,
+    pub bytes: Vec<u8>,
+}
+
+// An event declared through `shared::network_events`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct NetworkEventData {
+    // Index of the event type in the `NetworkEventRegistry`
+    pub kind: u16,
+    pub bytes: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GameMessage {
+    pub header: PacketHeader,
+    pub content: GameMessageType,
+}
+
+impl GameMessage {
+    pub fn new(sequence: u32, content: GameMessageType) -> Self {
+        Self {
+            header: PacketHeader::new(sequence, content.kind()),
+            content,
+        }
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>, GameMessageError> {
+        let mut bytes = Vec::with_capacity(PACKET_HEADER_SIZE);
+        self.header.write(&mut bytes);
+
+        // State and input messages make up nearly all traffic, they are quantized and bit-packed
+        let mut writer = BitWriter::new();
+        match &self.content {
+            GameMessageType::ClientInput(input) => input.pack(&mut writer),
+            GameMessageType::ServerGameStateSnapshot(snapshot) => snapshot.pack(&mut writer),
+            GameMessageType::ServerGameStateDelta(delta) => delta.pack(&mut writer),
+            content => {
+                bincode::serialize_into(&mut bytes, content)
+                    .map_err(GameMessageError::Malformed)?;
+                return Ok(bytes);
+            }
+        }
+        bytes.extend(writer.finish());
+
+        Ok(bytes)
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, GameMessageError> {
+        let header = PacketHeader::read(bytes)?;
+
+        let content_bytes = &bytes[PACKET_HEADER_SIZE..];
+        let mut reader = BitReader::new(content_bytes);
+        let truncated = |_| GameMessageError::TruncatedContent(header.kind);
+
+        let content: GameMessageType = match header.kind {
+            GameMessageKind::ClientInput => {
+                GameMessageType::ClientInput(BitPacked::unpack(&mut reader).map_err(truncated)?)
+            }
+            GameMessageKind::ServerGameStateSnapshot => GameMessageType::ServerGameStateSnapshot(
+                BitPacked::unpack(&mut reader).map_err(truncated)?,
+            ),
+            GameMessageKind::ServerGameStateDelta => GameMessageType::ServerGameStateDelta(
+                BitPacked::unpack(&mut reader).map_err(truncated)?,
+            ),
+            _ => bincode::deserialize(content_bytes).map_err(|error| match *error {
+                bincode::ErrorKind::Io(_) => GameMessageError::TruncatedContent(header.kind),
+                _ => GameMessageError::Malformed(error),
+            })?,
+        };
+
+        if content.kind() != header.kind {
+            return Err(GameMessageError::KindMismatch {
+                header: header.kind,
+                content: content.kind(),
+            });
+        }
+
+        Ok(Self { header, content })
+    }
+}
+
+#[derive(Debug)]
+pub enum GameMessageError {
+    TruncatedHeader {
+        received: usize,
+    },
+    TruncatedContent(GameMessageKind),
+    InvalidMagic(u32),
+    VersionMismatch {
+        expected: u16,
+        received: u16,
+    },
+    UnknownKind(u8),
+    KindMismatch {
+        header: GameMessageKind,
+        content: GameMessageKind,
+    },
+    Malformed(bincode::Error),
+}
+
+impl fmt::Display for GameMessageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GameMessageError::TruncatedHeader { received } => write!(
+                f,
+                "packet header truncated: expected {} bytes, received {}",
+                PACKET_HEADER_SIZE, received
+            ),
+            GameMessageError::TruncatedContent(kind) => {
+                write!(f, "packet content truncated for {:?} message", kind)
+            }
+            GameMessageError::InvalidMagic(magic) => {
+                write!(f, "invalid protocol magic: {:#010x}", magic)
+            }
+            GameMessageError::VersionMismatch { expected, received } => write!(
+                f,
+                "protocol version mismatch: expected {}, received {}",
+                expected, received
+            ),
+            GameMessageError::UnknownKind(kind) => write!(f, "unknown message kind: {}", kind),
+            GameMessageError::KindMismatch { header, content } => write!(
+                f,
+                "message kind mismatch: header says {:?}, content is {:?}",
+                header, content
+            ),
+            GameMessageError::Malformed(error) => write!(f, "malformed message: {}", error),
+        }
+    }
+}
+
+impl Error for GameMessageError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            GameMessageError::Malformed(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encoded(content: GameMessageType) -> Vec<u8> {
+        GameMessage::new(1, content).encode().unwrap()
+    }
+
+    #[test]
+    fn rejects_other_protocols() {
+        let mut bytes = encoded(GameMessageType::Heartbeat);
+        bytes[0..4].copy_from_slice(&0xdead_beefu32.to_le_bytes());
+
+        assert!(matches!(
+            GameMessage::decode(&bytes),
+            Err(GameMessageError::InvalidMagic(0xdead_beef))
+        ));
+    }
+
+    #[test]
+    fn rejects_other_versions() {
+        let mut bytes = encoded(GameMessageType::Heartbeat);
+        bytes[4..6].copy_from_slice(&(PROTOCOL_VERSION - 1).to_le_bytes());
+
+        let error = GameMessage::decode(&bytes).unwrap_err();
+        assert!(matches!(
+            error,
+            GameMessageError::VersionMismatch { expected, received }
+                if expected == PROTOCOL_VERSION && received == PROTOCOL_VERSION - 1
+        ));
+    }
+
+    #[test]
+    fn rejects_unknown_kinds() {
+        let mut bytes = encoded(GameMessageType::Heartbeat);
+        bytes[20] = 200;
+
+        assert!(matches!(
+            GameMessage::decode(&bytes),
+            Err(GameMessageError::UnknownKind(200))
+        ));
+    }
+
+    #[test]
+    fn rejects_truncated_headers() {
+        let bytes = encoded(GameMessageType::Heartbeat);
+
+        assert!(matches!(
+            GameMessage::decode(&bytes[..10]),
+            Err(GameMessageError::TruncatedHeader { received: 10 })
+        ));
+    }
+
+    #[test]
+    fn rejects_truncated_content() {
+        let bytes = encoded(GameMessageType::ConnectionRequest(ConnectionRequestData {
+            client_salt: 42,
+        }));
+
+        assert!(matches!(
+            GameMessage::decode(&bytes[..bytes.len() - 1]),
+            Err(GameMessageError::TruncatedContent(
+                GameMessageKind::ConnectionRequest
+            ))
+        ));
+
+        // Bit-packed content runs out the same way
+        let bytes = encoded(GameMessageType::ServerGameStateSnapshot(
+            ServerGameStateSnapshotData::default(),
+        ));
+
+        assert!(matches!(
+            GameMessage::decode(&bytes[..PACKET_HEADER_SIZE]),
+            Err(GameMessageError::TruncatedContent(
+                GameMessageKind::ServerGameStateSnapshot
+            ))
+        ));
+    }
+}
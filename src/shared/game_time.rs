@@ -0,0 +1,99 @@
+use std::sync::{Arc, Mutex};
+
+use bevy::prelude::*;
+
+// Seconds that only pass when told to. Clones share the same time, so every app of a test and the
+// network between them can be stepped together.
+#[derive(Debug, Clone, Default)]
+pub struct ManualClock(Arc<Mutex<f64>>);
+
+impl ManualClock {
+    pub fn seconds(&self) -> f64 {
+        *self.0.lock().unwrap()
+    }
+
+    #[cfg(test)]
+    pub fn advance(&self, seconds: f64) {
+        *self.0.lock().unwrap() += seconds;
+    }
+}
+
+// The time systems run on. Follows bevy's `Time`, which can't be set by hand, unless it is built
+// from a `ManualClock`.
+#[derive(Debug, Default)]
+pub struct GameTime {
+    manual_clock: Option<ManualClock>,
+    seconds_since_startup: f64,
+    delta_seconds: f64,
+}
+
+impl GameTime {
+    #[cfg(test)]
+    pub fn from_manual_clock(manual_clock: ManualClock) -> Self {
+        Self {
+            seconds_since_startup: manual_clock.seconds(),
+            manual_clock: Some(manual_clock),
+            delta_seconds: 0.0,
+        }
+    }
+
+    pub fn seconds_since_startup(&self) -> f64 {
+        self.seconds_since_startup
+    }
+
+    pub fn delta_seconds_f64(&self) -> f64 {
+        self.delta_seconds
+    }
+
+    pub fn delta_seconds(&self) -> f32 {
+        self.delta_seconds as f32
+    }
+}
+
+pub fn game_time_update(time: Res<Time>, mut game_time: ResMut<GameTime>) {
+    match game_time.manual_clock.as_ref().map(ManualClock::seconds) {
+        Some(seconds) => {
+            game_time.delta_seconds = seconds - game_time.seconds_since_startup;
+            game_time.seconds_since_startup = seconds;
+        }
+        None => {
+            game_time.delta_seconds = time.delta_seconds_f64();
+            game_time.seconds_since_startup = time.seconds_since_startup();
+        }
+    }
+}
+
+// Add after `CorePlugin`. A `GameTime` inserted before is kept, which is how tests take over.
+#[derive(Debug, Default)]
+pub struct GameTimePlugin;
+
+impl Plugin for GameTimePlugin {
+    fn build(&self, app_builder: &mut AppBuilder) {
+        app_builder
+            .init_resource::<GameTime>()
+            .add_system_to_stage(CoreStage::First, game_time_update.system());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn follows_the_manual_clock() {
+        let manual_clock = ManualClock::default();
+        manual_clock.advance(1.0);
+
+        let mut world = World::default();
+        world.insert_resource(Time::default());
+        world.insert_resource(GameTime::from_manual_clock(manual_clock.clone()));
+
+        let mut stage = SystemStage::single_threaded().with_system(game_time_update.system());
+        manual_clock.advance(0.25);
+        stage.run(&mut world);
+
+        let game_time = world.get_resource::<GameTime>().unwrap();
+        assert_eq!(game_time.seconds_since_startup(), 1.25);
+        assert_eq!(game_time.delta_seconds_f64(), 0.25);
+    }
+}
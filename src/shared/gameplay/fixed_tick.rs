@@ -0,0 +1,256 @@
+use bevy::{ecs::schedule::ShouldRun, prelude::*, transform::TransformSystem};
+use bevy_rapier3d::{
+    physics::{step_world_system, RapierConfiguration, RigidBodyHandleComponent},
+    rapier::dynamics::{IntegrationParameters, RigidBodySet},
+};
+
+use crate::shared::game_time::GameTime;
+
+pub const TICK_RATE: f64 = 60.0;
+
+// Runs after `CoreStage::Update` so it sees the input sampled this frame
+pub const FIXED_TICK_STAGE: &str = "fixed_tick";
+
+// Number of the simulation tick being run
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct GameTick(pub u32);
+
+// Accumulates frame time and hands it out in fixed steps
+#[derive(Debug, Clone)]
+pub struct FixedTickClock {
+    // Seconds simulated per tick
+    pub step: f64,
+    // How fast simulated time passes compared to real time
+    pub time_scale: f64,
+    // A slow frame is followed by at most this many ticks, the rest of the time is dropped
+    pub max_ticks_per_frame: u32,
+    accumulator: f64,
+    ticking: bool,
+}
+
+impl Default for FixedTickClock {
+    fn default() -> Self {
+        Self::new(1.0 / TICK_RATE)
+    }
+}
+
+impl FixedTickClock {
+    pub fn new(step: f64) -> Self {
+        Self {
+            step,
+            time_scale: 1.0,
+            max_ticks_per_frame: 8,
+            accumulator: 0.0,
+            ticking: false,
+        }
+    }
+
+    // How far rendering is between the last tick and the next one, from 0 to 1
+    pub fn overstep(&self) -> f32 {
+        (self.accumulator / self.step).min(1.0) as f32
+    }
+
+    // Called repeatedly each frame, returns true as long as another tick is due
+    pub fn poll(&mut self, delta: f64) -> bool {
+        if !self.ticking {
+            let max_accumulated = self.step * self.max_ticks_per_frame as f64;
+            self.accumulator = (self.accumulator + delta * self.time_scale).min(max_accumulated);
+        }
+
+        self.ticking = self.accumulator >= self.step;
+
+        if self.ticking {
+            self.accumulator -= self.step;
+        }
+
+        self.ticking
+    }
+}
+
+// Add after `RapierPhysicsPlugin`, it takes over stepping physics. Gameplay systems go in
+// `FIXED_TICK_STAGE` before "resume_physics".
+#[derive(Default)]
+pub struct FixedTickPlugin;
+
+impl Plugin for FixedTickPlugin {
+    fn build(&self, app_builder: &mut AppBuilder) {
+        let clock = FixedTickClock::default();
+
+        app_builder
+            // Every tick steps physics exactly once by the tick length, however long frames take
+            .insert_resource(RapierConfiguration {
+                physics_pipeline_active: false,
+                time_dependent_number_of_timesteps: false,
+                ..Default::default()
+            })
+            .insert_resource(IntegrationParameters {
+                dt: clock.step as f32,
+                ..Default::default()
+            })
+            .insert_resource(clock)
+            .insert_resource(GameTick::default())
+            .add_stage_after(
+                CoreStage::Update,
+                FIXED_TICK_STAGE,
+                SystemStage::single_threaded()
+                    .with_run_criteria(fixed_tick_run_criteria.system())
+                    .with_system(fixed_tick_resume_physics.system().label("resume_physics"))
+                    .with_system(
+                        step_world_system
+                            .system()
+                            .label("step_physics")
+                            .after("resume_physics"),
+                    )
+                    .with_system(
+                        fixed_tick_pause_physics
+                            .system()
+                            .label("pause_physics")
+                            .after("step_physics"),
+                    )
+                    .with_system(fixed_tick_record_poses.system().after("pause_physics")),
+            )
+            .add_system_to_stage(
+                CoreStage::PostUpdate,
+                fixed_tick_interpolate_transforms
+                    .system()
+                    .before(TransformSystem::TransformPropagate),
+            );
+    }
+}
+
+pub fn fixed_tick_run_criteria(
+    time: Res<GameTime>,
+    mut clock: ResMut<FixedTickClock>,
+    mut tick: ResMut<GameTick>,
+) -> ShouldRun {
+    if clock.poll(time.delta_seconds_f64()) {
+        tick.0 = tick.0.wrapping_add(1);
+        ShouldRun::YesAndCheckAgain
+    } else {
+        ShouldRun::No
+    }
+}
+
+// Rapier steps once per frame on its own, it is kept paused outside of the fixed stage and only
+// let through for the step taken on each tick
+pub fn fixed_tick_resume_physics(mut configuration: ResMut<RapierConfiguration>) {
+    configuration.physics_pipeline_active = true;
+}
+
+pub fn fixed_tick_pause_physics(mut configuration: ResMut<RapierConfiguration>) {
+    configuration.physics_pipeline_active = false;
+}
+
+// Body poses of the last two ticks, rendered in between them
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TickInterpolated {
+    previous: Option<(Vec3, Quat)>,
+    current: Option<(Vec3, Quat)>,
+}
+
+impl TickInterpolated {
+    pub fn push(&mut self, translation: Vec3, rotation: Quat) {
+        self.previous = self.current.or(Some((translation, rotation)));
+        self.current = Some((translation, rotation));
+    }
+
+    pub fn sample(&self, overstep: f32) -> Option<(Vec3, Quat)> {
+        let (previous, current) = (self.previous?, self.current?);
+
+        Some((
+            previous.0.lerp(current.0, overstep),
+            previous.1.slerp(current.1, overstep),
+        ))
+    }
+}
+
+pub fn fixed_tick_record_poses(
+    rigid_bodies: Res<RigidBodySet>,
+    mut query: Query<(&RigidBodyHandleComponent, &mut TickInterpolated)>,
+) {
+    for (rigid_body_handle, mut interpolated) in query.iter_mut() {
+        if let Some(rigid_body) = rigid_bodies.get(rigid_body_handle.handle()) {
+            let position = rigid_body.position();
+            let translation = position.translation.vector;
+            let rotation = position.rotation;
+
+            interpolated.push(
+                Vec3::new(translation.x, translation.y, translation.z),
+                Quat::from_xyzw(rotation.i, rotation.j, rotation.k, rotation.w),
+            );
+        }
+    }
+}
+
+// Overrides the pose rapier copied over from the body, which is always the latest tick
+pub fn fixed_tick_interpolate_transforms(
+    clock: Res<FixedTickClock>,
+    mut query: Query<(&TickInterpolated, &mut Transform)>,
+) {
+    let overstep = clock.overstep();
+
+    for (interpolated, mut transform) in query.iter_mut() {
+        if let Some((translation, rotation)) = interpolated.sample(overstep) {
+            transform.translation = translation;
+            transform.rotation = rotation;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ticks_for(clock: &mut FixedTickClock, delta: f64) -> u32 {
+        let mut ticks = 0;
+
+        while clock.poll(delta) {
+            ticks += 1;
+        }
+
+        ticks
+    }
+
+    // Steps and deltas are powers of two so the accumulator stays exact
+    #[test]
+    fn hands_out_fixed_steps() {
+        let mut clock = FixedTickClock::new(0.125);
+
+        assert_eq!(ticks_for(&mut clock, 0.3125), 2);
+        assert_eq!(clock.overstep(), 0.5);
+
+        assert_eq!(ticks_for(&mut clock, 0.03125), 0);
+        assert_eq!(ticks_for(&mut clock, 0.03125), 1);
+        assert_eq!(clock.overstep(), 0.0);
+    }
+
+    #[test]
+    fn drops_time_after_long_frames() {
+        let mut clock = FixedTickClock::new(0.125);
+        clock.max_ticks_per_frame = 4;
+
+        assert_eq!(ticks_for(&mut clock, 10.0), 4);
+        assert_eq!(ticks_for(&mut clock, 0.0), 0);
+    }
+
+    #[test]
+    fn time_scale_dilates_ticks() {
+        let mut clock = FixedTickClock::new(0.125);
+        clock.time_scale = 1.5;
+
+        assert_eq!(ticks_for(&mut clock, 0.25), 3);
+    }
+
+    #[test]
+    fn interpolates_between_ticks() {
+        let mut interpolated = TickInterpolated::default();
+        assert_eq!(interpolated.sample(0.5), None);
+
+        interpolated.push(Vec3::ZERO, Quat::IDENTITY);
+        assert_eq!(interpolated.sample(0.5), Some((Vec3::ZERO, Quat::IDENTITY)));
+
+        interpolated.push(Vec3::new(2.0, 0.0, 0.0), Quat::IDENTITY);
+        let (translation, _) = interpolated.sample(0.25).unwrap();
+        assert!((translation - Vec3::new(0.5, 0.0, 0.0)).length() < 1e-6);
+    }
+}
@@ -1,20 +1,23 @@
-use core::panic;
-use std::{convert::TryInto, error::Error};
+use crate::shared::{game_message::ClientId, replication::Replicated};
 use bevy::{prelude::*, scene::InstanceId};
 use bevy_rapier3d::{
     physics::RapierPhysicsPlugin,
     rapier::{dynamics::RigidBodyBuilder, geometry::ColliderBuilder},
-    render::RapierRenderPlugin,
 };
+use core::panic;
 use nalgebra::Point3;
-use crate::shared::gameplay::player_input::PlayerInput;
+use std::{convert::TryInto, error::Error};
 
+mod fixed_tick;
 mod player_input;
 mod player_movement;
-mod player_shooting;
+pub use fixed_tick::{
+    FixedTickClock, FixedTickPlugin, GameTick, TickInterpolated, FIXED_TICK_STAGE,
+};
 use player_input::player_local_input;
+pub use player_input::{LocalPlayer, PlayerInput};
 use player_movement::player_movement;
-use player_shooting::player_shooting;
+pub use player_movement::{player_movement_velocity, player_turn_rate};
 
 #[derive(Default)]
 pub struct GameplayPlugin {}
@@ -24,12 +27,18 @@ impl Plugin for GameplayPlugin {
         app_builder
             .add_plugin(RapierPhysicsPlugin)
             // .add_plugin(RapierRenderPlugin)
+            .add_plugin(FixedTickPlugin)
             .add_startup_system(setup.system())
             .insert_resource(SceneInstance::default())
             .add_system(player_local_input.system())
-            .add_system(player_movement.system())
-            .add_system(player_shooting.system())
-            .add_system(scene_update.system());
+            .add_system(scene_update.system())
+            .add_system_to_stage(
+                FIXED_TICK_STAGE,
+                player_movement
+                    .system()
+                    .label("player_movement")
+                    .before("resume_physics"),
+            );
     }
 }
 
@@ -44,31 +53,6 @@ fn setup(
     let test_scene_id = scene_spawner.spawn(asset_server.load("models/test_map.gltf#Scene0"));
     scene_instance.0 = Some(test_scene_id);
 
-    // add entities to the world
-    commands
-        .spawn_bundle(PbrBundle {
-            mesh: meshes.add(Mesh::from(shape::Capsule {
-                depth: 1.75,
-                radius: 0.4,
-                ..Default::default()
-            })),
-            material: materials.add(Color::rgb(1.0, 0.0, 0.0).into()),
-            ..Default::default()
-        })
-        .insert(PlayerInput::default())
-        .insert_bundle((
-            RigidBodyBuilder::new_dynamic()
-                .lock_rotations()
-                .translation(5.0, 20.0, -5.0),
-            ColliderBuilder::capsule_y(1.75, 0.4),
-        ))
-        .with_children(|parent| {
-            parent.spawn_bundle(PerspectiveCameraBundle {
-                transform: Transform::from_xyz(0.0, 0.0, 0.0),
-                ..Default::default()
-            });
-        });
-
     // cube
     commands
         .spawn_bundle(PbrBundle {
@@ -79,7 +63,8 @@ fn setup(
         .insert_bundle((
             RigidBodyBuilder::new_dynamic().translation(5.0, 50.0, -10.0),
             ColliderBuilder::cuboid(0.5, 0.5, 0.5),
-        ));
+        ))
+        .insert_bundle((NetworkProp { prop_id: 0 }, Replicated));
 
     // light
     commands.spawn_bundle(LightBundle {
@@ -90,6 +75,54 @@ fn setup(
     asset_server.watch_for_changes().unwrap();
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NetworkPlayer {
+    pub client_id: ClientId,
+}
+
+// Dynamic bodies placed by the map, ids have to match between server and client
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NetworkProp {
+    pub prop_id: u32,
+}
+
+// Child of a player body, carries the camera of the local player
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PlayerHead;
+
+// Spawns the physics body of a player without any rendering components, returns the body and its
+// head child which `player_movement` pitches.
+pub fn spawn_player_body(commands: &mut Commands, translation: Vec3) -> (Entity, Entity) {
+    let mut head = None;
+
+    let body = commands
+        .spawn_bundle((
+            Transform::from_translation(translation),
+            GlobalTransform::identity(),
+            PlayerInput::default(),
+        ))
+        .insert_bundle((
+            RigidBodyBuilder::new_dynamic()
+                .lock_rotations()
+                .translation(translation.x, translation.y, translation.z),
+            ColliderBuilder::capsule_y(1.75, 0.4),
+        ))
+        .with_children(|parent| {
+            head = Some(
+                parent
+                    .spawn_bundle((
+                        Transform::identity(),
+                        GlobalTransform::identity(),
+                        PlayerHead,
+                    ))
+                    .id(),
+            );
+        })
+        .id();
+
+    (body, head.unwrap())
+}
+
 #[derive(Default)]
 struct SceneInstance(Option<InstanceId>);
 
@@ -160,7 +193,7 @@ fn mesh_extract_positions_and_indices(
             // Try map &[u8] to &[u8; 4]
             .map(|c| c.try_into().unwrap())
             // Map &[u8; 4] to f32
-            .map(|bytes| f32::from_le_bytes(bytes))
+            .map(f32::from_le_bytes)
             .collect();
 
         let positions: Vec<Point3<f32>> = position_floats
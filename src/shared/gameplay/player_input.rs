@@ -1,5 +1,7 @@
 use bevy::{input::mouse::MouseMotion, prelude::*};
 
+use crate::shared::game_message::ClientInputData;
+
 #[derive(Reflect, Debug, Default)]
 #[reflect(Component)]
 pub struct PlayerInput {
@@ -12,8 +14,40 @@ pub struct PlayerInput {
     pub mouse_vertical: f32,
 }
 
+impl From<&ClientInputData> for PlayerInput {
+    fn from(input: &ClientInputData) -> Self {
+        Self {
+            move_left: input.move_left,
+            move_right: input.move_right,
+            move_forward: input.move_forward,
+            move_back: input.move_back,
+            mouse_horizontal: input.mouse_horizontal,
+            mouse_vertical: input.mouse_vertical,
+        }
+    }
+}
+
+impl From<&PlayerInput> for ClientInputData {
+    fn from(input: &PlayerInput) -> Self {
+        Self {
+            sequence: 0,
+            tick: 0,
+            move_forward: input.move_forward,
+            move_left: input.move_left,
+            move_back: input.move_back,
+            move_right: input.move_right,
+            mouse_horizontal: input.mouse_horizontal,
+            mouse_vertical: input.mouse_vertical,
+        }
+    }
+}
+
+// Marks the player controlled by this machine's keyboard and mouse
+#[derive(Debug, Default, Clone, Copy)]
+pub struct LocalPlayer;
+
 pub fn player_local_input(
-    mut query: Query<&mut PlayerInput>,
+    mut query: Query<&mut PlayerInput, With<LocalPlayer>>,
     mut mouse_motion_events: EventReader<MouseMotion>,
     keyboard_input: Res<Input<KeyCode>>,
 ) {
@@ -29,7 +63,8 @@ pub fn player_local_input(
         player_input.move_left = keyboard_input.pressed(KeyCode::A);
         player_input.move_right = keyboard_input.pressed(KeyCode::D);
 
-        player_input.mouse_horizontal = mouse_motion_vector.x;
-        player_input.mouse_vertical = mouse_motion_vector.y;
+        // Frames and ticks don't line up, motion adds up until `player_movement` uses it
+        player_input.mouse_horizontal += mouse_motion_vector.x;
+        player_input.mouse_vertical += mouse_motion_vector.y;
     }
 }
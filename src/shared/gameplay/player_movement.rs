@@ -1,65 +1,98 @@
-use bevy::{core::Time, math::Vec3, prelude::*};
+use bevy::{math::Vec3, prelude::*};
 use bevy_rapier3d::{physics::RigidBodyHandleComponent, rapier::dynamics::RigidBodySet};
 use nalgebra::Vector3;
 
-use crate::shared::gameplay::player_input::PlayerInput;
+use crate::shared::gameplay::{fixed_tick::FixedTickClock, player_input::PlayerInput};
 
-pub fn player_movement(
-    time: Res<Time>,
-    mut rigid_bodies: ResMut<RigidBodySet>,
-    mut transform_query: Query<&mut Transform>,
-    mut player_query: Query<(Entity, &Children, &PlayerInput, &RigidBodyHandleComponent)>,
-) {
-    for (entity, children, input, rigid_body_handle) in player_query.iter_mut() {
-        if let Ok(mut transform) = transform_query.get_mut(entity) {
-            // POSITION
-            let mut input_vector = Vec3::default();
-            let movement_speed = 5.0;
-            let mouse_speed = 1.0;
+const MOVEMENT_SPEED: f32 = 5.0;
+
+// Velocity a player body should have for the given input, vertical velocity is left to physics.
+// Shared by `player_movement` and client-side prediction so both agree on the result.
+pub fn player_movement_velocity(
+    input: &PlayerInput,
+    rotation: Quat,
+    current_velocity: Vec3,
+) -> Vec3 {
+    // POSITION
+    let mut input_vector = Vec3::default();
+
+    if input.move_right {
+        input_vector.x += 1.0;
+    }
+    if input.move_left {
+        input_vector.x -= 1.0;
+    }
+    if input.move_forward {
+        input_vector.y -= 1.0;
+    }
+    if input.move_back {
+        input_vector.y += 1.0;
+    }
 
-            if input.move_right {
-                input_vector.x += 1.0;
-            }
-            if input.move_left {
-                input_vector.x -= 1.0;
-            }
-            if input.move_forward {
-                input_vector.y -= 1.0;
-            }
-            if input.move_back {
-                input_vector.y += 1.0;
-            }
+    if input_vector.length() > 0.0 {
+        input_vector = input_vector.normalize();
+    }
 
-            if input_vector.length() > 0.0 {
-                input_vector.normalize();
-            }
+    input_vector *= MOVEMENT_SPEED;
 
-            input_vector *= movement_speed;
+    let forward_direction = rotation * Vec3::Z;
+    let strafing_direction = rotation * Vec3::X;
+    let movement_vector = forward_direction * input_vector.y + strafing_direction * input_vector.x;
 
-            let forward_direction = transform.rotation * Vec3::Z;
-            let strafing_direction = transform.rotation * Vec3::X;
-            let movement_vector =
-                forward_direction * input_vector.y + strafing_direction * input_vector.x;
+    Vec3::new(
+        movement_vector.x,
+        current_velocity.y + movement_vector.y,
+        movement_vector.z,
+    )
+}
 
+// Angular velocity around the Y axis
+pub fn player_turn_rate(input: &PlayerInput) -> f32 {
+    input.mouse_horizontal
+}
+
+// Runs once per tick in the fixed tick stage
+pub fn player_movement(
+    clock: Res<FixedTickClock>,
+    mut rigid_bodies: ResMut<RigidBodySet>,
+    mut transform_query: Query<&mut Transform>,
+    mut player_query: Query<(
+        Entity,
+        &Children,
+        &mut PlayerInput,
+        &RigidBodyHandleComponent,
+    )>,
+) {
+    for (entity, children, mut input, rigid_body_handle) in player_query.iter_mut() {
+        if let Ok(transform) = transform_query.get_mut(entity) {
             let test = rigid_bodies.get_mut(rigid_body_handle.handle()).unwrap();
             let current_velocity: &Vector3<f32> = test.linvel();
 
+            let velocity = player_movement_velocity(
+                &input,
+                transform.rotation,
+                Vec3::new(current_velocity.x, current_velocity.y, current_velocity.z),
+            );
+
             test.set_linvel(
-                Vector3::<f32>::new(
-                    movement_vector.x,
-                    current_velocity.y + movement_vector.y,
-                    movement_vector.z,
-                ),
+                Vector3::<f32>::new(velocity.x, velocity.y, velocity.z),
                 true,
             );
 
-            test.set_angvel(Vector3::<f32>::new(0.0, input.mouse_horizontal, 0.0), true);
+            test.set_angvel(
+                Vector3::<f32>::new(0.0, player_turn_rate(&input), 0.0),
+                true,
+            );
         }
 
         if let Ok(mut transform) = transform_query.get_mut(children[0]) {
             transform.rotate(Quat::from_rotation_x(
-                input.mouse_vertical * time.delta_seconds(),
+                input.mouse_vertical * clock.step as f32,
             ));
         }
+
+        // Mouse motion is turned into rotation once, the next tick starts from nothing
+        input.mouse_horizontal = 0.0;
+        input.mouse_vertical = 0.0;
     }
 }
@@ -1,24 +1,37 @@
 use bevy::{
     asset::AssetPlugin, core::CorePlugin, diagnostic::DiagnosticsPlugin, gltf::GltfPlugin,
-    input::InputPlugin, log::LogPlugin, prelude::PluginGroup,
-    scene::ScenePlugin, transform::TransformPlugin,
+    input::InputPlugin, log::LogPlugin, prelude::PluginGroup, scene::ScenePlugin,
+    transform::TransformPlugin,
 };
 
+pub mod bit_packing;
+pub mod channel;
+pub mod fragmentation;
+pub mod game_message;
+pub mod game_time;
 pub mod gameplay;
+pub mod network_conditions;
+pub mod network_diagnostics;
+pub mod network_events;
+pub mod replication;
+pub mod snapshot_delta;
+pub mod transport;
+use game_time::GameTimePlugin;
 use gameplay::GameplayPlugin;
 
 pub struct SharedPlugins;
 impl PluginGroup for SharedPlugins {
     fn build(&mut self, group: &mut bevy::app::PluginGroupBuilder) {
-        group.add(LogPlugin::default());
-        group.add(CorePlugin::default());
-        group.add(TransformPlugin::default());
-        group.add(DiagnosticsPlugin::default());
-        group.add(InputPlugin::default());
-        group.add(AssetPlugin::default());
-        group.add(ScenePlugin::default());
+        group.add(LogPlugin);
+        group.add(CorePlugin);
+        group.add(GameTimePlugin);
+        group.add(TransformPlugin);
+        group.add(DiagnosticsPlugin);
+        group.add(InputPlugin);
+        group.add(AssetPlugin);
+        group.add(ScenePlugin);
 
-        group.add(GltfPlugin::default());
+        group.add(GltfPlugin);
 
         group.add(GameplayPlugin::default());
     }
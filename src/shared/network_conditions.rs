@@ -0,0 +1,580 @@
+use std::{
+    env,
+    error::Error,
+    fmt, io,
+    net::SocketAddr,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use bevy::prelude::warn;
+
+use crate::shared::{game_time::ManualClock, transport::Transport};
+
+// Holds a config string like "latency=0.1,jitter=0.02,loss=0.05,seed=7"
+pub const NETWORK_CONDITIONS_VARIABLE: &str = "RADWARS_NETWORK_CONDITIONS";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct NetworkConditions {
+    // Seconds every outgoing datagram is held back
+    pub latency: f64,
+    // Up to this many seconds more or less than `latency`
+    pub jitter: f64,
+    // Chances between 0 and 1, rolled for every outgoing datagram
+    pub packet_loss: f64,
+    pub duplication: f64,
+    pub reordering: f64,
+    // Seconds a reordered datagram waits on top of its latency
+    pub reorder_delay: f64,
+    // Only read when a transport gets wrapped
+    pub seed: u64,
+}
+
+impl Default for NetworkConditions {
+    fn default() -> Self {
+        Self {
+            latency: 0.0,
+            jitter: 0.0,
+            packet_loss: 0.0,
+            duplication: 0.0,
+            reordering: 0.0,
+            reorder_delay: 0.05,
+            seed: 0x5eed,
+        }
+    }
+}
+
+// Settings come from the command line, the builder is for setting up conditions in code
+#[allow(dead_code)]
+impl NetworkConditions {
+    pub fn with_latency(mut self, latency: f64) -> Self {
+        self.latency = latency;
+        self
+    }
+
+    pub fn with_jitter(mut self, jitter: f64) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    pub fn with_packet_loss(mut self, packet_loss: f64) -> Self {
+        self.packet_loss = packet_loss;
+        self
+    }
+
+    pub fn with_duplication(mut self, duplication: f64) -> Self {
+        self.duplication = duplication;
+        self
+    }
+
+    pub fn with_reordering(mut self, reordering: f64) -> Self {
+        self.reordering = reordering;
+        self
+    }
+
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    pub fn is_perfect(&self) -> bool {
+        self.latency <= 0.0
+            && self.jitter <= 0.0
+            && self.packet_loss <= 0.0
+            && self.duplication <= 0.0
+            && self.reordering <= 0.0
+    }
+}
+
+impl FromStr for NetworkConditions {
+    type Err = NetworkConditionsError;
+
+    fn from_str(config: &str) -> Result<Self, Self::Err> {
+        let mut conditions = NetworkConditions::default();
+
+        for entry in config
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+        {
+            let (key, value) = match entry.split_once('=') {
+                Some((key, value)) => (key.trim(), value.trim()),
+                None => return Err(NetworkConditionsError::MissingValue(entry.to_string())),
+            };
+
+            let invalid_value = || NetworkConditionsError::InvalidValue {
+                key: key.to_string(),
+                value: value.to_string(),
+            };
+
+            if key == "seed" {
+                conditions.seed = value.parse().map_err(|_| invalid_value())?;
+                continue;
+            }
+
+            let number = value
+                .parse::<f64>()
+                .ok()
+                .filter(|number| number.is_finite() && *number >= 0.0)
+                .ok_or_else(invalid_value)?;
+
+            match key {
+                "latency" => conditions.latency = number,
+                "jitter" => conditions.jitter = number,
+                "reorder_delay" => conditions.reorder_delay = number,
+                "loss" | "duplication" | "reordering" => {
+                    if number > 1.0 {
+                        return Err(invalid_value());
+                    }
+
+                    match key {
+                        "loss" => conditions.packet_loss = number,
+                        "duplication" => conditions.duplication = number,
+                        _ => conditions.reordering = number,
+                    }
+                }
+                _ => return Err(NetworkConditionsError::UnknownKey(key.to_string())),
+            }
+        }
+
+        Ok(conditions)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NetworkConditionsError {
+    MissingValue(String),
+    UnknownKey(String),
+    InvalidValue { key: String, value: String },
+}
+
+impl fmt::Display for NetworkConditionsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NetworkConditionsError::MissingValue(entry) => {
+                write!(f, "expected key=value, got \"{}\"", entry)
+            }
+            NetworkConditionsError::UnknownKey(key) => write!(f, "unknown key \"{}\"", key),
+            NetworkConditionsError::InvalidValue { key, value } => {
+                write!(f, "invalid value \"{}\" for {}", value, key)
+            }
+        }
+    }
+}
+
+impl Error for NetworkConditionsError {}
+
+// Small and reproducible, quality doesn't matter for rolling packet fates
+#[derive(Debug, Clone)]
+pub struct XorShiftRng {
+    state: u64,
+}
+
+impl XorShiftRng {
+    pub fn new(seed: u64) -> Self {
+        // A zero state would only ever produce zeros
+        let state = seed ^ 0x9e37_79b9_7f4a_7c15;
+
+        Self {
+            state: if state == 0 {
+                0x9e37_79b9_7f4a_7c15
+            } else {
+                state
+            },
+        }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state
+    }
+
+    // Uniform in [0, 1)
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    pub fn chance(&mut self, probability: f64) -> bool {
+        probability > 0.0 && self.next_f64() < probability
+    }
+}
+
+// Shared handle to the conditions, every transport wrapped with it picks up changes right away
+#[derive(Debug, Clone)]
+pub struct NetworkConditioner {
+    conditions: Arc<Mutex<NetworkConditions>>,
+    created_at: Instant,
+    // Delays are measured on the wall clock unless time is stepped by hand
+    manual_clock: Option<ManualClock>,
+}
+
+impl Default for NetworkConditioner {
+    fn default() -> Self {
+        Self::new(NetworkConditions::default())
+    }
+}
+
+impl NetworkConditioner {
+    pub fn new(conditions: NetworkConditions) -> Self {
+        Self {
+            conditions: Arc::new(Mutex::new(conditions)),
+            created_at: Instant::now(),
+            manual_clock: None,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn with_manual_clock(mut self, manual_clock: ManualClock) -> Self {
+        self.manual_clock = Some(manual_clock);
+        self
+    }
+
+    // Falls back to a perfect network when the variable is unset or malformed
+    pub fn from_env() -> Self {
+        let conditions = match env::var(NETWORK_CONDITIONS_VARIABLE) {
+            Ok(config) => config.parse().unwrap_or_else(|error| {
+                warn!("Ignoring {}: {}", NETWORK_CONDITIONS_VARIABLE, error);
+                NetworkConditions::default()
+            }),
+            Err(_) => NetworkConditions::default(),
+        };
+
+        Self::new(conditions)
+    }
+
+    pub fn conditions(&self) -> NetworkConditions {
+        *self.conditions.lock().unwrap()
+    }
+
+    #[cfg(test)]
+    pub fn set_conditions(&self, conditions: NetworkConditions) {
+        *self.conditions.lock().unwrap() = conditions;
+    }
+
+    fn seconds(&self) -> f64 {
+        match &self.manual_clock {
+            Some(manual_clock) => manual_clock.seconds(),
+            None => self.created_at.elapsed().as_secs_f64(),
+        }
+    }
+}
+
+// Mixed into the seed so the two ends of a link don't roll the same fates. Ports would do too,
+// but clients get theirs from the OS and runs wouldn't repeat.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkEnd {
+    Server,
+    Client,
+}
+
+struct DelayedDatagram {
+    deliver_at: f64,
+    // Keeps datagrams due at the same instant in send order
+    order: u64,
+    address: SocketAddr,
+    bytes: Vec<u8>,
+}
+
+struct ConditionerState {
+    rng: XorShiftRng,
+    delayed: Vec<DelayedDatagram>,
+    next_order: u64,
+}
+
+// Applies the conditioner to everything sent through `inner`. Delayed datagrams go out whenever
+// the transport is used again, which the client and server do every frame.
+pub struct ConditionedTransport {
+    inner: Box<dyn Transport>,
+    conditioner: NetworkConditioner,
+    state: Mutex<ConditionerState>,
+}
+
+impl ConditionedTransport {
+    pub fn new(
+        inner: Box<dyn Transport>,
+        conditioner: NetworkConditioner,
+        link_end: LinkEnd,
+    ) -> Self {
+        let seed = conditioner.conditions().seed ^ ((link_end as u64) << 32);
+
+        Self {
+            inner,
+            conditioner,
+            state: Mutex::new(ConditionerState {
+                rng: XorShiftRng::new(seed),
+                delayed: Vec::new(),
+                next_order: 0,
+            }),
+        }
+    }
+
+    // Datagrams still held back
+    #[cfg(test)]
+    pub fn pending(&self) -> usize {
+        self.state.lock().unwrap().delayed.len()
+    }
+
+    pub fn flush(&self) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        self.send_due(&mut state, self.conditioner.seconds())
+    }
+
+    fn send_due(&self, state: &mut ConditionerState, now: f64) -> io::Result<()> {
+        let (mut due, waiting) = state
+            .delayed
+            .drain(..)
+            .partition::<Vec<_>, _>(|datagram| datagram.deliver_at <= now);
+        state.delayed = waiting;
+
+        due.sort_by(|a, b| {
+            a.deliver_at
+                .partial_cmp(&b.deliver_at)
+                .unwrap()
+                .then(a.order.cmp(&b.order))
+        });
+
+        for datagram in due {
+            self.inner.send_to(&datagram.bytes, datagram.address)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Transport for ConditionedTransport {
+    fn send_to(&self, bytes: &[u8], address: SocketAddr) -> io::Result<()> {
+        let conditions = self.conditioner.conditions();
+        let mut state = self.state.lock().unwrap();
+        let now = self.conditioner.seconds();
+
+        if conditions.is_perfect() && state.delayed.is_empty() {
+            return self.inner.send_to(bytes, address);
+        }
+
+        if !state.rng.chance(conditions.packet_loss) {
+            let copies = if state.rng.chance(conditions.duplication) {
+                2
+            } else {
+                1
+            };
+
+            for _ in 0..copies {
+                let mut delay =
+                    conditions.latency + conditions.jitter * (state.rng.next_f64() * 2.0 - 1.0);
+
+                if state.rng.chance(conditions.reordering) {
+                    delay += conditions.reorder_delay;
+                }
+
+                let order = state.next_order;
+                state.next_order += 1;
+
+                state.delayed.push(DelayedDatagram {
+                    deliver_at: now + delay.max(0.0),
+                    order,
+                    address,
+                    bytes: bytes.to_vec(),
+                });
+            }
+        }
+
+        self.send_due(&mut state, now)
+    }
+
+    fn receive_from(&self, buffer: &mut [u8]) -> io::Result<Option<(usize, SocketAddr)>> {
+        self.flush()?;
+
+        self.inner.receive_from(buffer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared::transport::LoopbackNetwork;
+
+    fn address(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    fn conditioned_pair(
+        conditions: NetworkConditions,
+        manual_clock: &ManualClock,
+    ) -> (ConditionedTransport, Box<dyn Transport>, NetworkConditioner) {
+        conditioned_pair_from(1, LinkEnd::Client, conditions, manual_clock)
+    }
+
+    fn conditioned_pair_from(
+        port: u16,
+        link_end: LinkEnd,
+        conditions: NetworkConditions,
+        manual_clock: &ManualClock,
+    ) -> (ConditionedTransport, Box<dyn Transport>, NetworkConditioner) {
+        let network = LoopbackNetwork::default();
+        let conditioner =
+            NetworkConditioner::new(conditions).with_manual_clock(manual_clock.clone());
+        let sender = ConditionedTransport::new(
+            Box::new(network.bind(address(port)).unwrap()),
+            conditioner.clone(),
+            link_end,
+        );
+
+        (
+            sender,
+            Box::new(network.bind(address(2)).unwrap()),
+            conditioner,
+        )
+    }
+
+    fn receive_all(transport: &dyn Transport) -> Vec<u8> {
+        let mut buffer = [0; 16];
+        let mut received = Vec::new();
+
+        while let Some((length, _)) = transport.receive_from(&mut buffer).unwrap() {
+            assert_eq!(length, 1);
+            received.push(buffer[0]);
+        }
+
+        received
+    }
+
+    #[test]
+    fn parses_config() {
+        let conditions =
+            "latency=0.1, jitter=0.02,loss=0.05,duplication=0.01,reordering=0.5,seed=7"
+                .parse::<NetworkConditions>()
+                .unwrap();
+
+        assert_eq!(
+            conditions,
+            NetworkConditions::default()
+                .with_latency(0.1)
+                .with_jitter(0.02)
+                .with_packet_loss(0.05)
+                .with_duplication(0.01)
+                .with_reordering(0.5)
+                .with_seed(7)
+        );
+        assert!("".parse::<NetworkConditions>().unwrap().is_perfect());
+
+        assert_eq!(
+            "latency".parse::<NetworkConditions>(),
+            Err(NetworkConditionsError::MissingValue("latency".to_string()))
+        );
+        assert_eq!(
+            "bandwidth=1".parse::<NetworkConditions>(),
+            Err(NetworkConditionsError::UnknownKey("bandwidth".to_string()))
+        );
+        assert!(matches!(
+            "loss=1.5".parse::<NetworkConditions>(),
+            Err(NetworkConditionsError::InvalidValue { .. })
+        ));
+        assert!(matches!(
+            "latency=-1".parse::<NetworkConditions>(),
+            Err(NetworkConditionsError::InvalidValue { .. })
+        ));
+    }
+
+    #[test]
+    fn same_seed_loses_same_datagrams() {
+        let run = || {
+            let (sender, receiver, _) = conditioned_pair(
+                NetworkConditions::default().with_packet_loss(0.5),
+                &ManualClock::default(),
+            );
+
+            for i in 0..100 {
+                sender.send_to(&[i], address(2)).unwrap();
+            }
+
+            receive_all(receiver.as_ref())
+        };
+
+        let received = run();
+
+        assert_eq!(received, run());
+        assert!(received.len() > 30 && received.len() < 70);
+        assert!(received.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[test]
+    fn fates_depend_on_the_link_end_not_the_port() {
+        let run = |port, link_end| {
+            let (sender, receiver, _) = conditioned_pair_from(
+                port,
+                link_end,
+                NetworkConditions::default().with_packet_loss(0.5),
+                &ManualClock::default(),
+            );
+
+            for i in 0..100 {
+                sender.send_to(&[i], address(2)).unwrap();
+            }
+
+            receive_all(receiver.as_ref())
+        };
+
+        assert_eq!(run(1, LinkEnd::Client), run(54321, LinkEnd::Client));
+        assert_ne!(run(1, LinkEnd::Client), run(1, LinkEnd::Server));
+    }
+
+    #[test]
+    fn duplicates_datagrams() {
+        let (sender, receiver, _) = conditioned_pair(
+            NetworkConditions::default().with_duplication(1.0),
+            &ManualClock::default(),
+        );
+
+        sender.send_to(&[1], address(2)).unwrap();
+        sender.send_to(&[2], address(2)).unwrap();
+
+        assert_eq!(receive_all(receiver.as_ref()), vec![1, 1, 2, 2]);
+    }
+
+    #[test]
+    fn holds_datagrams_back_for_latency() {
+        let manual_clock = ManualClock::default();
+        let (sender, receiver, _) = conditioned_pair(
+            NetworkConditions::default().with_latency(0.05),
+            &manual_clock,
+        );
+
+        sender.send_to(&[1], address(2)).unwrap();
+        assert_eq!(sender.pending(), 1);
+        assert!(receive_all(receiver.as_ref()).is_empty());
+
+        manual_clock.advance(0.04);
+        sender.flush().unwrap();
+        assert_eq!(sender.pending(), 1);
+
+        manual_clock.advance(0.01);
+        sender.flush().unwrap();
+
+        assert_eq!(sender.pending(), 0);
+        assert_eq!(receive_all(receiver.as_ref()), vec![1]);
+    }
+
+    #[test]
+    fn reordered_datagrams_are_overtaken() {
+        let manual_clock = ManualClock::default();
+        let (sender, receiver, conditioner) = conditioned_pair(
+            NetworkConditions::default().with_reordering(1.0),
+            &manual_clock,
+        );
+
+        sender.send_to(&[1], address(2)).unwrap();
+
+        // Conditions apply to the next datagram as soon as they change
+        conditioner.set_conditions(NetworkConditions::default());
+        sender.send_to(&[2], address(2)).unwrap();
+        assert_eq!(receive_all(receiver.as_ref()), vec![2]);
+
+        manual_clock.advance(NetworkConditions::default().reorder_delay);
+        sender.flush().unwrap();
+
+        assert_eq!(receive_all(receiver.as_ref()), vec![1]);
+    }
+}
@@ -0,0 +1,204 @@
+use bevy::diagnostic::{Diagnostic, DiagnosticId, Diagnostics};
+
+use crate::shared::{
+    channel::{ChannelEndpoint, ConnectionStats},
+    game_message::ClientId,
+};
+
+// "RADW" in the top bits, the kind of measurement above the connection index
+const DIAGNOSTIC_ID_BASE: u128 = 0x5241_4457 << 96;
+
+const fn diagnostic_id(kind: u128, index: u128) -> DiagnosticId {
+    DiagnosticId::from_u128(DIAGNOSTIC_ID_BASE | kind << 32 | index)
+}
+
+// Magnitude of the error found whenever the client reconciles with the server, in meters
+pub const PREDICTION_ERROR: DiagnosticId = diagnostic_id(7, 0);
+
+// Seconds between two samples of the traffic counters
+pub const NETWORK_DIAGNOSTICS_INTERVAL: f64 = 0.5;
+
+const HISTORY_LENGTH: usize = 20;
+
+// Diagnostics of one connection. A client has the one to the server, the server one per client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionDiagnostics {
+    pub round_trip_time: DiagnosticId,
+    pub round_trip_jitter: DiagnosticId,
+    pub packet_loss: DiagnosticId,
+    pub bytes_received_per_second: DiagnosticId,
+    pub bytes_sent_per_second: DiagnosticId,
+    pub snapshot_size: DiagnosticId,
+    // Measured by the server only, entities left out of a snapshot to stay within the bandwidth
+    // budget and 1 for every snapshot skipped altogether, 0 for every one sent
+    pub snapshot_deferred: DiagnosticId,
+    pub snapshots_dropped: DiagnosticId,
+}
+
+impl ConnectionDiagnostics {
+    pub const SERVER: ConnectionDiagnostics = ConnectionDiagnostics::with_index(0);
+
+    pub const fn for_client(client_id: ClientId) -> Self {
+        Self::with_index(client_id as u128 + 1)
+    }
+
+    const fn with_index(index: u128) -> Self {
+        Self {
+            round_trip_time: diagnostic_id(1, index),
+            round_trip_jitter: diagnostic_id(2, index),
+            packet_loss: diagnostic_id(3, index),
+            bytes_received_per_second: diagnostic_id(4, index),
+            bytes_sent_per_second: diagnostic_id(5, index),
+            snapshot_size: diagnostic_id(6, index),
+            snapshot_deferred: diagnostic_id(8, index),
+            snapshots_dropped: diagnostic_id(9, index),
+        }
+    }
+
+    // Also used to start over, re-adding a diagnostic drops its history
+    pub fn register(&self, diagnostics: &mut Diagnostics, name: &str) {
+        let diagnostic = |id, measurement: &str| {
+            Diagnostic::new(id, format!("{} {}", name, measurement), HISTORY_LENGTH)
+        };
+
+        diagnostics.add(diagnostic(self.round_trip_time, "rtt").with_suffix("s"));
+        diagnostics.add(diagnostic(self.round_trip_jitter, "rtt jitter").with_suffix("s"));
+        diagnostics.add(diagnostic(self.packet_loss, "packet loss"));
+        diagnostics.add(diagnostic(self.bytes_received_per_second, "bytes in/s"));
+        diagnostics.add(diagnostic(self.bytes_sent_per_second, "bytes out/s"));
+        diagnostics.add(diagnostic(self.snapshot_size, "snapshot bytes"));
+        diagnostics.add(diagnostic(self.snapshot_deferred, "snapshot deferred"));
+        diagnostics.add(diagnostic(self.snapshots_dropped, "snapshots dropped"));
+    }
+
+    pub fn measure(
+        &self,
+        diagnostics: &mut Diagnostics,
+        endpoint: &ChannelEndpoint,
+        sampler: &mut TrafficSampler,
+        now: f64,
+    ) {
+        let sample = match sampler.sample(endpoint.stats(), now) {
+            Some(sample) => sample,
+            None => return,
+        };
+
+        if let Some(round_trip_time) = endpoint.round_trip_time() {
+            diagnostics.add_measurement(self.round_trip_time, round_trip_time);
+            diagnostics.add_measurement(self.round_trip_jitter, endpoint.round_trip_jitter());
+        }
+
+        if let Some(packet_loss) = sample.packet_loss {
+            diagnostics.add_measurement(self.packet_loss, packet_loss);
+        }
+
+        diagnostics.add_measurement(
+            self.bytes_received_per_second,
+            sample.bytes_received_per_second,
+        );
+        diagnostics.add_measurement(self.bytes_sent_per_second, sample.bytes_sent_per_second);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TrafficSample {
+    pub bytes_received_per_second: f64,
+    pub bytes_sent_per_second: f64,
+    // Fraction of the packets resolved since the last sample that were lost, if there were any
+    pub packet_loss: Option<f64>,
+}
+
+// Turns the running totals of a connection into rates
+#[derive(Debug, Clone)]
+pub struct TrafficSampler {
+    pub interval: f64,
+    previous: Option<(ConnectionStats, f64)>,
+}
+
+impl Default for TrafficSampler {
+    fn default() -> Self {
+        Self {
+            interval: NETWORK_DIAGNOSTICS_INTERVAL,
+            previous: None,
+        }
+    }
+}
+
+impl TrafficSampler {
+    // Returns nothing until a whole interval passed since the previous sample
+    pub fn sample(&mut self, stats: ConnectionStats, now: f64) -> Option<TrafficSample> {
+        let (previous, previous_at) = match self.previous {
+            Some(previous) => previous,
+            None => {
+                self.previous = Some((stats, now));
+                return None;
+            }
+        };
+
+        let elapsed = now - previous_at;
+        if elapsed < self.interval {
+            return None;
+        }
+
+        self.previous = Some((stats, now));
+
+        let acked = stats.packets_acked - previous.packets_acked;
+        let lost = stats.packets_lost - previous.packets_lost;
+
+        Some(TrafficSample {
+            bytes_received_per_second: (stats.bytes_received - previous.bytes_received) as f64
+                / elapsed,
+            bytes_sent_per_second: (stats.bytes_sent - previous.bytes_sent) as f64 / elapsed,
+            packet_loss: match acked + lost {
+                0 => None,
+                resolved => Some(lost as f64 / resolved as f64),
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn samples_rates_once_per_interval() {
+        let mut sampler = TrafficSampler::default();
+        let mut stats = ConnectionStats::default();
+
+        assert_eq!(sampler.sample(stats, 1.0), None);
+
+        stats.bytes_sent = 500;
+        stats.bytes_received = 2000;
+        stats.packets_acked = 9;
+        stats.packets_lost = 1;
+        assert_eq!(sampler.sample(stats, 1.25), None);
+
+        assert_eq!(
+            sampler.sample(stats, 1.5),
+            Some(TrafficSample {
+                bytes_received_per_second: 4000.0,
+                bytes_sent_per_second: 1000.0,
+                packet_loss: Some(0.1),
+            })
+        );
+
+        assert_eq!(
+            sampler.sample(stats, 2.0).unwrap().packet_loss,
+            None,
+            "nothing was sent in between"
+        );
+    }
+
+    #[test]
+    fn connections_have_distinct_ids() {
+        let server = ConnectionDiagnostics::SERVER;
+        let first = ConnectionDiagnostics::for_client(1);
+        let last = ConnectionDiagnostics::for_client(ClientId::MAX);
+
+        assert_ne!(server.round_trip_time, first.round_trip_time);
+        assert_ne!(first.round_trip_time, last.round_trip_time);
+        assert_ne!(first.round_trip_time, first.round_trip_jitter);
+        assert_ne!(last.snapshot_size, PREDICTION_ERROR);
+    }
+}
@@ -0,0 +1,365 @@
+// Gameplay declares its events through `NetworkEventAppExt`, none does yet
+#![allow(dead_code)]
+
+use std::{any::TypeId, collections::HashMap};
+
+use bevy::prelude::*;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::shared::game_message::{ClientId, NetworkEventData};
+
+pub trait NetworkEvent: Serialize + DeserializeOwned + Send + Sync + 'static {}
+impl<T: Serialize + DeserializeOwned + Send + Sync + 'static> NetworkEvent for T {}
+
+// Token bucket, `burst` events can be sent at once before the rate kicks in
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    pub per_second: f64,
+    pub burst: u32,
+}
+
+impl RateLimit {
+    pub fn new(per_second: f64, burst: u32) -> Self {
+        Self { per_second, burst }
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct RateLimiter {
+    tokens: f64,
+    updated_at: Option<f64>,
+}
+
+impl RateLimiter {
+    pub fn try_acquire(&mut self, limit: &RateLimit, now: f64) -> bool {
+        let elapsed = self
+            .updated_at
+            .map_or(f64::MAX, |updated_at| now - updated_at);
+        self.tokens = (self.tokens + elapsed.max(0.0) * limit.per_second).min(limit.burst as f64);
+        self.updated_at = Some(now);
+
+        if self.tokens < 1.0 {
+            return false;
+        }
+
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NetworkEventDirection {
+    ToServer(RateLimit),
+    ToClients,
+}
+
+#[derive(Debug, Clone)]
+struct NetworkEventType {
+    type_id: TypeId,
+    name: &'static str,
+    direction: NetworkEventDirection,
+}
+
+// Event types sent over the network. Both sides have to declare the same ones, ideally in shared
+// code.
+#[derive(Debug, Clone, Default)]
+pub struct NetworkEventRegistry {
+    // Sorted by type name so the index, which goes over the wire, doesn't depend on the order
+    // plugins declared them in
+    types: Vec<NetworkEventType>,
+}
+
+impl NetworkEventRegistry {
+    pub fn len(&self) -> usize {
+        self.types.len()
+    }
+
+    pub fn kind_of<T: NetworkEvent>(&self) -> Option<u16> {
+        self.types
+            .iter()
+            .position(|event_type| event_type.type_id == TypeId::of::<T>())
+            .map(|index| index as u16)
+    }
+
+    pub fn direction(&self, kind: u16) -> Option<NetworkEventDirection> {
+        self.types
+            .get(kind as usize)
+            .map(|event_type| event_type.direction)
+    }
+
+    fn register<T: NetworkEvent>(&mut self, direction: NetworkEventDirection) -> bool {
+        if self.kind_of::<T>().is_some() {
+            return false;
+        }
+
+        self.types.push(NetworkEventType {
+            type_id: TypeId::of::<T>(),
+            name: std::any::type_name::<T>(),
+            direction,
+        });
+        self.types.sort_by_key(|event_type| event_type.name);
+
+        true
+    }
+}
+
+// Received by the server, sent by a client as a plain `T`
+#[derive(Debug, Clone, PartialEq)]
+pub struct FromClient<T> {
+    pub client_id: ClientId,
+    pub event: T,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EventRecipients {
+    All,
+    Client(ClientId),
+    AllExcept(ClientId),
+}
+
+impl EventRecipients {
+    pub fn includes(&self, client_id: ClientId) -> bool {
+        match self {
+            EventRecipients::All => true,
+            EventRecipients::Client(recipient) => *recipient == client_id,
+            EventRecipients::AllExcept(excluded) => *excluded != client_id,
+        }
+    }
+}
+
+// Sent by the server, received by clients as a plain `T`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ToClients<T> {
+    pub recipients: EventRecipients,
+    pub event: T,
+}
+
+// Serialized events waiting for the client or server to send them
+#[derive(Debug, Default)]
+pub struct NetworkEventOutbox {
+    pub to_server: Vec<NetworkEventData>,
+    pub to_clients: Vec<(EventRecipients, NetworkEventData)>,
+}
+
+// Received events waiting to be turned back into typed events, the sender is only known on the
+// server
+#[derive(Debug, Default)]
+pub struct NetworkEventInbox {
+    events: HashMap<u16, Vec<(Option<ClientId>, Vec<u8>)>>,
+}
+
+impl NetworkEventInbox {
+    pub fn push(&mut self, sender: Option<ClientId>, event: NetworkEventData) {
+        self.events
+            .entry(event.kind)
+            .or_default()
+            .push((sender, event.bytes));
+    }
+
+    fn take(&mut self, kind: u16) -> Vec<(Option<ClientId>, Vec<u8>)> {
+        self.events.remove(&kind).unwrap_or_default()
+    }
+}
+
+fn serialize_or_warn<T: NetworkEvent>(kind: u16, event: &T) -> Option<NetworkEventData> {
+    match bincode::serialize(event) {
+        Ok(bytes) => Some(NetworkEventData { kind, bytes }),
+        Err(error) => {
+            warn!(
+                "Failed to serialize {}: {}",
+                std::any::type_name::<T>(),
+                error
+            );
+            None
+        }
+    }
+}
+
+fn deserialize_or_warn<T: NetworkEvent>(bytes: &[u8]) -> Option<T> {
+    match bincode::deserialize(bytes) {
+        Ok(event) => Some(event),
+        Err(error) => {
+            warn!(
+                "Discarded malformed {}: {}",
+                std::any::type_name::<T>(),
+                error
+            );
+            None
+        }
+    }
+}
+
+fn network_events_queue_to_server<T: NetworkEvent>(
+    registry: Res<NetworkEventRegistry>,
+    mut outbox: ResMut<NetworkEventOutbox>,
+    mut events: EventReader<T>,
+) {
+    let kind = registry.kind_of::<T>().unwrap();
+
+    for event in events.iter() {
+        if let Some(event) = serialize_or_warn(kind, event) {
+            outbox.to_server.push(event);
+        }
+    }
+}
+
+fn network_events_queue_to_clients<T: NetworkEvent>(
+    registry: Res<NetworkEventRegistry>,
+    mut outbox: ResMut<NetworkEventOutbox>,
+    mut events: EventReader<ToClients<T>>,
+) {
+    let kind = registry.kind_of::<T>().unwrap();
+
+    for ToClients { recipients, event } in events.iter() {
+        if let Some(event) = serialize_or_warn(kind, event) {
+            outbox.to_clients.push((*recipients, event));
+        }
+    }
+}
+
+fn network_events_emit_from_clients<T: NetworkEvent>(
+    registry: Res<NetworkEventRegistry>,
+    mut inbox: ResMut<NetworkEventInbox>,
+    mut events: EventWriter<FromClient<T>>,
+) {
+    let kind = registry.kind_of::<T>().unwrap();
+
+    for (sender, bytes) in inbox.take(kind) {
+        let client_id = match sender {
+            Some(client_id) => client_id,
+            None => continue,
+        };
+
+        if let Some(event) = deserialize_or_warn(&bytes) {
+            events.send(FromClient { client_id, event });
+        }
+    }
+}
+
+fn network_events_emit_from_server<T: NetworkEvent>(
+    registry: Res<NetworkEventRegistry>,
+    mut inbox: ResMut<NetworkEventInbox>,
+    mut events: EventWriter<T>,
+) {
+    let kind = registry.kind_of::<T>().unwrap();
+
+    for (_, bytes) in inbox.take(kind) {
+        if let Some(event) = deserialize_or_warn(&bytes) {
+            events.send(event);
+        }
+    }
+}
+
+// Client events are written as plain `T` on clients and read as `FromClient<T>` on the server,
+// server events are written as `ToClients<T>` on the server and read as plain `T` on clients.
+// Events are queued in `CoreStage::PostUpdate` and sent in `CoreStage::Last`, received ones are
+// put in the inbox by systems labeled "network_events_receive".
+pub trait NetworkEventAppExt {
+    fn add_client_event<T: NetworkEvent>(&mut self, rate_limit: RateLimit) -> &mut Self;
+    fn add_server_event<T: NetworkEvent>(&mut self) -> &mut Self;
+}
+
+fn register_network_event<T: NetworkEvent>(
+    app_builder: &mut AppBuilder,
+    direction: NetworkEventDirection,
+) -> bool {
+    app_builder
+        .init_resource::<NetworkEventOutbox>()
+        .init_resource::<NetworkEventInbox>()
+        .world_mut()
+        .get_resource_or_insert_with(NetworkEventRegistry::default)
+        .register::<T>(direction)
+}
+
+impl NetworkEventAppExt for AppBuilder {
+    fn add_client_event<T: NetworkEvent>(&mut self, rate_limit: RateLimit) -> &mut Self {
+        if !register_network_event::<T>(self, NetworkEventDirection::ToServer(rate_limit)) {
+            return self;
+        }
+
+        self.add_event::<T>()
+            .add_event::<FromClient<T>>()
+            .add_system_to_stage(
+                CoreStage::PreUpdate,
+                network_events_emit_from_clients::<T>
+                    .system()
+                    .after("network_events_receive"),
+            )
+            .add_system_to_stage(
+                CoreStage::PostUpdate,
+                network_events_queue_to_server::<T>.system(),
+            )
+    }
+
+    fn add_server_event<T: NetworkEvent>(&mut self) -> &mut Self {
+        if !register_network_event::<T>(self, NetworkEventDirection::ToClients) {
+            return self;
+        }
+
+        self.add_event::<T>()
+            .add_event::<ToClients<T>>()
+            .add_system_to_stage(
+                CoreStage::PreUpdate,
+                network_events_emit_from_server::<T>
+                    .system()
+                    .after("network_events_receive"),
+            )
+            .add_system_to_stage(
+                CoreStage::PostUpdate,
+                network_events_queue_to_clients::<T>.system(),
+            )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rate_limiter_allows_bursts_then_the_rate() {
+        let limit = RateLimit::new(2.0, 3);
+        let mut limiter = RateLimiter::default();
+
+        let allowed = (0..5).filter(|_| limiter.try_acquire(&limit, 10.0)).count();
+        assert_eq!(allowed, 3);
+
+        // Half a second buys one more
+        assert!(limiter.try_acquire(&limit, 10.5));
+        assert!(!limiter.try_acquire(&limit, 10.5));
+
+        // Tokens never pile up beyond the burst
+        let allowed = (0..5)
+            .filter(|_| limiter.try_acquire(&limit, 100.0))
+            .count();
+        assert_eq!(allowed, 3);
+    }
+
+    #[test]
+    fn recipients() {
+        assert!(EventRecipients::All.includes(4));
+        assert!(EventRecipients::Client(4).includes(4));
+        assert!(!EventRecipients::Client(4).includes(5));
+        assert!(!EventRecipients::AllExcept(4).includes(4));
+        assert!(EventRecipients::AllExcept(4).includes(5));
+    }
+
+    #[test]
+    fn kinds_do_not_depend_on_declaration_order() {
+        let mut registry = NetworkEventRegistry::default();
+        registry.register::<u32>(NetworkEventDirection::ToClients);
+        registry.register::<String>(NetworkEventDirection::ToServer(RateLimit::new(1.0, 1)));
+
+        let mut reversed = NetworkEventRegistry::default();
+        reversed.register::<String>(NetworkEventDirection::ToServer(RateLimit::new(1.0, 1)));
+        reversed.register::<u32>(NetworkEventDirection::ToClients);
+
+        assert_eq!(registry.len(), 2);
+        assert_eq!(registry.kind_of::<u32>(), reversed.kind_of::<u32>());
+        assert_eq!(registry.kind_of::<String>(), reversed.kind_of::<String>());
+        assert_eq!(
+            registry.direction(registry.kind_of::<u32>().unwrap()),
+            Some(NetworkEventDirection::ToClients)
+        );
+        assert!(!registry.register::<u32>(NetworkEventDirection::ToClients));
+    }
+}
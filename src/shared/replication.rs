@@ -0,0 +1,375 @@
+use std::{any::TypeId, error::Error, fmt};
+
+use bevy::{
+    ecs::component::Component,
+    prelude::*,
+    reflect::{GetTypeRegistration, TypeRegistryArc},
+};
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::shared::game_message::{EntityComponentsData, NetworkId, ReplicatedComponentData};
+
+// Marks an entity the server spawns on clients, along with its registered components
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Replicated;
+
+#[derive(Clone)]
+struct ReplicatedComponentType {
+    type_id: TypeId,
+    name: &'static str,
+    serialize: fn(&dyn Reflect) -> Result<Vec<u8>, bincode::Error>,
+    deserialize: fn(&[u8]) -> Result<Box<dyn Reflect>, bincode::Error>,
+}
+
+// Component types replicated from the server to clients. Both sides have to register the same
+// ones, ideally in shared code.
+#[derive(Clone, Default)]
+pub struct ReplicationRegistry {
+    // Sorted by type name so the index, which goes over the wire, doesn't depend on the order
+    // plugins registered them in
+    types: Vec<ReplicatedComponentType>,
+}
+
+impl ReplicationRegistry {
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.types.len()
+    }
+
+    pub fn kind_of<T: Component>(&self) -> Option<u16> {
+        self.types
+            .iter()
+            .position(|component_type| component_type.type_id == TypeId::of::<T>())
+            .map(|index| index as u16)
+    }
+
+    fn register<T>(&mut self)
+    where
+        T: Component + Reflect + Serialize + DeserializeOwned,
+    {
+        if self.kind_of::<T>().is_some() {
+            return;
+        }
+
+        self.types.push(ReplicatedComponentType {
+            type_id: TypeId::of::<T>(),
+            name: std::any::type_name::<T>(),
+            serialize: |value| {
+                bincode::serialize(
+                    value
+                        .downcast_ref::<T>()
+                        .expect("component of another type"),
+                )
+            },
+            deserialize: |bytes| Ok(Box::new(bincode::deserialize::<T>(bytes)?)),
+        });
+        self.types.sort_by_key(|component_type| component_type.name);
+    }
+}
+
+// Components have to derive `Reflect` with `#[reflect(Component)]`, which needs `Default`
+#[allow(dead_code)]
+pub trait ReplicationAppExt {
+    fn replicate<T>(&mut self) -> &mut Self
+    where
+        T: Component + Reflect + GetTypeRegistration + Serialize + DeserializeOwned;
+}
+
+impl ReplicationAppExt for AppBuilder {
+    fn replicate<T>(&mut self) -> &mut Self
+    where
+        T: Component + Reflect + GetTypeRegistration + Serialize + DeserializeOwned,
+    {
+        register_replicated::<T>(self.world_mut());
+        self
+    }
+}
+
+pub fn register_replicated<T>(world: &mut World)
+where
+    T: Component + Reflect + GetTypeRegistration + Serialize + DeserializeOwned,
+{
+    {
+        let type_registry = world.get_resource_or_insert_with(TypeRegistryArc::default);
+        let mut type_registry = type_registry.write();
+        type_registry.register::<T>();
+
+        if type_registry
+            .get_type_data::<ReflectComponent>(TypeId::of::<T>())
+            .is_none()
+        {
+            panic!(
+                "{} has to be reflected with #[reflect(Component)] to be replicated",
+                std::any::type_name::<T>()
+            );
+        }
+    }
+
+    world
+        .get_resource_or_insert_with(ReplicationRegistry::default)
+        .register::<T>();
+}
+
+// Serializes the registered components of every replicated entity. With `changed_only` only
+// those changed since the calling exclusive system last ran are included.
+pub fn collect_components(
+    world: &mut World,
+    changed_only: bool,
+) -> Result<Vec<EntityComponentsData>, ReplicationError> {
+    let type_registry = world.get_resource::<TypeRegistryArc>().unwrap().clone();
+    let type_registry = type_registry.read();
+    let registry = match world.get_resource::<ReplicationRegistry>() {
+        Some(registry) => registry.clone(),
+        None => return Ok(Vec::new()),
+    };
+
+    // Entities the server hasn't given an id yet are skipped
+    let entities = world
+        .query_filtered::<(Entity, &NetworkId), With<Replicated>>()
+        .iter(world)
+        .map(|(entity, network_id)| (entity, *network_id))
+        .collect::<Vec<_>>();
+
+    let mut collected = Vec::new();
+
+    for (entity, network_id) in entities {
+        let mut components = Vec::new();
+
+        for (kind, component_type) in registry.types.iter().enumerate() {
+            let reflect_component = type_registry
+                .get_type_data::<ReflectComponent>(component_type.type_id)
+                .ok_or(ReplicationError::NotReflected(component_type.name))?;
+
+            let value = match reflect_component.reflect_component_mut(world, entity) {
+                Some(value) => value,
+                None => continue,
+            };
+
+            if changed_only && !value.is_changed() {
+                continue;
+            }
+
+            components.push(ReplicatedComponentData {
+                kind: kind as u16,
+                bytes: (component_type.serialize)(&*value).map_err(ReplicationError::Malformed)?,
+            });
+        }
+
+        if !components.is_empty() {
+            collected.push(EntityComponentsData {
+                network_id,
+                components,
+            });
+        }
+    }
+
+    Ok(collected)
+}
+
+// Inserts the components the entity doesn't have yet and overwrites the others
+pub fn apply_components(
+    world: &mut World,
+    entity: Entity,
+    components: &[ReplicatedComponentData],
+) -> Result<(), ReplicationError> {
+    let type_registry = world.get_resource::<TypeRegistryArc>().unwrap().clone();
+    let type_registry = type_registry.read();
+    let registry = world
+        .get_resource::<ReplicationRegistry>()
+        .cloned()
+        .unwrap_or_default();
+
+    for component in components.iter() {
+        let component_type = registry
+            .types
+            .get(component.kind as usize)
+            .ok_or(ReplicationError::UnknownKind(component.kind))?;
+
+        let reflect_component = type_registry
+            .get_type_data::<ReflectComponent>(component_type.type_id)
+            .ok_or(ReplicationError::NotReflected(component_type.name))?;
+
+        let value =
+            (component_type.deserialize)(&component.bytes).map_err(ReplicationError::Malformed)?;
+
+        let exists = match world.get_entity(entity) {
+            Some(entity_ref) => entity_ref.contains_type_id(component_type.type_id),
+            None => return Err(ReplicationError::NoSuchEntity(entity)),
+        };
+
+        if exists {
+            reflect_component.apply_component(world, entity, &*value);
+        } else {
+            reflect_component.add_component(world, entity, &*value);
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Debug)]
+pub enum ReplicationError {
+    UnknownKind(u16),
+    NotReflected(&'static str),
+    NoSuchEntity(Entity),
+    Malformed(bincode::Error),
+}
+
+impl fmt::Display for ReplicationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReplicationError::UnknownKind(kind) => {
+                write!(f, "no replicated component registered as {}", kind)
+            }
+            ReplicationError::NotReflected(name) => {
+                write!(f, "{} is not reflected as a component", name)
+            }
+            ReplicationError::NoSuchEntity(entity) => {
+                write!(f, "entity {:?} doesn't exist", entity)
+            }
+            ReplicationError::Malformed(error) => write!(f, "malformed component: {}", error),
+        }
+    }
+}
+
+impl Error for ReplicationError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ReplicationError::Malformed(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::*;
+
+    #[derive(Reflect, Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+    #[reflect(Component)]
+    struct Health {
+        points: u32,
+    }
+
+    #[derive(Reflect, Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+    #[reflect(Component)]
+    struct Ammo {
+        rounds: u16,
+    }
+
+    #[derive(Default)]
+    struct Collected(Vec<EntityComponentsData>);
+
+    fn collect_changes(world: &mut World) {
+        let collected = collect_components(world, true).unwrap();
+        world.get_resource_mut::<Collected>().unwrap().0 = collected;
+    }
+
+    fn world() -> World {
+        let mut world = World::default();
+        register_replicated::<Health>(&mut world);
+        register_replicated::<Ammo>(&mut world);
+        world
+    }
+
+    #[test]
+    fn kinds_do_not_depend_on_registration_order() {
+        let mut reversed = World::default();
+        register_replicated::<Ammo>(&mut reversed);
+        register_replicated::<Health>(&mut reversed);
+
+        let registry = reversed.get_resource::<ReplicationRegistry>().unwrap();
+        let other_world = world();
+        let other_registry = other_world.get_resource::<ReplicationRegistry>().unwrap();
+
+        assert_eq!(registry.len(), 2);
+        assert_eq!(registry.kind_of::<Ammo>(), other_registry.kind_of::<Ammo>());
+        assert_eq!(
+            registry.kind_of::<Health>(),
+            other_registry.kind_of::<Health>()
+        );
+    }
+
+    #[test]
+    fn collects_only_changed_components() {
+        let mut world = world();
+        world.insert_resource(Collected::default());
+
+        let player = world
+            .spawn()
+            .insert_bundle((
+                Replicated,
+                NetworkId(3),
+                Health { points: 100 },
+                Ammo { rounds: 30 },
+            ))
+            .id();
+        // Not marked as replicated
+        world
+            .spawn()
+            .insert_bundle((NetworkId(4), Health { points: 5 }));
+
+        let mut stage =
+            SystemStage::single_threaded().with_system(collect_changes.exclusive_system());
+
+        stage.run(&mut world);
+        let collected = &world.get_resource::<Collected>().unwrap().0;
+        assert_eq!(collected.len(), 1);
+        assert_eq!(collected[0].network_id, NetworkId(3));
+        assert_eq!(collected[0].components.len(), 2);
+
+        stage.run(&mut world);
+        assert!(world.get_resource::<Collected>().unwrap().0.is_empty());
+
+        world.get_mut::<Ammo>(player).unwrap().rounds = 29;
+        stage.run(&mut world);
+
+        let collected = &world.get_resource::<Collected>().unwrap().0;
+        let registry = world.get_resource::<ReplicationRegistry>().unwrap();
+        assert_eq!(collected.len(), 1);
+        assert_eq!(collected[0].components.len(), 1);
+        assert_eq!(
+            Some(collected[0].components[0].kind),
+            registry.kind_of::<Ammo>()
+        );
+    }
+
+    #[test]
+    fn applies_collected_components() {
+        let mut server_world = world();
+        server_world.spawn().insert_bundle((
+            Replicated,
+            NetworkId(7),
+            Health { points: 40 },
+            Ammo { rounds: 2 },
+        ));
+
+        let collected = collect_components(&mut server_world, false).unwrap();
+        assert_eq!(collected.len(), 1);
+
+        let mut client_world = world();
+        let prop = client_world
+            .spawn()
+            .insert_bundle((NetworkId(7), Health { points: 100 }))
+            .id();
+
+        apply_components(&mut client_world, prop, &collected[0].components).unwrap();
+
+        assert_eq!(
+            client_world.get::<Health>(prop),
+            Some(&Health { points: 40 })
+        );
+        assert_eq!(client_world.get::<Ammo>(prop), Some(&Ammo { rounds: 2 }));
+
+        let unknown = ReplicatedComponentData {
+            kind: 9,
+            bytes: Vec::new(),
+        };
+        assert!(matches!(
+            apply_components(&mut client_world, prop, &[unknown]),
+            Err(ReplicationError::UnknownKind(9))
+        ));
+    }
+}
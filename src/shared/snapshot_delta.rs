@@ -0,0 +1,346 @@
+use std::collections::VecDeque;
+
+use bevy::math::{Quat, Vec3};
+
+use crate::shared::game_message::{
+    sequence_greater_than, ClientId, EntityStateDelta, PlayerStateData, PropStateData,
+    ServerGameStateDeltaData, ServerGameStateSnapshotData,
+};
+
+trait EntityState: Clone {
+    type Id: Copy + Ord;
+
+    fn id(&self) -> Self::Id;
+    fn with_id(id: Self::Id) -> Self;
+    fn fields(&self) -> (Vec3, Quat, Vec3);
+    fn fields_mut(&mut self) -> (&mut Vec3, &mut Quat, &mut Vec3);
+}
+
+impl EntityState for PlayerStateData {
+    type Id = ClientId;
+
+    fn id(&self) -> ClientId {
+        self.client_id
+    }
+
+    fn with_id(client_id: ClientId) -> Self {
+        Self {
+            client_id,
+            ..Default::default()
+        }
+    }
+
+    fn fields(&self) -> (Vec3, Quat, Vec3) {
+        (self.translation, self.rotation, self.linear_velocity)
+    }
+
+    fn fields_mut(&mut self) -> (&mut Vec3, &mut Quat, &mut Vec3) {
+        (
+            &mut self.translation,
+            &mut self.rotation,
+            &mut self.linear_velocity,
+        )
+    }
+}
+
+impl EntityState for PropStateData {
+    type Id = u32;
+
+    fn id(&self) -> u32 {
+        self.prop_id
+    }
+
+    fn with_id(prop_id: u32) -> Self {
+        Self {
+            prop_id,
+            ..Default::default()
+        }
+    }
+
+    fn fields(&self) -> (Vec3, Quat, Vec3) {
+        (self.translation, self.rotation, self.linear_velocity)
+    }
+
+    fn fields_mut(&mut self) -> (&mut Vec3, &mut Quat, &mut Vec3) {
+        (
+            &mut self.translation,
+            &mut self.rotation,
+            &mut self.linear_velocity,
+        )
+    }
+}
+
+// Fields are compared exactly so reconstruction on the client is lossless
+fn diff_entities<T: EntityState>(
+    baseline: &[T],
+    current: &[T],
+) -> (Vec<EntityStateDelta<T::Id>>, Vec<T::Id>) {
+    let mut changed = Vec::new();
+
+    for entity in current.iter() {
+        let (translation, rotation, linear_velocity) = entity.fields();
+
+        let delta = match baseline.iter().find(|base| base.id() == entity.id()) {
+            Some(base) => {
+                let (base_translation, base_rotation, base_linear_velocity) = base.fields();
+
+                EntityStateDelta {
+                    id: entity.id(),
+                    translation: Some(translation).filter(|value| *value != base_translation),
+                    rotation: Some(rotation).filter(|value| *value != base_rotation),
+                    linear_velocity: Some(linear_velocity)
+                        .filter(|value| *value != base_linear_velocity),
+                }
+            }
+            None => EntityStateDelta {
+                id: entity.id(),
+                translation: Some(translation),
+                rotation: Some(rotation),
+                linear_velocity: Some(linear_velocity),
+            },
+        };
+
+        if delta.translation.is_some()
+            || delta.rotation.is_some()
+            || delta.linear_velocity.is_some()
+        {
+            changed.push(delta);
+        }
+    }
+
+    let removed = baseline
+        .iter()
+        .map(|base| base.id())
+        .filter(|id| !current.iter().any(|entity| entity.id() == *id))
+        .collect();
+
+    (changed, removed)
+}
+
+fn apply_entities<T: EntityState>(
+    baseline: &[T],
+    changed: &[EntityStateDelta<T::Id>],
+    removed: &[T::Id],
+) -> Vec<T> {
+    let mut entities: Vec<T> = baseline
+        .iter()
+        .filter(|base| !removed.contains(&base.id()))
+        .cloned()
+        .collect();
+
+    for delta in changed.iter() {
+        let index = match entities.iter().position(|entity| entity.id() == delta.id) {
+            Some(index) => index,
+            None => {
+                entities.push(T::with_id(delta.id));
+                entities.len() - 1
+            }
+        };
+
+        let (translation, rotation, linear_velocity) = entities[index].fields_mut();
+
+        if let Some(value) = delta.translation {
+            *translation = value;
+        }
+        if let Some(value) = delta.rotation {
+            *rotation = value;
+        }
+        if let Some(value) = delta.linear_velocity {
+            *linear_velocity = value;
+        }
+    }
+
+    entities.sort_by_key(|entity| entity.id());
+    entities
+}
+
+pub fn encode_delta(
+    baseline: &ServerGameStateSnapshotData,
+    current: &ServerGameStateSnapshotData,
+) -> ServerGameStateDeltaData {
+    let (players, removed_players) = diff_entities(&baseline.players, &current.players);
+    let (props, removed_props) = diff_entities(&baseline.props, &current.props);
+
+    ServerGameStateDeltaData {
+        tick: current.tick,
+        baseline_tick: baseline.tick,
+        time: current.time,
+        last_processed_input: current.last_processed_input,
+        players,
+        removed_players,
+        props,
+        removed_props,
+    }
+}
+
+pub fn apply_delta(
+    baseline: &ServerGameStateSnapshotData,
+    delta: &ServerGameStateDeltaData,
+) -> ServerGameStateSnapshotData {
+    ServerGameStateSnapshotData {
+        tick: delta.tick,
+        time: delta.time,
+        last_processed_input: delta.last_processed_input,
+        players: apply_entities(&baseline.players, &delta.players, &delta.removed_players),
+        props: apply_entities(&baseline.props, &delta.props, &delta.removed_props),
+    }
+}
+
+// Recently sent (server) or received (client) snapshots that can serve as a delta baseline.
+// Baselines older than the capacity are gone, forcing the server back to a full snapshot.
+pub struct SnapshotHistory {
+    snapshots: VecDeque<ServerGameStateSnapshotData>,
+    capacity: usize,
+}
+
+impl Default for SnapshotHistory {
+    fn default() -> Self {
+        Self::new(64)
+    }
+}
+
+impl SnapshotHistory {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            snapshots: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    pub fn insert(&mut self, snapshot: ServerGameStateSnapshotData) {
+        if let Some(latest) = self.snapshots.back() {
+            if !sequence_greater_than(snapshot.tick, latest.tick) {
+                return;
+            }
+        }
+
+        if self.snapshots.len() == self.capacity {
+            self.snapshots.pop_front();
+        }
+
+        self.snapshots.push_back(snapshot);
+    }
+
+    pub fn get(&self, tick: u32) -> Option<&ServerGameStateSnapshotData> {
+        self.snapshots.iter().find(|snapshot| snapshot.tick == tick)
+    }
+
+    pub fn latest_tick(&self) -> Option<u32> {
+        self.snapshots.back().map(|snapshot| snapshot.tick)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::shared::game_message::{GameMessage, GameMessageType};
+
+    fn snapshot(tick: u32, prop_count: u32) -> ServerGameStateSnapshotData {
+        ServerGameStateSnapshotData {
+            tick,
+            time: tick as f64 / 20.0,
+            last_processed_input: tick * 3,
+            players: (1..=4)
+                .map(|client_id| PlayerStateData {
+                    client_id,
+                    translation: Vec3::new(client_id as f32, 1.0, -5.0),
+                    rotation: Quat::from_rotation_y(client_id as f32),
+                    linear_velocity: Vec3::ZERO,
+                })
+                .collect(),
+            props: (0..prop_count)
+                .map(|prop_id| PropStateData {
+                    prop_id,
+                    translation: Vec3::new(prop_id as f32, 0.5, 3.0),
+                    rotation: Quat::IDENTITY,
+                    linear_velocity: Vec3::ZERO,
+                })
+                .collect(),
+        }
+    }
+
+    fn encoded_size(content: GameMessageType) -> usize {
+        GameMessage::new(1, content).encode().unwrap().len()
+    }
+
+    #[test]
+    fn unchanged_state_produces_empty_delta() {
+        let baseline = snapshot(1, 40);
+        let current = ServerGameStateSnapshotData {
+            tick: 2,
+            ..baseline.clone()
+        };
+
+        let delta = encode_delta(&baseline, &current);
+
+        assert!(delta.players.is_empty());
+        assert!(delta.props.is_empty());
+        assert_eq!(apply_delta(&baseline, &delta), current);
+    }
+
+    #[test]
+    fn delta_reconstructs_exactly_and_saves_bytes() {
+        let baseline = snapshot(10, 40);
+        let mut current = snapshot(12, 40);
+
+        current.players[1].translation += Vec3::new(0.25, 0.0, 0.1);
+        current.players[1].linear_velocity = Vec3::new(5.0, 0.0, 0.0);
+        current.props[7].rotation = Quat::from_rotation_x(0.3);
+        current.props[21].translation.y -= 0.01;
+
+        let delta = encode_delta(&baseline, &current);
+
+        assert_eq!(delta.baseline_tick, 10);
+        assert_eq!(delta.players.len(), 1);
+        assert_eq!(delta.props.len(), 2);
+        assert_eq!(delta.players[0].rotation, None);
+        assert_eq!(apply_delta(&baseline, &delta), current);
+
+        let full_size = encoded_size(GameMessageType::ServerGameStateSnapshot(current));
+        let delta_size = encoded_size(GameMessageType::ServerGameStateDelta(delta));
+
+        assert!(
+            delta_size * 5 < full_size,
+            "delta {} bytes, full {} bytes",
+            delta_size,
+            full_size
+        );
+    }
+
+    #[test]
+    fn delta_handles_spawned_and_removed_entities() {
+        let baseline = snapshot(1, 5);
+        let mut current = snapshot(2, 5);
+
+        current.players.remove(2);
+        current.props.remove(0);
+        current.props.push(PropStateData {
+            prop_id: 99,
+            translation: Vec3::new(1.0, 2.0, 3.0),
+            rotation: Quat::from_rotation_z(1.0),
+            linear_velocity: Vec3::Y,
+        });
+
+        let delta = encode_delta(&baseline, &current);
+
+        assert_eq!(delta.removed_players, vec![3]);
+        assert_eq!(delta.removed_props, vec![0]);
+        assert_eq!(apply_delta(&baseline, &delta), current);
+    }
+
+    #[test]
+    fn history_forgets_old_baselines() {
+        let mut history = SnapshotHistory::new(4);
+
+        for tick in 1..=6 {
+            history.insert(snapshot(tick, 1));
+        }
+
+        // Late arrivals don't replace newer snapshots
+        history.insert(snapshot(3, 1));
+
+        assert!(history.get(2).is_none());
+        assert!(history.get(3).is_some());
+        assert_eq!(history.latest_tick(), Some(6));
+    }
+}
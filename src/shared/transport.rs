@@ -0,0 +1,162 @@
+#[cfg(test)]
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+};
+use std::{
+    io,
+    net::{SocketAddr, UdpSocket},
+};
+
+// Moves datagrams between addresses, implemented by real sockets and the in-memory loopback
+pub trait Transport: Send + Sync {
+    fn send_to(&self, bytes: &[u8], address: SocketAddr) -> io::Result<()>;
+
+    // Returns None once no datagram is waiting
+    fn receive_from(&self, buffer: &mut [u8]) -> io::Result<Option<(usize, SocketAddr)>>;
+}
+
+impl Transport for UdpSocket {
+    fn send_to(&self, bytes: &[u8], address: SocketAddr) -> io::Result<()> {
+        UdpSocket::send_to(self, bytes, address)?;
+
+        Ok(())
+    }
+
+    fn receive_from(&self, buffer: &mut [u8]) -> io::Result<Option<(usize, SocketAddr)>> {
+        match self.recv_from(buffer) {
+            Ok(received) => Ok(Some(received)),
+            Err(error)
+                if error.kind() == io::ErrorKind::WouldBlock
+                    || error.kind() == io::ErrorKind::TimedOut =>
+            {
+                Ok(None)
+            }
+            Err(error) => Err(error),
+        }
+    }
+}
+
+#[cfg(test)]
+type Mailboxes = HashMap<SocketAddr, VecDeque<(SocketAddr, Vec<u8>)>>;
+
+#[cfg(test)]
+// An in-process stand-in for the network, datagrams are delivered instantly and in order.
+// Clones share the same network.
+#[derive(Debug, Clone, Default)]
+pub struct LoopbackNetwork {
+    mailboxes: Arc<Mutex<Mailboxes>>,
+}
+
+#[cfg(test)]
+impl LoopbackNetwork {
+    pub fn bind(&self, address: SocketAddr) -> io::Result<LoopbackTransport> {
+        let mut mailboxes = self.mailboxes.lock().unwrap();
+
+        if mailboxes.contains_key(&address) {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("{} is already bound", address),
+            ));
+        }
+
+        mailboxes.insert(address, VecDeque::default());
+
+        Ok(LoopbackTransport {
+            address,
+            network: self.clone(),
+        })
+    }
+}
+
+#[cfg(test)]
+#[derive(Debug)]
+pub struct LoopbackTransport {
+    address: SocketAddr,
+    network: LoopbackNetwork,
+}
+
+#[cfg(test)]
+impl Transport for LoopbackTransport {
+    fn send_to(&self, bytes: &[u8], address: SocketAddr) -> io::Result<()> {
+        // Like UDP, sending to an address nobody listens on silently goes nowhere
+        if let Some(mailbox) = self.network.mailboxes.lock().unwrap().get_mut(&address) {
+            mailbox.push_back((self.address, bytes.to_vec()));
+        }
+
+        Ok(())
+    }
+
+    fn receive_from(&self, buffer: &mut [u8]) -> io::Result<Option<(usize, SocketAddr)>> {
+        let datagram = self
+            .network
+            .mailboxes
+            .lock()
+            .unwrap()
+            .get_mut(&self.address)
+            .and_then(|mailbox| mailbox.pop_front());
+
+        Ok(datagram.map(|(sender, bytes)| {
+            // Datagrams that don't fit are truncated, same as with a real socket
+            let length = bytes.len().min(buffer.len());
+            buffer[..length].copy_from_slice(&bytes[..length]);
+
+            (length, sender)
+        }))
+    }
+}
+
+#[cfg(test)]
+impl Drop for LoopbackTransport {
+    fn drop(&mut self) {
+        self.network.mailboxes.lock().unwrap().remove(&self.address);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn loopback_delivers_in_order() {
+        let network = LoopbackNetwork::default();
+        let first = network.bind(address(1)).unwrap();
+        let second = network.bind(address(2)).unwrap();
+
+        first.send_to(b"hello", address(2)).unwrap();
+        first.send_to(b"world", address(2)).unwrap();
+        // Nobody listens here
+        first.send_to(b"lost", address(3)).unwrap();
+
+        let mut buffer = [0; 4];
+        assert_eq!(
+            second.receive_from(&mut buffer).unwrap(),
+            Some((4, address(1)))
+        );
+        assert_eq!(&buffer, b"hell");
+        assert_eq!(
+            second.receive_from(&mut buffer).unwrap(),
+            Some((4, address(1)))
+        );
+        assert_eq!(&buffer, b"worl");
+        assert_eq!(second.receive_from(&mut buffer).unwrap(), None);
+    }
+
+    #[test]
+    fn addresses_are_released_on_drop() {
+        let network = LoopbackNetwork::default();
+        let transport = network.bind(address(1)).unwrap();
+
+        assert_eq!(
+            network.bind(address(1)).unwrap_err().kind(),
+            io::ErrorKind::AddrInUse
+        );
+
+        drop(transport);
+        assert!(network.bind(address(1)).is_ok());
+    }
+}
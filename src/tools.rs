@@ -0,0 +1,62 @@
+use bevy::math::{Quat, Vec3};
+
+use crate::shared::{
+    fragmentation::{FRAGMENT_PAYLOAD_SIZE, MAX_MESSAGE_SIZE, MAX_PACKET_SIZE},
+    game_message::{
+        ClientId, GameMessage, GameMessageType, PlayerStateData, PropStateData,
+        ServerGameStateSnapshotData, PACKET_HEADER_SIZE, PROTOCOL_MAGIC, PROTOCOL_VERSION,
+    },
+};
+
+// Helps budgeting player and prop counts against the packet size
+pub fn print_protocol(players: usize, props: usize) {
+    println!(
+        "protocol version {} (magic {:#010x})",
+        PROTOCOL_VERSION, PROTOCOL_MAGIC
+    );
+    println!(
+        "packet header {} bytes, packets up to {} bytes, messages up to {} bytes",
+        PACKET_HEADER_SIZE, MAX_PACKET_SIZE, MAX_MESSAGE_SIZE
+    );
+
+    let snapshot = ServerGameStateSnapshotData {
+        tick: 1,
+        time: 0.0,
+        last_processed_input: 0,
+        players: (0..players)
+            .map(|index| PlayerStateData {
+                client_id: index as ClientId + 1,
+                translation: Vec3::new(index as f32, 1.0, 0.0),
+                rotation: Quat::IDENTITY,
+                linear_velocity: Vec3::ZERO,
+            })
+            .collect(),
+        props: (0..props)
+            .map(|index| PropStateData {
+                prop_id: index as u32,
+                translation: Vec3::new(index as f32, 0.5, 0.0),
+                rotation: Quat::IDENTITY,
+                linear_velocity: Vec3::ZERO,
+            })
+            .collect(),
+    };
+
+    match GameMessage::new(0, GameMessageType::ServerGameStateSnapshot(snapshot)).encode() {
+        Ok(bytes) => {
+            let packets = if bytes.len() <= MAX_PACKET_SIZE {
+                1
+            } else {
+                bytes.len().div_ceil(FRAGMENT_PAYLOAD_SIZE)
+            };
+
+            println!(
+                "full snapshot with {} players and {} props: {} bytes in {} packet(s)",
+                players,
+                props,
+                bytes.len(),
+                packets
+            );
+        }
+        Err(error) => eprintln!("Failed to encode snapshot: {}", error),
+    }
+}